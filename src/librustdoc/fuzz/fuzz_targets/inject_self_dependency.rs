@@ -0,0 +1,57 @@
+//! Feeds arbitrary manifests to twoslash's `inject_self_dependency`, which gets whatever
+//! Cargo.toml RUSTDOC_TWOSLASH_CARGO_TOML points at.
+//!
+//! Run from `src/librustdoc` with `cargo fuzz run inject_self_dependency`.
+//!
+//! The output isn't checked to be at least as long as the input: the package is renamed,
+//! and its name may have been longer than the one it gets.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use rustdoc::html::twoslash_fuzzing::{TMP_PACKAGE_NAME, inject_self_dependency, same_crate};
+use toml_edit::{DocumentMut, Item};
+
+/// Keys of the `[dependencies]` of `doc`
+fn dependencies(doc: &DocumentMut) -> Vec<String> {
+    let table = doc.get("dependencies").and_then(Item::as_table_like);
+    table.into_iter().flat_map(|table| table.iter().map(|(key, _)| key.to_string())).collect()
+}
+
+fuzz_target!(|data: &[u8]| {
+    // Manifests are read into strings, so no other input reaches the function
+    let Ok(cargo_toml) = std::str::from_utf8(data) else { return };
+    let (output, added) = inject_self_dependency(cargo_toml, "/fuzz/crate", &["std"]);
+
+    let Ok(input) = cargo_toml.parse::<DocumentMut>() else {
+        // Manifests that don't parse are passed through
+        assert!(!added);
+        assert_eq!(output, cargo_toml);
+        return;
+    };
+    let output: DocumentMut = output.parse().expect("a valid manifest stays valid");
+
+    let before = dependencies(&input);
+    let after = dependencies(&output);
+    for dep in &before {
+        assert!(after.contains(dep), "dependency `{dep}` was dropped");
+    }
+    let Some(name) = input.get("package").and_then(|package| package.get("name")) else {
+        assert!(!added);
+        return;
+    };
+    let Some(name) = name.as_str() else { return };
+    if name == TMP_PACKAGE_NAME {
+        assert!(!added);
+        return;
+    }
+    let dep_key = name.replace('-', "_");
+    let count = |deps: &[String]| deps.iter().filter(|dep| same_crate(dep, &dep_key)).count();
+    if added {
+        assert_eq!(count(&before), 0);
+        assert_eq!(count(&after), 1);
+        assert_eq!(output["package"]["name"].as_str(), Some(TMP_PACKAGE_NAME));
+    } else {
+        assert_eq!(count(&after), count(&before));
+    }
+});
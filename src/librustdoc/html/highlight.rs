@@ -28,6 +28,7 @@ use crate::html::format::HrefInfo;
 use crate::html::macro_expansion::ExpandedCode;
 use crate::html::render::span_map::{DUMMY_SP, Span};
 use crate::html::render::{Context, LinkFromSrc};
+use crate::html::twoslash::{self, TypeAnnotation};
 
 /// This type is needed in case we want to render links on items to allow to go to their definition.
 pub(crate) struct HrefContext<'a, 'tcx> {
@@ -43,10 +44,10 @@ pub(crate) struct HrefContext<'a, 'tcx> {
 
 /// Type annotation data for twoslash-style hovers
 #[derive(Debug, Clone, Default)]
-#[allow(dead_code)]
 pub(crate) struct TypeAnnotationInfo {
     pub type_text: String,
     pub docs: Option<String>,
+    pub kind: twoslash::TokenKind,
 }
 
 /// Decorations are represented as a map from CSS class to vector of character ranges.
@@ -59,6 +60,24 @@ pub(crate) struct DecorationInfo {
     pub(crate) type_annotations: FxIndexMap<(u32, u32), TypeAnnotationInfo>,
 }
 
+impl DecorationInfo {
+    /// Decorations carrying only the given twoslash type annotations
+    pub(crate) fn from_type_annotations(
+        annotations: impl IntoIterator<Item = TypeAnnotation>,
+    ) -> Self {
+        let type_annotations = annotations
+            .into_iter()
+            .map(|ann| {
+                let range = (ann.start, ann.start + ann.length);
+                let info =
+                    TypeAnnotationInfo { type_text: ann.type_text, docs: ann.docs, kind: ann.kind };
+                (range, info)
+            })
+            .collect();
+        DecorationInfo { decorations: Default::default(), type_annotations }
+    }
+}
+
 #[derive(Eq, PartialEq, Clone)]
 pub(crate) enum Tooltip {
     IgnoreAll,
@@ -83,6 +102,20 @@ pub(crate) fn render_example_with_highlighting(
     })
 }
 
+/// Highlights `src` with twoslash type annotations attached, returning the HTML output.
+///
+/// Annotations are matched to tokens while highlighting, so they line up with the source
+/// byte offsets regardless of HTML escaping or multi-byte characters. Annotated tokens get a
+/// `data-type` attribute (plus `data-docs` when the hover has documentation), which is what
+/// the rustdoc frontend turns into hover popovers.
+#[cfg(test)]
+pub(crate) fn annotate_html(src: &str, annotations: &[TypeAnnotation]) -> String {
+    let decoration_info = DecorationInfo::from_type_annotations(annotations.iter().cloned());
+    let mut out = String::new();
+    write_code(&mut out, src, None, Some(&decoration_info), None);
+    out
+}
+
 fn write_header(class: &str, tooltip: Option<&Tooltip>, extra_classes: &[String]) -> impl Display {
     fmt::from_fn(move |f| {
         write!(
@@ -1473,6 +1506,118 @@ fn generate_link_to_def(
     false
 }
 
+// Helper to escape text for HTML attribute (must escape quotes and newlines)
+fn escape_for_attr(s: &str) -> String {
+    let mut result = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => result.push_str("&quot;"),
+            '&' => result.push_str("&amp;"),
+            '<' => result.push_str("&lt;"),
+            '>' => result.push_str("&gt;"),
+            '\n' => result.push_str("&#10;"),
+            '\r' => result.push_str("&#13;"),
+            _ => result.push(c),
+        }
+    }
+    result
+}
+
+// Helper to syntax-highlight a Rust code string and return HTML
+fn highlight_rust_code(code: &str) -> String {
+    let mut out = String::new();
+    write_code(&mut out, code, None, None, None);
+    out
+}
+
+// Helper to render markdown docs to HTML using pulldown-cmark,
+// with syntax-highlighted Rust code blocks
+fn render_docs_markdown(md: &str) -> String {
+    let mut opts = Options::empty();
+    opts.insert(Options::ENABLE_STRIKETHROUGH);
+    opts.insert(Options::ENABLE_TABLES);
+    let parser = Parser::new_ext(md, opts);
+
+    let mut html_output = String::new();
+    let mut in_code_block = false;
+    let mut is_rust_block = false;
+    let mut code_buf = String::new();
+
+    let mut non_code_events: Vec<Event<'_>> = Vec::new();
+
+    for event in parser {
+        match event {
+            Event::Start(Tag::CodeBlock(ref kind)) => {
+                // Flush any accumulated non-code events first
+                if !non_code_events.is_empty() {
+                    html::push_html(&mut html_output, non_code_events.drain(..));
+                }
+                in_code_block = true;
+                code_buf.clear();
+                is_rust_block = match kind {
+                    CodeBlockKind::Fenced(lang) => {
+                        let lang = lang.split(',').next().unwrap_or("").trim();
+                        lang.is_empty() || lang == "rust" || lang == "rs"
+                    }
+                    CodeBlockKind::Indented => true,
+                };
+            }
+            Event::Text(ref text) if in_code_block => {
+                code_buf.push_str(text);
+            }
+            Event::End(TagEnd::CodeBlock) => {
+                in_code_block = false;
+                if is_rust_block {
+                    html_output.push_str("<pre class=\"rust\">");
+                    html_output.push_str(&highlight_rust_code(&code_buf));
+                    html_output.push_str("</pre>");
+                } else {
+                    html_output.push_str("<pre><code>");
+                    html_output.push_str(&EscapeBodyText(&code_buf).to_string());
+                    html_output.push_str("</code></pre>");
+                }
+            }
+            _ => {
+                non_code_events.push(event);
+            }
+        }
+    }
+    // Flush remaining events
+    if !non_code_events.is_empty() {
+        html::push_html(&mut html_output, non_code_events.into_iter());
+    }
+    html_output
+}
+
+/// The `data-type` and `data-docs` attributes of an annotated token, with the token kind, when
+/// known, as `data-kind`.
+///
+/// `type_text` is split on its [`twoslash::DOCS_SEPARATOR`]: the signature is syntax-highlighted
+/// into `data-type` and the docs are rendered into `data-docs`. `docs`, if any, take the place of
+/// those in `type_text`.
+pub(crate) fn annotation_attrs(
+    type_text: &str,
+    docs: Option<&str>,
+    kind: twoslash::TokenKind,
+) -> String {
+    let kind_attr = kind.css_name().map(|kind| format!(" data-kind=\"{kind}\""));
+    let kind_attr = kind_attr.unwrap_or_default();
+    let (sig, text_docs) = twoslash::type_and_docs(type_text);
+    let escaped_sig = escape_for_attr(&highlight_rust_code(sig));
+    match docs.or(text_docs) {
+        Some(docs) => {
+            let rendered_docs = render_docs_markdown(docs);
+            let escaped_docs = twoslash::sanitize_docs_for_attribute(&rendered_docs);
+            format!(" data-type=\"{escaped_sig}\" data-docs=\"{escaped_docs}\"{kind_attr}")
+        }
+        None => format!(" data-type=\"{escaped_sig}\"{kind_attr}"),
+    }
+}
+
+fn build_annotation_attrs(ann: &TypeAnnotationInfo) -> String {
+    annotation_attrs(&ann.type_text, ann.docs.as_deref(), ann.kind)
+}
+
 /// This function writes `text` into `out` with some modifications depending on `klass`:
 ///
 /// * If `klass` is `None`, `text` is written into `out` with no modification.
@@ -1492,108 +1637,6 @@ fn string_without_closing_tag<T: Display>(
     type_annotations: Option<&FxIndexMap<(u32, u32), TypeAnnotationInfo>>,
     byte_offset: u32,
 ) -> Option<&'static str> {
-    // Helper to escape text for HTML attribute (must escape quotes and newlines)
-    fn escape_for_attr(s: &str) -> String {
-        let mut result = String::with_capacity(s.len());
-        for c in s.chars() {
-            match c {
-                '"' => result.push_str("&quot;"),
-                '&' => result.push_str("&amp;"),
-                '<' => result.push_str("&lt;"),
-                '>' => result.push_str("&gt;"),
-                '\n' => result.push_str("&#10;"),
-                '\r' => result.push_str("&#13;"),
-                _ => result.push(c),
-            }
-        }
-        result
-    }
-
-    // Helper to syntax-highlight a Rust code string and return HTML
-    fn highlight_rust_code(code: &str) -> String {
-        let mut out = String::new();
-        write_code(&mut out, code, None, None, None);
-        out
-    }
-
-    // Helper to render markdown docs to HTML using pulldown-cmark,
-    // with syntax-highlighted Rust code blocks
-    fn render_docs_markdown(md: &str) -> String {
-        let mut opts = Options::empty();
-        opts.insert(Options::ENABLE_STRIKETHROUGH);
-        opts.insert(Options::ENABLE_TABLES);
-        let parser = Parser::new_ext(md, opts);
-
-        let mut html_output = String::new();
-        let mut in_code_block = false;
-        let mut is_rust_block = false;
-        let mut code_buf = String::new();
-
-        let mut non_code_events: Vec<Event<'_>> = Vec::new();
-
-        for event in parser {
-            match event {
-                Event::Start(Tag::CodeBlock(ref kind)) => {
-                    // Flush any accumulated non-code events first
-                    if !non_code_events.is_empty() {
-                        html::push_html(&mut html_output, non_code_events.drain(..));
-                    }
-                    in_code_block = true;
-                    code_buf.clear();
-                    is_rust_block = match kind {
-                        CodeBlockKind::Fenced(lang) => {
-                            let lang = lang.split(',').next().unwrap_or("").trim();
-                            lang.is_empty() || lang == "rust" || lang == "rs"
-                        }
-                        CodeBlockKind::Indented => true,
-                    };
-                }
-                Event::Text(ref text) if in_code_block => {
-                    code_buf.push_str(text);
-                }
-                Event::End(TagEnd::CodeBlock) => {
-                    in_code_block = false;
-                    if is_rust_block {
-                        html_output.push_str("<pre class=\"rust\">");
-                        html_output.push_str(&highlight_rust_code(&code_buf));
-                        html_output.push_str("</pre>");
-                    } else {
-                        html_output.push_str("<pre><code>");
-                        html_output.push_str(&EscapeBodyText(&code_buf).to_string());
-                        html_output.push_str("</code></pre>");
-                    }
-                }
-                _ => {
-                    non_code_events.push(event);
-                }
-            }
-        }
-        // Flush remaining events
-        if !non_code_events.is_empty() {
-            html::push_html(&mut html_output, non_code_events.into_iter());
-        }
-        html_output
-    }
-
-    // Helper to build data attributes for type annotations
-    // Splits type_text on "\n\n---\n\n" separator: sig (syntax-highlighted) goes in data-type, docs rendered to HTML in data-docs
-    fn build_annotation_attrs(ann: &TypeAnnotationInfo) -> String {
-        let separator = "\n\n---\n\n";
-        if let Some(sep_pos) = ann.type_text.find(separator) {
-            let sig = &ann.type_text[..sep_pos];
-            let docs = &ann.type_text[sep_pos + separator.len()..];
-            let highlighted_sig = highlight_rust_code(sig);
-            let escaped_sig = escape_for_attr(&highlighted_sig);
-            let rendered_docs = render_docs_markdown(docs);
-            let escaped_docs = escape_for_attr(&rendered_docs);
-            format!(" data-type=\"{escaped_sig}\" data-docs=\"{escaped_docs}\"")
-        } else {
-            let highlighted = highlight_rust_code(&ann.type_text);
-            let escaped = escape_for_attr(&highlighted);
-            format!(" data-type=\"{escaped}\"")
-        }
-    }
-
     let data_type_str = type_annotation
         .map(|ann| build_annotation_attrs(ann))
         .unwrap_or_default();
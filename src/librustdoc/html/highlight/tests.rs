@@ -3,7 +3,8 @@ use rustc_data_structures::fx::FxIndexMap;
 use rustc_span::create_default_session_globals_then;
 use test::Bencher;
 
-use super::{DecorationInfo, write_code};
+use super::{DecorationInfo, annotate_html, write_code};
+use crate::html::twoslash::{TokenKind, TypeAnnotation};
 
 const STYLE: &str = r#"
 <style>
@@ -86,6 +87,53 @@ let a = 4;";
     });
 }
 
+#[test]
+fn test_annotate_html() {
+    create_default_session_globals_then(|| {
+        let src = "let café = \"<é>\";";
+        let annotations = [TypeAnnotation::new(4, "café".len() as u32, "let café: &str")];
+        let html = annotate_html(src, &annotations);
+        let (before, after) = html.split_once(" data-type=\"").unwrap();
+        assert!(before.ends_with("<span"));
+        assert!(after.split_once('>').unwrap().1.starts_with("café</span>"));
+        // The string literal after the annotated token is still escaped and unannotated
+        assert!(after.contains("&lt;é&gt;"));
+        assert_eq!(html.matches("data-type").count(), 1);
+    });
+}
+
+#[test]
+fn test_annotate_html_kind() {
+    create_default_session_globals_then(|| {
+        let src = "fn f<'a>(s: &'a str) {}";
+        let annotations = [
+            TypeAnnotation { kind: TokenKind::Lifetime, ..TypeAnnotation::new(5, 2, "'a") },
+            TypeAnnotation::new(14, 2, "'a"),
+        ];
+        let html = annotate_html(src, &annotations);
+        assert_eq!(html.matches("data-type").count(), 2);
+        // Only tokens of a known kind are marked
+        assert_eq!(html.matches(" data-kind=\"lifetime\"").count(), 1);
+    });
+}
+
+#[test]
+fn test_annotate_html_docs() {
+    create_default_session_globals_then(|| {
+        let src = "let s = 1;";
+        let annotations = [TypeAnnotation {
+            docs: Some("Says \"hi\"\u{7}".into()),
+            kind: TokenKind::Variable,
+            ..TypeAnnotation::new(4, 1, "let s: i32")
+        }];
+        let html = annotate_html(src, &annotations);
+        let docs = html.split_once(" data-docs=\"").unwrap().1.split_once('"').unwrap().0;
+        assert!(docs.starts_with("&lt;p&gt;Says "));
+        assert!(!docs.contains('\u{7}'));
+        assert!(html.split_once(docs).unwrap().1.starts_with("\" data-kind=\"variable\""));
+    });
+}
+
 #[bench]
 fn bench_html_highlighting(b: &mut Bencher) {
     let src = include_str!("../../../../compiler/rustc_ast/src/visit.rs");
@@ -53,7 +53,7 @@ use crate::clean::RenderedLink;
 use crate::doctest;
 use crate::doctest::GlobalTestOptions;
 use crate::html::escape::{Escape, EscapeBodyText};
-use crate::html::highlight::{self, TypeAnnotationInfo};
+use crate::html::highlight;
 use crate::html::length_limit::HtmlWithLimit;
 use crate::html::render::small_url_encode;
 use crate::html::toc::{Toc, TocBuilder};
@@ -342,22 +342,33 @@ impl<'a, I: Iterator<Item = Event<'a>>> Iterator for CodeBlocks<'_, 'a, I> {
             }
         };
 
-        // Process twoslash type annotations if enabled
-        let decoration_info = if twoslash::is_enabled() {
-            let annotations = twoslash::process_code_block(&text);
-            let mut type_annotations = FxIndexMap::default();
-            for ann in annotations {
-                let start = ann.start;
-                let end = start + ann.length;
-                type_annotations.insert(
-                    (start, end),
-                    TypeAnnotationInfo { type_text: ann.type_text, docs: ann.docs },
-                );
+        // Process twoslash type annotations if enabled, globally or for this block
+        let twoslash_attrs = match kind {
+            CodeBlockKind::Fenced(ref lang) => twoslash::parse_block_attrs(lang),
+            CodeBlockKind::Indented => Default::default(),
+        };
+        let decoration_info = if twoslash_attrs.enabled() {
+            // Analyzed with the hidden lines, as the doctest is compiled, then shown without
+            let mut annotations = twoslash::process_code_block_lossy(
+                &original_text,
+                Some(&twoslash_attrs),
+                twoslash::TwoslashConfig::global(),
+            );
+            // A `// @errors` marker needs the diagnostics to check, even if they aren't shown
+            let errors = twoslash::parse_errors_directive(&original_text);
+            if (compile_fail && !twoslash_attrs.no_annotations) || errors.is_some() {
+                let diagnostics = twoslash::diagnostics_for_code(&original_text);
+                if let Some(errors) = &errors
+                    && let Err(e) = twoslash::check_errors(errors, &diagnostics)
+                {
+                    warn_wrong_error_count(errors, &e);
+                }
+                if !twoslash_attrs.no_annotations {
+                    annotations.extend(diagnostics);
+                }
             }
-            Some(highlight::DecorationInfo {
-                decorations: Default::default(),
-                type_annotations,
-            })
+            let annotations = twoslash::annotations_for_html(&original_text, annotations);
+            Some(highlight::DecorationInfo::from_type_annotations(annotations))
         } else {
             None
         };
@@ -378,6 +389,21 @@ impl<'a, I: Iterator<Item = Event<'a>>> Iterator for CodeBlocks<'_, 'a, I> {
     }
 }
 
+/// Warn about a code block whose `// @errors N` marker doesn't match how it fails to compile.
+///
+/// Code blocks are rendered without the item they document at hand, so this is a warning of
+/// the session rendering happens in rather than a lint at the block's span. Rendering a
+/// standalone Markdown file has no session, and prints the warning instead.
+fn warn_wrong_error_count(directive: &twoslash::ErrorsDirective, e: &twoslash::DiagnosticError) {
+    let msg = format!("`// @errors` on line {} of a code block: {e}", directive.line);
+    rustc_middle::ty::tls::with_opt(|tcx| match tcx {
+        Some(tcx) => {
+            tcx.dcx().warn(msg);
+        }
+        None => eprintln!("warning: {msg}"),
+    });
+}
+
 /// Make headings links with anchor IDs and build up TOC.
 struct LinkReplacerInner<'a> {
     links: &'a [RenderedLink],
@@ -11,6 +11,14 @@ pub(crate) mod sources;
 pub(crate) mod static_files;
 pub(crate) mod toc;
 pub(crate) mod twoslash;
+// used by the fuzz targets in `fuzz/`
+#[cfg(fuzzing)]
+pub use twoslash::fuzzing as twoslash_fuzzing;
+// for tools embedding rustdoc, such as themes postprocessing twoslash annotations
+pub use twoslash::api as twoslash_api;
+// for callers running on tokio
+#[cfg(feature = "async")]
+pub use twoslash::async_analyzer as twoslash_async;
 mod url_parts_builder;
 
 #[cfg(test)]
@@ -6,92 +6,554 @@
 //! through rust-analyzer to extract type information for hover annotations.
 
 use once_cell::sync::Lazy;
-use std::sync::Mutex;
-use twoslash_rust::{Analyzer, AnalyzerSettings};
-
-/// Global analyzer instance (reused across code blocks)
-static ANALYZER: Lazy<Mutex<Analyzer>> = Lazy::new(|| {
-    let cargo_toml = resolve_cargo_toml();
-    Mutex::new(Analyzer::new(AnalyzerSettings {
-        cargo_toml,
-        target_dir: Some("/tmp/rustdoc-twoslash-cache".into()),
-    }))
+use regex::Regex;
+use rustc_data_structures::fx::FxHashMap;
+use serde::{Deserialize, Serialize};
+use std::{fmt, fs, iter, mem};
+use std::cell::RefCell;
+use std::ops::{Deref, DerefMut, Range};
+use std::panic::{self, AssertUnwindSafe};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Condvar, Mutex, Once, PoisonError};
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant, SystemTime};
+use twoslash_rust::{AnalysisResult, Analyzer, AnalyzerSettings};
+
+pub use self::config::TwoslashConfig;
+pub use self::render::sanitize_docs_for_attribute;
+use self::cache::{CARGO_LOCK, CacheKind, cached, read_cargo_lock};
+use self::config::{CONFIG, LogLevel, is_enabled};
+use self::manifest::{
+    CARGO_TOML, CargoResolver, DefaultCargoResolver, FALLBACK_CARGO_TOML, MANIFESTS,
+    block_cargo_toml, diagnostics_cargo_toml, edit_manifest, resolve_cargo_toml,
+    shared_cargo_toml, with_edition,
+};
+use self::render::{block_output, doc_url, normalize_type_text};
+
+/// Log a message at `level`, if [`LOG_LEVEL`](config::LOG_LEVEL) lets it through
+macro_rules! twoslash_log {
+    ($level:expr, $($arg:tt)*) => {
+        if $level <= *$crate::html::twoslash::config::LOG_LEVEL {
+            eprintln!("twoslash: {}", format_args!($($arg)*));
+        }
+    };
+}
+
+#[cfg(feature = "async")]
+pub mod async_analyzer;
+mod cache;
+mod config;
+mod manifest;
+mod render;
+
+/// Pool of analyzer instances, sized by [`TwoslashConfig::parallelism`] and reused across
+/// code blocks.
+///
+/// Instances are created on first use so single-threaded callers only ever pay for one.
+static ANALYZERS: Lazy<Pool<Analyzer>> = Lazy::new(|| {
+    if CONFIG.watch_cargo {
+        watch_manifests();
+    }
+    Pool::new(CONFIG.parallelism, new_analyzer)
 });
 
-/// Resolve the Cargo.toml to use for twoslash analysis.
+/// What analyzes code blocks: an [`Analyzer`], or a stand-in for one in tests
+pub trait AnalyzerTrait {
+    /// Hover information for the tokens of `code`, or why it couldn't be analyzed
+    fn analyze(&mut self, code: &str) -> Result<AnalysisResult, String>;
+}
+
+impl AnalyzerTrait for Analyzer {
+    fn analyze(&mut self, code: &str) -> Result<AnalysisResult, String> {
+        Analyzer::analyze(self, code).map_err(|e| e.to_string())
+    }
+}
+
+/// Create an analyzer for the given pool slot.
+///
+/// Each slot gets its own target directory so concurrent `cargo` invocations
+/// don't block on each other's build directory lock.
+fn new_analyzer(slot: usize) -> Result<Analyzer, TwoslashError> {
+    let target_dir = if slot == 0 {
+        CONFIG.target_dir.clone()
+    } else {
+        CONFIG.target_dir_with_suffix(&format!("-{slot}"))
+    };
+    analyzer_in(target_dir)
+}
+
+/// An analyzer for the shared Cargo.toml building in `target_dir`
+fn analyzer_in(target_dir: PathBuf) -> Result<Analyzer, TwoslashError> {
+    let settings = AnalyzerSettingsBuilder::default()
+        .cargo_toml(shared_cargo_toml())
+        .target_dir(Some(target_dir))
+        .build()
+        .map_err(TwoslashError::InvalidSettings)?;
+    Ok(new_analyzer_with(settings))
+}
+
+/// `Analyzer::new`, with the environment the analyzer runs Cargo in set up first.
 ///
-/// Checks RUSTDOC_TWOSLASH_CARGO_TOML env var first, then tries to find
-/// Cargo.toml relative to the current directory. This lets twoslash-rust
-/// scaffold temp projects with the same dependencies as the crate being documented.
-fn resolve_cargo_toml() -> Option<String> {
-    let cargo_path = std::env::var("RUSTDOC_TWOSLASH_CARGO_TOML")
-        .ok()
-        .unwrap_or_else(|| "Cargo.toml".to_string());
+/// AnalyzerSettings has no way to make the analyzer's Cargo run offline, but Cargo reads
+/// CARGO_NET_OFFLINE from the environment, which it inherits from rustdoc. So with
+/// [`TwoslashConfig::offline`], that's set before the first analyzer is created.
+fn new_analyzer_with(settings: AnalyzerSettings) -> Analyzer {
+    static CARGO_OFFLINE: Once = Once::new();
+    if CONFIG.offline {
+        CARGO_OFFLINE.call_once(|| {
+            // SAFETY: std's own accesses to the environment, which rustdoc's and the spawned
+            // `Command`s' go through, are synchronized with `set_var`. Nothing in rustdoc
+            // reads it through libc while code blocks are being rendered.
+            unsafe { std::env::set_var("CARGO_NET_OFFLINE", "true") };
+        });
+    }
+    Analyzer::new(settings)
+}
 
-    let content = match std::fs::read_to_string(&cargo_path) {
-        Ok(c) => c,
-        Err(_) => {
-            eprintln!("twoslash: no Cargo.toml found, external deps won't have annotations");
-            return None;
+/// Number of times the analyzers were [reinitialized](reinitialize_analyzer), for thread-local
+/// analyzers to notice theirs is outdated
+static ANALYZER_GENERATION: AtomicUsize = AtomicUsize::new(0);
+
+/// Number of threads that created a thread-local analyzer, each getting its own target dir
+static LOCAL_ANALYZER_THREADS: AtomicUsize = AtomicUsize::new(0);
+
+/// A thread-local analyzer, shared with the thread a timed analysis runs on
+type LocalAnalyzer = Arc<Mutex<Analyzer>>;
+
+thread_local! {
+    /// This thread's analyzer, with the [`ANALYZER_GENERATION`] it was created in and the
+    /// number of its target dir
+    static LOCAL_ANALYZER: RefCell<Option<(usize, usize, LocalAnalyzer)>> =
+        const { RefCell::new(None) };
+}
+
+/// This thread's own analyzer, created on first use like the pooled ones, so that threads
+/// never wait for each other's analyzers.
+///
+/// Each thread builds in a target dir of its own. The analyzer is replaced after a
+/// [`reinitialize_analyzer`], and lives until the thread exits. It's locked while analyzing,
+/// which only keeps this thread waiting when a timed out analysis is still running on it.
+fn local_analyzer() -> Result<LocalAnalyzer, TwoslashError> {
+    LOCAL_ANALYZER.with(|local| {
+        let mut local = local.borrow_mut();
+        let generation = ANALYZER_GENERATION.load(Ordering::Acquire);
+        let reusable = local.as_ref().is_some_and(|&(created_in, _, _)| created_in == generation);
+        if !reusable {
+            let dir_number = match local.take() {
+                Some((_, dir_number, _)) => dir_number,
+                None => LOCAL_ANALYZER_THREADS.fetch_add(1, Ordering::Relaxed),
+            };
+            let target_dir = CONFIG.target_dir_with_suffix(&format!("-thread{dir_number}"));
+            let analyzer = Arc::new(Mutex::new(analyzer_in(target_dir)?));
+            *local = Some((generation, dir_number, analyzer));
         }
+        let (_, _, analyzer) = local.as_ref().expect("just created");
+        Ok(Arc::clone(analyzer))
+    })
+}
+
+/// Run `f` with this thread's own analyzer, see [`local_analyzer`], failing if the analyzer
+/// can't be created.
+///
+/// `f` must not call `with_local_analyzer` itself, the analyzer is locked while it runs.
+pub fn with_local_analyzer<F, R>(f: F) -> Result<R, TwoslashError>
+where
+    F: FnOnce(&mut Analyzer) -> R,
+{
+    let analyzer = local_analyzer()?;
+    // A panic during an earlier analysis doesn't leave the analyzer unusable
+    let mut analyzer = analyzer.lock().unwrap_or_else(PoisonError::into_inner);
+    Ok(f(&mut analyzer))
+}
+
+/// Re-resolve the Cargo.toml of the crate being documented, and replace the analyzers so
+/// that the next code blocks are analyzed against it.
+///
+/// This is for watch-mode workflows that keep the process running while the manifest
+/// changes. Blocks being analyzed finish with their analyzer, which is replaced once it's
+/// returned. The manifest is looked for where it was first found; if it can't be read
+/// anymore, the analyzers are left as they are.
+pub fn reinitialize_analyzer() -> Result<(), TwoslashError> {
+    let resolved = resolve_cargo_toml(&DefaultCargoResolver, &CONFIG);
+    if resolved.is_none()
+        && let Some(path) = MANIFESTS.package.as_ref().or(MANIFESTS.workspace.as_ref())
+    {
+        return Err(TwoslashError::ManifestUnreadable(path.clone()));
+    }
+    *CARGO_TOML.write().unwrap_or_else(PoisonError::into_inner) = resolved;
+    *CARGO_LOCK.write().unwrap_or_else(PoisonError::into_inner) = read_cargo_lock();
+    ANALYZERS.reset();
+    ANALYZER_GENERATION.fetch_add(1, Ordering::Release);
+    // Taken out of the map first, so the lock isn't held while they shut down
+    let block_analyzers =
+        mem::take(&mut *BLOCK_ANALYZERS.lock().unwrap_or_else(PoisonError::into_inner));
+    drop(block_analyzers);
+    twoslash_log!(LogLevel::Info, "reinitialized the analyzers");
+    Ok(())
+}
+
+/// How often [`watch_manifests`] checks for changes
+const WATCH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Reinitialize the analyzers from a background thread whenever the Cargo.toml or
+/// Cargo.lock of the crate being documented changes, for [`TwoslashConfig::watch_cargo`].
+///
+/// Changes are noticed by polling modification times.
+fn watch_manifests() {
+    let paths: Vec<PathBuf> = [MANIFESTS.package.clone(), MANIFESTS.workspace.clone()]
+        .into_iter()
+        .chain([MANIFESTS.lock_file()])
+        .flatten()
+        .collect();
+    let modified = move || -> Vec<Option<SystemTime>> {
+        paths.iter().map(|path| fs::metadata(path).and_then(|meta| meta.modified()).ok()).collect()
     };
+    let mut last = modified();
+    let spawned = std::thread::Builder::new().name("twoslash-watch".into()).spawn(move || {
+        loop {
+            std::thread::sleep(WATCH_INTERVAL);
+            let current = modified();
+            if current == last {
+                continue;
+            }
+            last = current;
+            twoslash_log!(LogLevel::Info, "Cargo.toml or Cargo.lock changed");
+            if let Err(e) = reinitialize_analyzer() {
+                twoslash_log!(LogLevel::Error, "{e}");
+            }
+        }
+    });
+    if let Err(e) = spawned {
+        twoslash_log!(LogLevel::Warn, "can't watch Cargo.toml for changes: {e}");
+    }
+}
+
+/// A fixed-size pool of lazily created instances.
+///
+/// [`Pool::acquire`] hands out an idle instance, creates one while below capacity, and
+/// only blocks once every instance is in use. Each instance is created for a distinct
+/// slot number, which is what gives analyzers their own target directories. A slot whose
+/// instance fails to be created is tried again by the next `acquire`.
+struct Pool<T> {
+    capacity: usize,
+    create: Box<dyn Fn(usize) -> Result<T, TwoslashError> + Send + Sync>,
+    state: Mutex<PoolState<T>>,
+    returned: Condvar,
+}
+
+struct PoolState<T> {
+    /// Instances not currently acquired, with their slot
+    idle: Vec<(usize, T)>,
+    /// Number of slots handed out so far
+    created: usize,
+    /// Slots whose instance was dropped by [`Pool::reset`], to create a new one for
+    free: Vec<usize>,
+    /// Number of resets so far; instances acquired before the last one aren't reused
+    generation: usize,
+}
+
+impl<T> Pool<T> {
+    fn new(
+        capacity: usize,
+        create: impl Fn(usize) -> Result<T, TwoslashError> + Send + Sync + 'static,
+    ) -> Self {
+        Pool {
+            capacity: capacity.max(1),
+            create: Box::new(create),
+            state: Mutex::new(PoolState {
+                idle: Vec::new(),
+                created: 0,
+                free: Vec::new(),
+                generation: 0,
+            }),
+            returned: Condvar::new(),
+        }
+    }
+
+    fn acquire(&self) -> Result<PoolGuard<'_, T>, TwoslashError> {
+        // The state is only touched in small non-panicking sections, so it stays
+        // consistent even if a holder panicked while the lock was taken
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        loop {
+            let generation = state.generation;
+            if let Some((slot, item)) = state.idle.pop() {
+                return Ok(PoolGuard { pool: self, slot, generation, item: Some(item) });
+            }
+            let slot = state.free.pop().or_else(|| {
+                (state.created < self.capacity).then(|| {
+                    state.created += 1;
+                    state.created - 1
+                })
+            });
+            if let Some(slot) = slot {
+                drop(state);
+                // Created outside the lock, since starting an analyzer is slow
+                let item = (self.create)(slot).inspect_err(|_| {
+                    self.state.lock().unwrap_or_else(PoisonError::into_inner).free.push(slot);
+                    self.returned.notify_one();
+                })?;
+                return Ok(PoolGuard { pool: self, slot, generation, item: Some(item) });
+            }
+            state = self.returned.wait(state).unwrap_or_else(PoisonError::into_inner);
+        }
+    }
+
+    /// Drop the idle instances, and the acquired ones once they're returned, so that new
+    /// ones are created in their place
+    fn reset(&self) {
+        let mut state = self.state.lock().unwrap_or_else(PoisonError::into_inner);
+        state.generation += 1;
+        let idle = mem::take(&mut state.idle);
+        state.free.extend(idle.iter().map(|&(slot, _)| slot));
+        drop(state);
+        // Outside the lock, since shutting an analyzer down can take a while
+        drop(idle);
+        self.returned.notify_all();
+    }
+}
+
+/// An instance acquired from a [`Pool`], returned to it on drop
+struct PoolGuard<'a, T> {
+    pool: &'a Pool<T>,
+    slot: usize,
+    /// [`PoolState::generation`] when the instance was acquired
+    generation: usize,
+    item: Option<T>,
+}
+
+impl<T> Deref for PoolGuard<'_, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.item.as_ref().expect("pool guard holds its item until dropped")
+    }
+}
 
-    eprintln!("twoslash: using Cargo.toml from {}", cargo_path);
+impl<T> DerefMut for PoolGuard<'_, T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.item.as_mut().expect("pool guard holds its item until dropped")
+    }
+}
 
-    // Add the crate being documented as a path dependency so that
-    // code examples referencing `crate_name::foo` resolve correctly.
-    let crate_dir = std::env::current_dir().ok()?;
-    let augmented = inject_self_dependency(&content, &crate_dir.to_string_lossy());
-    Some(augmented)
+impl<T> Drop for PoolGuard<'_, T> {
+    fn drop(&mut self) {
+        if let Some(item) = self.item.take() {
+            let mut state = self.pool.state.lock().unwrap_or_else(PoisonError::into_inner);
+            if self.generation == state.generation {
+                state.idle.push((self.slot, item));
+            } else {
+                state.free.push(self.slot);
+                drop(state);
+                drop(item);
+            }
+            self.pool.returned.notify_one();
+        }
+    }
 }
 
-/// Inject the crate being documented as a path dependency.
+/// Analyzers for code blocks that need a manifest of their own, see [`block_cargo_toml`]: a
+/// pool of them for each manifest, keyed by the suffix of their target dirs.
 ///
-/// Parses the crate name from the Cargo.toml and adds it as:
-///   `crate_name = { path = "/path/to/crate" }`
-fn inject_self_dependency(cargo_toml: &str, crate_path: &str) -> String {
-    let name_line = cargo_toml
-        .lines()
-        .find(|l| l.trim().starts_with("name"));
+/// The map is only locked to find a pool, so that blocks are analyzed concurrently whatever
+/// their manifest.
+static BLOCK_ANALYZERS: Lazy<Mutex<FxHashMap<String, Arc<Pool<Analyzer>>>>> =
+    Lazy::new(Default::default);
 
-    let Some(name_line) = name_line else {
-        return cargo_toml.to_string();
+/// Run `f` with an analyzer whose temp projects use the given edition, and have `runtime`
+/// as a dependency, with `cargo_toml` being the [`block_cargo_toml`] for them
+fn with_block_analyzer<R>(
+    edition: Option<&str>,
+    runtime: Option<AsyncRuntime>,
+    cargo_toml: Option<String>,
+    f: impl FnOnce(&mut Analyzer) -> R,
+) -> Result<R, TwoslashError> {
+    let mut suffix = edition.map(|edition| format!("-edition{edition}")).unwrap_or_default();
+    if let Some(runtime) = runtime {
+        suffix.push_str(&format!("-{}", runtime.crate_name()));
+    }
+    let pool = {
+        let mut pools = BLOCK_ANALYZERS.lock().unwrap_or_else(PoisonError::into_inner);
+        let pool = pools.entry(suffix).or_insert_with_key(|suffix| {
+            let (suffix, edition) = (suffix.clone(), edition.map(String::from));
+            // Slots get target dirs of their own, as in `new_analyzer`
+            let create = move |slot| {
+                let suffix = if slot == 0 { suffix.clone() } else { format!("{suffix}-{slot}") };
+                let settings = AnalyzerSettingsBuilder::default()
+                    .cargo_toml(cargo_toml.clone())
+                    .target_dir(Some(CONFIG.target_dir_with_suffix(&suffix)))
+                    .edition(edition.clone())
+                    .build()
+                    .map_err(TwoslashError::InvalidSettings)?;
+                Ok(new_analyzer_with(settings))
+            };
+            Arc::new(Pool::new(CONFIG.parallelism, create))
+        });
+        Arc::clone(pool)
     };
+    let mut analyzer = pool.acquire()?;
+    Ok(f(&mut analyzer))
+}
 
-    let crate_name = match name_line.split('=').nth(1) {
-        Some(val) => val.trim().trim_matches('"').to_string(),
-        None => return cargo_toml.to_string(),
-    };
+/// Builder for the [`AnalyzerSettings`] of an analyzer instance.
+///
+/// `AnalyzerSettings` itself only has a manifest and a target dir, so the edition is folded
+/// into the manifest.
+#[derive(Debug, Clone, Default)]
+pub struct AnalyzerSettingsBuilder {
+    cargo_toml: Option<String>,
+    target_dir: Option<PathBuf>,
+    edition: Option<String>,
+}
+
+/// Invalid [`AnalyzerSettingsBuilder`] settings
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConfigError {
+    /// An edition that isn't one of [`EDITIONS`]
+    InvalidEdition(String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::InvalidEdition(edition) => write!(f, "invalid edition `{edition}`"),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl AnalyzerSettingsBuilder {
+    pub fn cargo_toml(mut self, cargo_toml: Option<String>) -> Self {
+        self.cargo_toml = cargo_toml;
+        self
+    }
+
+    pub fn target_dir(mut self, target_dir: Option<PathBuf>) -> Self {
+        self.target_dir = target_dir;
+        self
+    }
+
+    /// Edition of the temp projects, pinned in their manifest
+    pub fn edition(mut self, edition: Option<String>) -> Self {
+        self.edition = edition;
+        self
+    }
+
+    pub fn build(self) -> Result<AnalyzerSettings, ConfigError> {
+        let cargo_toml = match self.edition {
+            Some(edition) if !EDITIONS.contains(&edition.as_str()) => {
+                return Err(ConfigError::InvalidEdition(edition));
+            }
+            Some(edition) => Some(edit_manifest(
+                self.cargo_toml.as_deref().unwrap_or(FALLBACK_CARGO_TOML),
+                |doc| with_edition(doc, &edition),
+            )),
+            None => self.cargo_toml,
+        };
+        Ok(AnalyzerSettings { cargo_toml, target_dir: self.target_dir })
+    }
+}
+
+/// Lexical class of the text an annotation covers, see [`classify_token`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenClass {
+    /// A name, including raw identifiers, lifetimes and the path keywords `self`, `Self`,
+    /// `super` and `crate`, which name things too
+    Identifier,
+    /// A reserved word other than the path keywords
+    Keyword,
+    /// `+`, `->`, `==`, `&`, `?` and the like
+    Operator,
+    /// `,`, `;`, `:`, `::`, `#`, `$` and brackets
+    Punctuation,
+    /// A number, string, char or boolean literal
+    Literal,
+    /// Empty, whitespace, or more than one token
+    #[default]
+    Unknown,
+}
+
+/// Reserved words other than the path keywords, which [`TokenClass`] counts as identifiers
+const KEYWORDS: &[&str] = &[
+    "abstract", "as", "async", "await", "become", "box", "break", "const", "continue", "do",
+    "dyn", "else", "enum", "extern", "final", "fn", "for", "gen", "if", "impl", "in", "let",
+    "loop", "macro", "match", "mod", "move", "mut", "override", "priv", "pub", "ref", "return",
+    "static", "struct", "trait", "try", "type", "typeof", "unsafe", "unsized", "use", "virtual",
+    "where", "while", "yield",
+];
 
-    // Rename the temp project to avoid circular dependency
-    let mut result = cargo_toml.replacen(
-        name_line,
-        &format!("name = \"twoslash-rustdoc-tmp\""),
-        1,
-    );
-
-    // Use underscore form for the dependency key (Cargo normalizes hyphens)
-    let dep_key = crate_name.replace('-', "_");
-    let dep_line = format!(
-        "{} = {{ path = \"{}\", package = \"{}\" }}",
-        dep_key, crate_path, crate_name,
-    );
-
-    // Check if [dependencies] section exists
-    if let Some(pos) = result.find("[dependencies]") {
-        let after = pos + "[dependencies]".len();
-        eprintln!("twoslash: adding self-dependency: {}", dep_line);
-        result = format!("{}\n{}{}", &result[..after], dep_line, &result[after..]);
+/// Class of the single token at `start..start + length` in `code`.
+///
+/// This is a minimal lexer for the short ranges the analyzer hovers: a range holding more
+/// than one token, or not fitting `code`, is [`TokenClass::Unknown`].
+pub fn classify_token(code: &str, start: usize, length: usize) -> TokenClass {
+    let Some(token) = code.get(start..start.saturating_add(length)) else {
+        return TokenClass::Unknown;
+    };
+    let Some(first) = token.chars().next() else { return TokenClass::Unknown };
+    let is_ident = |text: &str| rustc_lexer::is_ident(text.strip_prefix("r#").unwrap_or(text));
+    let is_literal_suffix = |text: &str| text.chars().all(rustc_lexer::is_id_continue);
+    if token == "true" || token == "false" {
+        TokenClass::Literal
+    } else if KEYWORDS.contains(&token) {
+        TokenClass::Keyword
+    } else if is_ident(token) || (first == '\'' && token.len() > 1 && is_ident(&token[1..])) {
+        TokenClass::Identifier
+    } else if first.is_ascii_digit() && token.splitn(2, '.').all(is_literal_suffix) {
+        // `1`, `0x1f`, `1_000u32`, `1.5`, `2.`
+        TokenClass::Literal
+    } else if is_quoted_literal(token) {
+        TokenClass::Literal
+    } else if token.chars().all(|c| "+-*/%^!&|=<>@.~?".contains(c)) {
+        TokenClass::Operator
+    } else if token == "::" || (token.len() == 1 && ",;:#$()[]{}".contains(first)) {
+        TokenClass::Punctuation
     } else {
-        eprintln!("twoslash: adding self-dependency: {}", dep_line);
-        result = format!("{}\n[dependencies]\n{}\n", result, dep_line);
+        TokenClass::Unknown
+    }
+}
+
+/// Whether `token` is exactly one string, byte string, C string or char literal, raw or not
+fn is_quoted_literal(token: &str) -> bool {
+    let unprefixed = token.strip_prefix(['b', 'c']).unwrap_or(token);
+    if let Some(raw) = unprefixed.strip_prefix('r') {
+        let hashes = raw.len() - raw.trim_start_matches('#').len();
+        let closing = format!("\"{}", "#".repeat(hashes));
+        return raw[hashes..].starts_with('"')
+            && raw.len() >= 2 * hashes + 2
+            && raw.ends_with(&closing)
+            && !raw[hashes + 1..raw.len() - closing.len()].contains(&closing);
     }
+    if unprefixed.starts_with('\'') {
+        return char_literal_len(unprefixed, 0) == Some(unprefixed.len());
+    }
+    let Some(body) = unprefixed.strip_prefix('"').and_then(|rest| rest.strip_suffix('"')) else {
+        return false;
+    };
+    // The closing quote is the first unescaped one
+    let mut escaped = false;
+    body.chars().all(|c| {
+        let ok = escaped || c != '"';
+        escaped = !escaped && c == '\\';
+        ok
+    }) && !escaped
+}
 
-    result
+/// What separates the signature in a hover text from the docs following it
+pub(crate) const DOCS_SEPARATOR: &str = "\n\n---\n\n";
+
+/// The signature in a hover text, and the docs after the [`DOCS_SEPARATOR`] if there are any
+pub(crate) fn type_and_docs(type_text: &str) -> (&str, Option<&str>) {
+    match type_text.split_once(DOCS_SEPARATOR) {
+        Some((signature, docs)) => (signature, Some(docs)),
+        None => (type_text, None),
+    }
 }
 
 /// Information about a type annotation to render
-#[derive(Debug, Clone)]
+#[derive(Clone, Serialize, Deserialize)]
 pub struct TypeAnnotation {
     /// Byte offset in the code where the token starts
     pub start: u32,
@@ -101,173 +563,2703 @@ pub struct TypeAnnotation {
     pub type_text: String,
     /// Optional documentation
     pub docs: Option<String>,
+    /// Syntactic role of the annotated token, or what produced the annotation
+    #[serde(default)]
+    pub kind: TokenKind,
+    /// Whether the token is a macro call (`name!`), whose hover describes the expansion
+    #[serde(default)]
+    pub macro_expanded: bool,
+    /// Documentation page of the hovered item or type, if one could be derived
+    #[serde(default)]
+    pub url: Option<String>,
+    /// How important the annotation is to show when space is short, 0 being the most. Set
+    /// from `kind` by default, see [`TokenKind::default_priority`].
+    #[serde(default)]
+    pub priority: u8,
+    /// Lexical class of the annotated text, see [`classify_token`]
+    #[serde(default)]
+    pub token_class: TokenClass,
 }
 
-/// Check if twoslash processing is enabled
-pub fn is_enabled() -> bool {
-    std::env::var("RUSTDOC_TWOSLASH").is_ok()
+/// Annotations are ordered, compare equal and hash by `(start, length, type_text)`, which
+/// is source order first.
+///
+/// The type text is what the annotation says, while `docs`, `kind` and the rest are
+/// metadata, so two annotations of the same range with the same type text but different
+/// docs are duplicates, as for a `HashSet` or `sort` followed by `dedup`.
+impl Ord for TypeAnnotation {
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        (self.start, self.length)
+            .cmp(&(other.start, other.length))
+            .then_with(|| self.type_text.cmp(&other.type_text))
+    }
 }
 
-/// Item-level keyword prefixes that indicate top-level declarations
-const ITEM_KEYWORDS: &[&str] = &[
-    "fn ", "struct ", "enum ", "impl ", "trait ", "mod ",
-    "pub ", "extern ", "const ", "static ", "type ",
-    "use ", "#[", "#!",
-];
+impl PartialOrd for TypeAnnotation {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-/// Check if a line starts a top-level item
-fn is_item_line(line: &str) -> bool {
-    let trimmed = line.trim();
-    if trimmed.is_empty() {
-        return false;
+impl PartialEq for TypeAnnotation {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other).is_eq()
     }
-    ITEM_KEYWORDS.iter().any(|kw| trimmed.starts_with(kw))
 }
 
-/// Split code into preamble (top-level items) and body (statements).
-///
-/// Handles mixed code like:
-/// ```text
-/// fn helper() -> i32 { 42 }     // preamble
-///                                // preamble (blank line)
-/// let x = helper();              // body (needs fn main wrapper)
-/// ```
+impl Eq for TypeAnnotation {}
+
+impl std::hash::Hash for TypeAnnotation {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        (self.start, self.length, &self.type_text).hash(state);
+    }
+}
+
+/// Prints only the type, like `i32`, then the docs as a second paragraph if there are any.
 ///
-/// Returns (preamble, body) where preamble contains complete item definitions
-/// and body contains statement-level code that needs fn main() wrapping.
-/// If no wrapping is needed, body is empty.
-fn split_items_and_statements(code: &str) -> (String, String) {
-    // If code already has fn main, no splitting needed
-    if code.contains("fn main") {
-        return (code.to_string(), String::new());
+/// This is not the `<token>: <type>` display: an annotation can't see the source it was
+/// produced for, so it has no token to print. That display is
+/// [`TypeAnnotation::display(code)`](TypeAnnotation::display), which takes the source.
+impl fmt::Display for TypeAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.type_text)?;
+        match &self.docs {
+            Some(docs) => write!(f, "\n\n{docs}"),
+            None => Ok(()),
+        }
     }
+}
 
-    let lines: Vec<&str> = code.lines().collect();
-    let mut brace_depth: i32 = 0;
-    let mut split_point = 0; // byte offset where body starts
-    let mut line_byte_offset = 0;
+/// Every field, with the byte range in place of `start` and `length`
+impl fmt::Debug for TypeAnnotation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypeAnnotation")
+            .field("range", &self.byte_range())
+            .field("type_text", &self.type_text)
+            .field("docs", &self.docs)
+            .field("kind", &self.kind)
+            .field("macro_expanded", &self.macro_expanded)
+            .field("url", &self.url)
+            .field("priority", &self.priority)
+            .field("token_class", &self.token_class)
+            .finish()
+    }
+}
 
-    for (_i, line) in lines.iter().enumerate() {
-        let trimmed = line.trim();
+/// An annotation displayed with the text it annotates, see [`TypeAnnotation::display`]
+pub struct AnnotationDisplay<'a> {
+    annotation: &'a TypeAnnotation,
+    code: &'a str,
+}
 
-        if brace_depth > 0 {
-            // Inside a braced item, track depth
-            brace_depth += trimmed.chars().filter(|&c| c == '{').count() as i32;
-            brace_depth -= trimmed.chars().filter(|&c| c == '}').count() as i32;
-            line_byte_offset += line.len() + 1; // +1 for newline
-            if brace_depth <= 0 {
-                brace_depth = 0;
-                split_point = line_byte_offset;
+impl fmt::Display for AnnotationDisplay<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.annotation.inline_label(self.code))?;
+        match &self.annotation.docs {
+            Some(docs) => write!(f, "\n\n{docs}"),
+            None => Ok(()),
+        }
+    }
+}
+
+/// An annotation whose byte range doesn't fit the code it was produced for
+#[derive(Debug, Clone, PartialEq)]
+pub enum AnnotationError {
+    /// The range ends past the end of the code
+    OutOfBounds { start: u32, length: u32, code_len: usize },
+    /// The range starts inside a multi-byte character
+    MisalignedStart { byte_offset: usize },
+    /// The range ends inside a multi-byte character
+    MisalignedEnd { byte_offset: usize },
+}
+
+impl fmt::Display for AnnotationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AnnotationError::OutOfBounds { start, length, code_len } => write!(
+                f,
+                "annotation at bytes {start}..{}, past the end of the code ({code_len} bytes)",
+                *start as u64 + *length as u64,
+            ),
+            AnnotationError::MisalignedStart { byte_offset } => {
+                write!(f, "annotation starts at byte {byte_offset}, inside a character")
+            }
+            AnnotationError::MisalignedEnd { byte_offset } => {
+                write!(f, "annotation ends at byte {byte_offset}, inside a character")
             }
-            continue;
         }
+    }
+}
 
-        if trimmed.is_empty() {
-            // Blank lines between items are part of preamble
-            line_byte_offset += line.len() + 1;
-            split_point = line_byte_offset;
-            continue;
+impl std::error::Error for AnnotationError {}
+
+impl TypeAnnotation {
+    /// An annotation of the `length` bytes at `start`, without docs and of unknown kind
+    pub fn new(start: u32, length: u32, type_text: impl Into<String>) -> TypeAnnotation {
+        TypeAnnotation {
+            start,
+            length,
+            type_text: type_text.into(),
+            docs: None,
+            kind: TokenKind::Unknown,
+            macro_expanded: false,
+            url: None,
+            priority: TokenKind::Unknown.default_priority(),
+            token_class: TokenClass::Unknown,
+        }
+    }
+
+    fn byte_range(&self) -> Range<usize> {
+        let start = self.start as usize;
+        start..start + self.length as usize
+    }
+
+    /// Check that the annotation's byte range lies within `code` on character boundaries
+    pub fn validate(&self, code: &str) -> Result<(), AnnotationError> {
+        let range = self.byte_range();
+        if range.end > code.len() {
+            let (start, length) = (self.start, self.length);
+            return Err(AnnotationError::OutOfBounds { start, length, code_len: code.len() });
         }
+        if !code.is_char_boundary(range.start) {
+            return Err(AnnotationError::MisalignedStart { byte_offset: range.start });
+        }
+        if !code.is_char_boundary(range.end) {
+            return Err(AnnotationError::MisalignedEnd { byte_offset: range.end });
+        }
+        Ok(())
+    }
+
+    /// Displays as `<token>: <type>`, like `x: i32` for the `x` of `let x = 1;` in `code`,
+    /// then the docs as a second paragraph if there are any
+    pub fn display<'a>(&'a self, code: &'a str) -> AnnotationDisplay<'a> {
+        AnnotationDisplay { annotation: self, code }
+    }
+
+    /// The text in `code` the annotation describes, or `None` if its range doesn't fit `code`
+    #[inline]
+    pub fn source_text<'a>(&self, code: &'a str) -> Option<&'a str> {
+        code.get(self.byte_range())
+    }
 
-        if is_item_line(trimmed) {
-            // Count braces on this line
-            brace_depth += trimmed.chars().filter(|&c| c == '{').count() as i32;
-            brace_depth -= trimmed.chars().filter(|&c| c == '}').count() as i32;
-            if brace_depth < 0 {
-                brace_depth = 0;
+    /// The annotation as a comment to put after its line in plain-text output, as
+    /// `// : i32`, or `// error: message` for a diagnostic.
+    ///
+    /// The type is on one line and without documentation: a binding's type, a function's
+    /// signature without the name (`fn(x: u8) -> u8`), or the hover signature otherwise.
+    pub fn to_inline_comment(&self, code: &str) -> String {
+        match self.kind {
+            TokenKind::CompileError | TokenKind::CompileWarning => {
+                format!("// {}", self.inline_label(code))
             }
-            line_byte_offset += line.len() + 1;
-            if brace_depth == 0 {
-                split_point = line_byte_offset;
+            _ => format!("// : {}", self.inline_type(code)),
+        }
+    }
+
+    /// `name: Type`, or `error: message` for a diagnostic, to tell apart the annotations
+    /// sharing a comment
+    fn inline_label(&self, code: &str) -> String {
+        let ty = self.inline_type(code);
+        match self.kind {
+            TokenKind::CompileError => format!("error: {ty}"),
+            TokenKind::CompileWarning => format!("warning: {ty}"),
+            _ => format!("{}: {ty}", self.source_text(code).unwrap_or_default()),
+        }
+    }
+
+    fn inline_type(&self, code: &str) -> String {
+        let signature = type_and_docs(&self.type_text).0;
+        if matches!(self.kind, TokenKind::CompileError | TokenKind::CompileWarning) {
+            return squash_whitespace(signature);
+        }
+        let name = self.source_text(code).unwrap_or_default();
+        let is_fn_name = |&(i, _): &(usize, &str)| {
+            let after = &signature[i + 3 + name.len()..];
+            !after.starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        };
+        let fn_item = format!("fn {name}");
+        if !name.is_empty()
+            && let Some((i, _)) = signature.match_indices(&fn_item).find(is_fn_name)
+        {
+            let line_start = signature[..i].rfind('\n').map_or(0, |i| i + 1);
+            let qualifiers = strip_visibility(&signature[line_start..i]);
+            return squash_whitespace(&format!("{qualifiers}fn{}", &signature[i + fn_item.len()..]));
+        }
+        match declared_type(signature) {
+            Some(ty) => squash_whitespace(ty),
+            None => {
+                let line = signature.lines().map(str::trim).rfind(|line| !line.is_empty());
+                squash_whitespace(line.unwrap_or_default())
             }
-            continue;
         }
+    }
+}
+
+/// The kind of token a type annotation belongs to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum TokenKind {
+    /// Hover information for a token whose kind couldn't be determined. Caches written before
+    /// it was renamed call it `Hover`.
+    #[default]
+    #[serde(alias = "Hover")]
+    Unknown,
+    /// Answer to a `// ^?` query comment
+    QueryResult,
+    /// A `let` binding or other local
+    Variable,
+    Function,
+    /// A struct, enum, union, trait or type alias
+    Type,
+    Field,
+    /// A function taking `self`
+    Method,
+    Macro,
+    /// A `const` or `static`
+    Constant,
+    /// A module or extern crate
+    Module,
+    Lifetime,
+    /// A compiler error, with the message as `type_text`
+    CompileError,
+    /// A compiler warning, with the message as `type_text`
+    CompileWarning,
+}
+
+impl TokenKind {
+    /// Value of the `data-kind` attribute marking annotated tokens of this kind in HTML
+    pub fn css_name(self) -> Option<&'static str> {
+        Some(match self {
+            TokenKind::Unknown => return None,
+            TokenKind::QueryResult => "query",
+            TokenKind::Variable => "variable",
+            TokenKind::Function => "function",
+            TokenKind::Type => "type",
+            TokenKind::Field => "field",
+            TokenKind::Method => "method",
+            TokenKind::Macro => "macro",
+            TokenKind::Constant => "constant",
+            TokenKind::Module => "module",
+            TokenKind::Lifetime => "lifetime",
+            TokenKind::CompileError => "compile-error",
+            TokenKind::CompileWarning => "compile-warning",
+        })
+    }
+
+    /// Classify a token from the hover signature the analyzer returned for it.
+    ///
+    /// The analyzer doesn't report symbol kinds, but rust-analyzer's signatures start with
+    /// the defining keyword (`let`, `fn`, `struct`, ...). Fields and locals without `let`
+    /// (parameters, closure arguments) both render as `name: Type`; members come with a
+    /// path line naming their container, which is what tells a field apart.
+    pub fn from_analyzer_kind(type_text: &str) -> TokenKind {
+        let signature = type_and_docs(type_text).0;
+        let mut has_container = false;
+        for line in signature.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            if let Some(kind) = Self::from_declaration(line, has_container) {
+                return kind;
+            }
+            has_container = true;
+        }
+        TokenKind::Unknown
+    }
 
-        // This line is a statement, everything from here is body
-        break;
+    /// Classify a token from its hover signature, if the analyzer had one for it, like
+    /// [`from_analyzer_kind`](Self::from_analyzer_kind). No (or a blank) signature gives
+    /// [`TokenKind::Unknown`].
+    pub fn from_opt_kind(signature: Option<&str>) -> TokenKind {
+        let signature = signature.filter(|signature| !signature.trim().is_empty());
+        signature.map_or(TokenKind::Unknown, Self::from_analyzer_kind)
     }
 
-    let split_point = split_point.min(code.len());
-    let preamble = &code[..split_point];
-    let body = &code[split_point..];
+    /// Whether the kind was determined, so kind-specific rendering applies
+    pub fn is_known(self) -> bool {
+        self != TokenKind::Unknown
+    }
 
-    if body.trim().is_empty() {
-        // All items, no wrapping needed
-        (code.to_string(), String::new())
-    } else {
-        (preamble.to_string(), body.to_string())
+    /// [`TypeAnnotation::priority`] of the annotations of this kind, 0 being the most
+    /// important:
+    ///
+    /// | Kind | Priority |
+    /// |---|---|
+    /// | `QueryResult` | 0 |
+    /// | `CompileError` | 2 |
+    /// | `CompileWarning` | 4 |
+    /// | `Variable` | 10 |
+    /// | `Type` | 20 |
+    /// | `Macro` | 30 |
+    /// | `Function`, `Method` | 40 |
+    /// | `Field` | 50 |
+    /// | `Constant` | 60 |
+    /// | `Module` | 70 |
+    /// | `Lifetime` | 80 |
+    /// | `Unknown` | 90 |
+    ///
+    /// Query results were asked for by the author and diagnostics are what the example is
+    /// about. After them come the types the source leaves out the most: those of bindings,
+    /// which are usually inferred, then what a type name stands for and what a macro
+    /// expands to. Signatures of functions, fields and constants are mostly spelled out
+    /// where they're defined, and modules and lifetimes tell the least. The gaps leave room
+    /// for a [postprocessor](render::set_annotation_postprocessor) to rank annotations in between.
+    pub fn default_priority(self) -> u8 {
+        match self {
+            TokenKind::QueryResult => 0,
+            TokenKind::CompileError => 2,
+            TokenKind::CompileWarning => 4,
+            TokenKind::Variable => 10,
+            TokenKind::Type => 20,
+            TokenKind::Macro => 30,
+            TokenKind::Function | TokenKind::Method => 40,
+            TokenKind::Field => 50,
+            TokenKind::Constant => 60,
+            TokenKind::Module => 70,
+            TokenKind::Lifetime => 80,
+            TokenKind::Unknown => 90,
+        }
     }
-}
 
-/// Process a code block and extract type annotations
-pub fn process_code_block(code: &str) -> Vec<TypeAnnotation> {
-    let mut analyzer = match ANALYZER.lock() {
-        Ok(a) => a,
-        Err(_) => return vec![],
-    };
+    fn from_declaration(line: &str, has_container: bool) -> Option<TokenKind> {
+        let decl = strip_visibility(line);
+        // Function qualifiers, including `const` as in `const fn`, which isn't a constant
+        let mut function = decl;
+        while let Some(rest) =
+            ["const ", "default ", "async ", "unsafe ", "extern \"C\" ", "extern \"Rust\" "]
+                .iter()
+                .find_map(|qualifier| function.strip_prefix(qualifier))
+        {
+            function = rest.trim_start();
+        }
+        let decl = if function.starts_with("fn ") {
+            function
+        } else {
+            decl.strip_prefix("unsafe ").unwrap_or(decl)
+        };
 
-    // Split into preamble (top-level items) and body (statements needing fn main)
-    let (preamble, body) = split_items_and_statements(code);
-    let needs_wrap = !body.is_empty();
-    let (wrapped_code, preamble_len, fn_main_offset) = if needs_wrap {
-        let fn_main_prefix = "fn main() {\n";
-        let suffix = "\n}";
-        let wrapped = format!("{}{}{}{}", preamble, fn_main_prefix, body, suffix);
-        (wrapped, preamble.len() as u32, fn_main_prefix.len() as u32)
-    } else {
-        (code.to_string(), 0, 0)
-    };
+        let keyword = |keywords: &[&str]| keywords.iter().any(|k| decl.starts_with(k));
+        let kind = if keyword(&["let "]) {
+            TokenKind::Variable
+        } else if let Some(rest) = decl.strip_prefix("fn ") {
+            if takes_self(rest) { TokenKind::Method } else { TokenKind::Function }
+        } else if keyword(&["struct ", "enum ", "union ", "trait ", "type "]) {
+            TokenKind::Type
+        } else if keyword(&["macro_rules! ", "macro "]) {
+            TokenKind::Macro
+        } else if keyword(&["const ", "static "]) {
+            TokenKind::Constant
+        } else if keyword(&["mod ", "extern crate ", "crate "]) {
+            TokenKind::Module
+        } else if decl.starts_with('\'') {
+            TokenKind::Lifetime
+        } else if is_binding(decl) {
+            if has_container { TokenKind::Field } else { TokenKind::Variable }
+        } else {
+            return None;
+        };
+        Some(kind)
+    }
+}
 
-    match analyzer.analyze(&wrapped_code) {
-        Ok(result) => {
-            result
-                .static_quick_infos
-                .into_iter()
-                .filter_map(|info| {
-                    // Adjust offsets for wrapped code
-                    // Positions in the wrapped code:
-                    // - 0..preamble_len: preamble items (same positions in original)
-                    // - preamble_len..(preamble_len+fn_main_offset): "fn main() {\n" (skip)
-                    // - (preamble_len+fn_main_offset)..end: body code (subtract fn_main_offset)
-                    let wrapper_start = preamble_len + fn_main_offset;
-                    
-                    let adjusted_start = if fn_main_offset == 0 {
-                        // No wrapping, use as-is
-                        info.start
-                    } else if info.start < preamble_len {
-                        // In preamble section, position is same in original
-                        info.start
-                    } else if info.start < wrapper_start {
-                        // In "fn main() {\n" part, skip this annotation
-                        return None;
-                    } else {
-                        // In the body of fn main, subtract fn_main_offset
-                        info.start - fn_main_offset
-                    };
-                    
-                    // Skip annotations that extend past the original code
-                    let original_len = code.len() as u32;
-                    if adjusted_start >= original_len {
-                        return None;
-                    }
-                    
-                    // Skip annotations for single-character tokens that are likely operators/punctuation
-                    if info.length <= 1 {
-                        return None;
-                    }
-                    
-                    Some(TypeAnnotation {
-                        start: adjusted_start,
-                        length: info.length,
-                        type_text: info.text,
-                        docs: info.docs,
-                    })
-                })
-                .collect()
+/// `line` without a leading `pub`, `pub(crate)`, `pub(in path)`, ...
+fn strip_visibility(line: &str) -> &str {
+    let Some(rest) = line.strip_prefix("pub") else { return line };
+    if let Some(restricted) = rest.strip_prefix('(') {
+        return restricted.split_once(')').map_or(line, |(_, rest)| rest.trim_start());
+    }
+    if rest.starts_with(' ') { rest.trim_start() } else { line }
+}
+
+/// Whether the signature after `fn ` has a `self` receiver
+fn takes_self(after_fn: &str) -> bool {
+    let Some((_, params)) = after_fn.split_once('(') else { return false };
+    let mut receiver = params.trim_start();
+    if let Some(rest) = receiver.strip_prefix('&') {
+        receiver = rest.trim_start();
+        if receiver.starts_with('\'') {
+            receiver = receiver.split_once(' ').map_or("", |(_, rest)| rest);
         }
-        Err(e) => {
-            eprintln!("twoslash error: {}", e);
-            vec![]
+    }
+    let receiver = receiver.strip_prefix("mut ").unwrap_or(receiver);
+    receiver
+        .strip_prefix("self")
+        .is_some_and(|rest| !rest.starts_with(|c: char| c.is_alphanumeric() || c == '_'))
+}
+
+/// Whether `decl` has the `name: Type` form
+fn is_binding(decl: &str) -> bool {
+    let decl = decl.strip_prefix("mut ").unwrap_or(decl);
+    let Some((name, ty)) = decl.split_once(':') else { return false };
+    !name.is_empty()
+        && name.chars().all(|c| c.is_alphanumeric() || c == '_')
+        && !ty.starts_with(':')
+}
+
+/// Editions accepted in code block attributes
+const EDITIONS: &[&str] = &["2015", "2018", "2021", "2024"];
+
+/// Per-block twoslash settings from a fenced code block's info string
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BlockAttrs {
+    /// `twoslash` / `no-twoslash`: overrides RUSTDOC_TWOSLASH for this block
+    pub enabled: Option<bool>,
+    /// `no-annotations`: analyze the block (so `// ^?` queries are checked) but show no hovers
+    pub no_annotations: bool,
+    /// `edition2018` / `edition=2018`: edition to analyze the block with
+    pub edition: Option<String>,
+    /// `files`: the block holds several files, each after a `// @filename: name.rs` line
+    pub files: bool,
+    /// `dedent`: remove the indentation the block's lines share before analyzing it, as
+    /// with RUSTDOC_TWOSLASH_DEDENT
+    pub dedent: bool,
+    /// rustdoc's `ignore`: the block isn't processed at all, whatever `enabled` says
+    pub ignore: bool,
+    /// rustdoc's `no_run`, which doesn't change how the block is processed
+    pub no_run: bool,
+}
+
+impl BlockAttrs {
+    /// Whether the block should be processed, taking the global setting into account
+    pub fn enabled(&self) -> bool {
+        !self.ignore && self.enabled.unwrap_or_else(is_enabled)
+    }
+}
+
+/// Parse the twoslash attributes out of a code block info string like `rust,twoslash,edition2018`.
+///
+/// Tokens that aren't twoslash attributes are ignored. Note that rustdoc's own info string
+/// parser rejects `=` outside of `{...}`, so `edition2018` is the spelling that keeps the
+/// block rendered as Rust.
+///
+/// rustdoc's own attributes affect twoslash like this:
+///
+/// | Attribute                          | Blocks with it are                                  |
+/// |------------------------------------|-----------------------------------------------------|
+/// | `ignore`                           | skipped, since they may not even parse as Rust      |
+/// | `ignore-<target>`                  | analyzed, being Rust for the other targets          |
+/// | `no_run`                           | analyzed, since analysis never runs the code        |
+/// | `should_panic`                     | analyzed                                            |
+/// | `compile_fail`                     | analyzed, and given the compiler's diagnostics      |
+/// | `edition2015` ... `edition2024`    | analyzed with that edition                          |
+/// | `test_harness`, `standalone_crate` | analyzed like any other block                       |
+/// | another language, such as `text`   | not highlighted by rustdoc, so never processed      |
+pub fn parse_block_attrs(info: &str) -> BlockAttrs {
+    let mut attrs = BlockAttrs::default();
+    for token in info.split([',', ' ', '\t']).map(str::trim).filter(|t| !t.is_empty()) {
+        match token {
+            "twoslash" => attrs.enabled = Some(true),
+            "no-twoslash" => attrs.enabled = Some(false),
+            "no-annotations" => attrs.no_annotations = true,
+            "files" => attrs.files = true,
+            "dedent" => attrs.dedent = true,
+            "ignore" => attrs.ignore = true,
+            "no_run" => attrs.no_run = true,
+            _ => {
+                let edition = token.strip_prefix("edition").map(|e| e.trim_start_matches('='));
+                if let Some(edition) = edition.filter(|e| EDITIONS.contains(e)) {
+                    attrs.edition = Some(edition.to_string());
+                }
+            }
         }
     }
+    attrs
+}
+
+/// Item-level keyword prefixes that indicate top-level declarations
+const ITEM_KEYWORDS: &[&str] = &[
+    "fn ", "struct ", "enum ", "impl ", "trait ", "mod ",
+    "pub ", "extern ", "const ", "static ", "type ",
+    "use ", "#[", "#!",
+];
+
+/// Check if a line starts a top-level item
+fn is_item_line(line: &str) -> bool {
+    let trimmed = line.trim();
+    if trimmed.is_empty() {
+        return false;
+    }
+    ITEM_KEYWORDS.iter().any(|kw| trimmed.starts_with(kw))
 }
+
+/// How [`split_items_and_statements`] treats a line that isn't inside an item
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LineKind {
+    /// Starts a top-level item (or an attribute on one)
+    Item,
+    /// Code that has to go into `fn main`
+    Statement,
+    /// Nothing but whitespace or comments
+    Blank,
+}
+
+/// Whether `rest` starts with a name and a type, as in the `X: i32` of `const X: i32`
+fn starts_with_typed_name(rest: &str) -> bool {
+    let name_len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+    let after = rest[name_len..].trim_start();
+    name_len > 0 && after.starts_with(':') && !after.starts_with("::")
+}
+
+/// Classify a line of a code block that isn't inside an item.
+///
+/// `const` and `static` also start expressions, namely `const { ... }` blocks and
+/// `static ||` coroutines, so they only start an item when a name and type or a function
+/// follow. Likewise for `async` and `unsafe` blocks versus `async fn` and `unsafe impl`.
+fn classify_line(line: &str) -> LineKind {
+    let trimmed = line.trim();
+    if trimmed.is_empty() || trimmed.starts_with("//") || trimmed.starts_with("/*") {
+        return LineKind::Blank;
+    }
+    let keyword_rest = |keyword: &str| {
+        let rest = trimmed.strip_prefix(keyword)?;
+        (!rest.starts_with(|c: char| c.is_alphanumeric() || c == '_')).then(|| rest.trim_start())
+    };
+    let is_item = if let Some(rest) = keyword_rest("const") {
+        ["fn ", "unsafe ", "async ", "extern "].iter().any(|k| rest.starts_with(k))
+            || starts_with_typed_name(rest)
+    } else if let Some(rest) = keyword_rest("static") {
+        starts_with_typed_name(rest.strip_prefix("mut ").unwrap_or(rest))
+    } else if let Some(rest) = keyword_rest("async") {
+        ["fn ", "unsafe "].iter().any(|k| rest.starts_with(k))
+    } else if let Some(rest) = keyword_rest("unsafe") {
+        ["fn ", "impl", "trait ", "extern "].iter().any(|k| rest.starts_with(k))
+    } else if keyword_rest("impl").is_some() {
+        // Including generic ones, `impl<T>`, with no space after the keyword
+        true
+    } else {
+        is_item_line(trimmed)
+    };
+    if is_item { LineKind::Item } else { LineKind::Statement }
+}
+
+/// Where [`nesting_delta`] is at the end of a line, for token kinds that can span lines
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum LexState {
+    Code,
+    /// Inside a `"..."` string
+    Str,
+    /// Inside a raw string, closed by `"` followed by this many `#`
+    RawStr(usize),
+    /// Inside a block comment, nested this deep
+    BlockComment(usize),
+}
+
+fn is_ident_byte(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b == b'_'
+}
+
+/// Number of `#` of a raw string literal starting at the `r` at `i`, if one does.
+///
+/// That's `r"`, `r#"` and so on, optionally prefixed by `b` or `c`, but not the end of an
+/// identifier like `bar"`.
+fn raw_string_hashes(bytes: &[u8], i: usize) -> Option<usize> {
+    let ident_start = match i.checked_sub(1).map(|j| bytes[j]) {
+        None => true,
+        Some(b'b' | b'c') => i < 2 || !is_ident_byte(bytes[i - 2]),
+        Some(prev) => !is_ident_byte(prev),
+    };
+    if !ident_start {
+        return None;
+    }
+    let hashes = bytes[i + 1..].iter().take_while(|&&b| b == b'#').count();
+    (bytes.get(i + 1 + hashes) == Some(&b'"')).then_some(hashes)
+}
+
+/// Length of the char literal starting at the `'` at `i`, or `None` for a lifetime or label
+fn char_literal_len(line: &str, i: usize) -> Option<usize> {
+    let rest = &line[i + 1..];
+    if let Some(escaped) = rest.strip_prefix('\\') {
+        // `'\n'`, `'\''`, `'\u{7fff}'`: the escaped char can be a quote itself
+        let first = escaped.chars().next()?;
+        let close = escaped[first.len_utf8()..].find('\'')?;
+        return Some(2 + first.len_utf8() + close + 1);
+    }
+    let c = rest.chars().next()?;
+    rest[c.len_utf8()..].starts_with('\'').then(|| 1 + c.len_utf8() + 1)
+}
+
+/// Net change in delimiter depth over `line`, starting and leaving the lexer in `state`.
+///
+/// Braces, brackets and parentheses all count, so that an item only ends once its body,
+/// a multi-line attribute like `#[derive(Debug,\n Clone)]` or a signature spread over
+/// several lines is closed. Delimiters in string, raw string and char literals and in
+/// comments don't count, so that `println!("{")` or a doc comment mentioning `}` doesn't
+/// throw off the depth.
+fn nesting_delta(line: &str, state: &mut LexState) -> i32 {
+    lex_line(line, state, |_| {})
+}
+
+/// [`nesting_delta`], also passing the byte range of each comment, or part of a block
+/// comment spanning lines, in `line` to `on_comment`
+fn lex_line(line: &str, state: &mut LexState, mut on_comment: impl FnMut(Range<usize>)) -> i32 {
+    let bytes = line.as_bytes();
+    let mut delta = 0;
+    let mut comment_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        match *state {
+            LexState::Str => match bytes[i] {
+                b'\\' => i += 1,
+                b'"' => *state = LexState::Code,
+                _ => {}
+            },
+            LexState::RawStr(hashes) => {
+                let closing = rest.get(1..=hashes).is_some_and(|h| h.iter().all(|&b| b == b'#'));
+                if bytes[i] == b'"' && closing {
+                    i += hashes;
+                    *state = LexState::Code;
+                }
+            }
+            LexState::BlockComment(depth) => {
+                if rest.starts_with(b"*/") {
+                    *state =
+                        if depth == 1 { LexState::Code } else { LexState::BlockComment(depth - 1) };
+                    i += 1;
+                    if depth == 1 {
+                        on_comment(comment_start..i + 1);
+                    }
+                } else if rest.starts_with(b"/*") {
+                    *state = LexState::BlockComment(depth + 1);
+                    i += 1;
+                }
+            }
+            LexState::Code => match bytes[i] {
+                // Line and doc comments run to the end of the line
+                b'/' if rest.starts_with(b"//") => {
+                    on_comment(i..bytes.len());
+                    break;
+                }
+                b'/' if rest.starts_with(b"/*") => {
+                    *state = LexState::BlockComment(1);
+                    comment_start = i;
+                    i += 1;
+                }
+                b'"' => *state = LexState::Str,
+                b'r' => {
+                    if let Some(hashes) = raw_string_hashes(bytes, i) {
+                        *state = LexState::RawStr(hashes);
+                        i += 1 + hashes;
+                    }
+                }
+                b'\'' => {
+                    if let Some(len) = char_literal_len(line, i) {
+                        i += len - 1;
+                    }
+                }
+                b'{' | b'[' | b'(' => delta += 1,
+                b'}' | b']' | b')' => delta -= 1,
+                _ => {}
+            },
+        }
+        i += 1;
+    }
+    if let LexState::BlockComment(_) = state {
+        on_comment(comment_start..bytes.len());
+    }
+    delta
+}
+
+/// Byte ranges of the comments in `code`, doc comments included, in order.
+///
+/// Block comments spanning lines are split into a range per line, without the newlines.
+fn find_comment_spans(code: &str) -> Vec<Range<usize>> {
+    let mut spans = vec![];
+    let mut state = LexState::Code;
+    let mut offset = 0;
+    for line in code.split_inclusive('\n') {
+        let content = line.strip_suffix('\n').unwrap_or(line);
+        lex_line(content, &mut state, |span| spans.push(offset + span.start..offset + span.end));
+        offset += line.len();
+    }
+    spans
+}
+
+/// Whether `code` defines `fn main` anywhere but in a comment
+fn has_main(code: &str) -> bool {
+    let comments = find_comment_spans(code);
+    code.match_indices("fn main")
+        .any(|(i, _)| !comments.iter().any(|comment| comment.contains(&i)))
+}
+
+/// Whether `code` starts with `#![no_std]`, among its other inner attributes.
+///
+/// Such a crate has no `std` to link a `fn main` against, so it isn't wrapped in one.
+fn is_no_std(code: &str) -> bool {
+    static NO_STD: Lazy<Regex> = Lazy::new(|| Regex::new(r"^#!\[\s*no_std\s*\]").unwrap());
+
+    code.lines()
+        .map(str::trim)
+        .filter(|line| classify_line(line) != LineKind::Blank)
+        .take_while(|line| line.starts_with("#!["))
+        .any(|line| NO_STD.is_match(line))
+}
+
+/// Split code into preamble (top-level items) and body (statements).
+///
+/// Handles mixed code like:
+/// ```text
+/// fn helper() -> i32 { 42 }     // preamble
+///                                // preamble (blank line)
+/// let x = helper();              // body (needs fn main wrapper)
+/// ```
+///
+/// Returns (preamble, body) where preamble contains complete item definitions
+/// and body contains statement-level code that needs fn main() wrapping.
+/// If no wrapping is needed, body is empty.
+fn split_items_and_statements(code: &str) -> (String, String) {
+    // If code already has fn main, no splitting needed
+    if has_main(code) {
+        return (code.to_string(), String::new());
+    }
+
+    let mut depth: i32 = 0;
+    let mut lex = LexState::Code;
+    // Whether the last item line was an outer attribute, which belongs to whatever follows
+    // it: the body only starts after the item it's on, or in front of it for a statement
+    let mut in_attribute = false;
+    let mut split_point = 0; // byte offset where body starts
+    let mut line_byte_offset = 0;
+
+    for line in code.lines() {
+        let trimmed = line.trim();
+
+        if depth > 0 || lex != LexState::Code {
+            // Inside an item still open, or a string or comment spanning lines, track depth
+            depth += nesting_delta(line, &mut lex);
+            line_byte_offset += line.len() + 1; // +1 for newline
+            if depth <= 0 && lex == LexState::Code {
+                depth = 0;
+                if !in_attribute {
+                    split_point = line_byte_offset;
+                }
+            }
+            continue;
+        }
+
+        match classify_line(line) {
+            LineKind::Blank => {
+                // Blank lines and comments between items are part of preamble
+                nesting_delta(line, &mut lex);
+                line_byte_offset += line.len() + 1;
+                if lex == LexState::Code && !in_attribute {
+                    split_point = line_byte_offset;
+                }
+            }
+            LineKind::Item => {
+                // Count delimiters on this line
+                in_attribute = trimmed.starts_with("#[");
+                depth += nesting_delta(line, &mut lex);
+                if depth < 0 {
+                    depth = 0;
+                }
+                line_byte_offset += line.len() + 1;
+                if depth == 0 && lex == LexState::Code && !in_attribute {
+                    split_point = line_byte_offset;
+                }
+            }
+            // This line is a statement, everything from here is body
+            LineKind::Statement => break,
+        }
+    }
+
+    let split_point = split_point.min(code.len());
+    let preamble = &code[..split_point];
+    let body = &code[split_point..];
+
+    if body.trim().is_empty() {
+        // All items, no wrapping needed
+        (code.to_string(), String::new())
+    } else {
+        (preamble.to_string(), body.to_string())
+    }
+}
+
+/// Maps byte offsets in a stripped copy of a code block back to the original
+#[derive(Debug, Default)]
+struct OffsetMap {
+    /// `(stripped_offset, removed)` pairs: offsets at or past `stripped_offset`
+    /// had `removed` bytes cut out in front of them
+    segments: Vec<(u32, u32)>,
+}
+
+impl OffsetMap {
+    fn to_original(&self, offset: u32) -> u32 {
+        let idx = self.segments.partition_point(|&(start, _)| start <= offset);
+        match idx.checked_sub(1) {
+            Some(i) => offset + self.segments[i].1,
+            None => offset,
+        }
+    }
+}
+
+/// Cut the given sorted, non-overlapping byte ranges out of `code`
+fn remove_ranges(code: &str, ranges: &[Range<usize>]) -> (String, OffsetMap) {
+    let mut stripped = String::with_capacity(code.len());
+    let mut offsets = OffsetMap::default();
+    let mut removed = 0;
+    let mut pos = 0;
+    for range in ranges {
+        stripped.push_str(&code[pos..range.start]);
+        removed += range.len() as u32;
+        offsets.segments.push((stripped.len() as u32, removed));
+        pos = range.end;
+    }
+    stripped.push_str(&code[pos..]);
+    (stripped, offsets)
+}
+
+/// `code` as it's compiled in a doctest, with the `# ` of rustdoc's hidden lines removed and
+/// `##` turned back into `#`, and the `(stripped_offset, removed)` pairs mapping it back onto
+/// `code`: offsets at or past `stripped_offset` had `removed` bytes cut out in front of them.
+///
+/// Lines are hidden as by `markdown::map_line`: a line starting with `# `, after any
+/// indentation, or a line of just `#`. Like `Line::for_code`, the indentation in front of
+/// the `# ` goes as well, and a line of just `#` is left empty.
+pub fn strip_hidden_lines(code: &str) -> (String, Vec<(u32, u32)>) {
+    let ranges: Vec<_> = line_ranges(code)
+        .filter_map(|(range, line)| {
+            let content = line.trim_end_matches(['\n', '\r']);
+            let indent = content.len() - content.trim_start().len();
+            let trimmed = content.trim();
+            // Of `##`, only the first `#` goes
+            let removed = if trimmed.starts_with("##") {
+                indent..indent + 1
+            } else if trimmed.starts_with("# ") {
+                0..indent + 2
+            } else if trimmed == "#" {
+                0..content.len()
+            } else {
+                return None;
+            };
+            Some(range.start + removed.start..range.start + removed.end)
+        })
+        .collect();
+    let (stripped, offsets) = remove_ranges(code, &ranges);
+    (stripped, offsets.segments)
+}
+
+/// Run `analyze` on `code` without rustdoc's hidden-line prefixes, see
+/// [`strip_hidden_lines`], then map the annotations back onto `code`
+fn with_hidden_lines(
+    code: &str,
+    analyze: impl FnOnce(&str) -> Result<Vec<TypeAnnotation>, TwoslashError>,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let (stripped, segments) = strip_hidden_lines(code);
+    if segments.is_empty() {
+        return analyze(code);
+    }
+    let offsets = OffsetMap { segments };
+    let mut annotations = analyze(&stripped)?;
+    for ann in &mut annotations {
+        ann.start = offsets.to_original(ann.start);
+    }
+    Ok(annotations)
+}
+
+/// `annotations` of `code`, a code block with rustdoc's hidden lines still in it, moved onto
+/// the text rustdoc shows for it.
+///
+/// That's `code` with its hidden lines left out, `##` turned into `#` and lines ending in
+/// `\n` rather than `\r\n`, as by `markdown::map_line`. Annotations on hidden lines go.
+pub fn annotations_for_html(code: &str, annotations: Vec<TypeAnnotation>) -> Vec<TypeAnnotation> {
+    let mut hidden = vec![];
+    let mut removed = vec![];
+    for (range, line) in line_ranges(code) {
+        let content = line.trim_end_matches(['\n', '\r']);
+        let trimmed = content.trim();
+        if trimmed.starts_with("##") {
+            let start = range.start + content.len() - content.trim_start().len();
+            removed.push(start..start + 1);
+        } else if trimmed.starts_with("# ") || trimmed == "#" {
+            hidden.push(range.clone());
+            removed.push(range);
+            continue;
+        }
+        if line.ends_with("\r\n") {
+            removed.push(range.end - 2..range.end - 1);
+        }
+    }
+    if removed.is_empty() {
+        return annotations;
+    }
+    let cut = |from: usize, to: usize| -> u32 {
+        let overlap = |r: &Range<usize>| r.end.min(to).saturating_sub(r.start.max(from));
+        removed.iter().map(overlap).sum::<usize>() as u32
+    };
+    annotations
+        .into_iter()
+        .filter(|ann| {
+            let range = ann.byte_range();
+            !hidden.iter().any(|h| h.contains(&range.start) || range.contains(&h.start))
+        })
+        .map(|mut ann| {
+            let range = ann.byte_range();
+            ann.start -= cut(0, range.start);
+            ann.length -= cut(range.start, range.end);
+            ann
+        })
+        .collect()
+}
+
+/// Iterate over the lines of a code block with the byte range each covers,
+/// including its trailing newline.
+fn line_ranges(code: &str) -> impl Iterator<Item = (Range<usize>, &str)> {
+    code.split_inclusive('\n').scan(0, |pos, line| {
+        let start = *pos;
+        *pos += line.len();
+        Some((start..*pos, line))
+    })
+}
+
+/// A `// ^?` hover query, asking for the type of the token above the caret
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueryComment {
+    /// 1-indexed line of the query comment
+    pub line: u32,
+    /// Byte range of the query line in the code, including its newline
+    pub range: Range<usize>,
+    /// Byte offset of the character the caret points at
+    pub target: u32,
+    /// Type the query asserts, e.g. `i32` in `// ^? i32`
+    pub expected: Option<String>,
+}
+
+/// A `// ^?` query whose answer didn't come out as written
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum QueryError {
+    /// No annotation covers the token the caret points at
+    NoAnnotation { line: u32 },
+    /// The annotation doesn't match the type asserted by the query
+    Mismatch { line: u32, expected: String, actual: String },
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryError::NoAnnotation { line } => {
+                write!(f, "line {line}: no type information for the queried token")
+            }
+            QueryError::Mismatch { line, expected, actual } => {
+                write!(f, "line {line}: expected `{expected}`, found `{actual}`")
+            }
+        }
+    }
+}
+
+impl std::error::Error for QueryError {}
+
+/// Find the `// ^?` query comments in a code block.
+///
+/// The caret's column selects the token on the closest preceding line that isn't itself
+/// a query. Queries without such a line, or pointing past its end, are ignored.
+pub fn parse_query_comments(code: &str) -> Vec<QueryComment> {
+    let mut queries = Vec::new();
+    let mut target_line: Option<(usize, &str)> = None;
+    for (i, (range, line)) in line_ranges(code).enumerate() {
+        let Some(caret) = query_caret(line) else {
+            target_line = Some((range.start, line.trim_end_matches(['\r', '\n'])));
+            continue;
+        };
+        let Some((line_start, target_text)) = target_line else { continue };
+        if caret >= target_text.len() {
+            continue;
+        }
+        let expected = line[caret + "^?".len()..].trim();
+        queries.push(QueryComment {
+            line: i as u32 + 1,
+            range,
+            target: (line_start + caret) as u32,
+            expected: (!expected.is_empty()).then(|| expected.to_string()),
+        });
+    }
+    queries
+}
+
+/// Column of the caret if `line` is a `// ^?` query comment
+fn query_caret(line: &str) -> Option<usize> {
+    let comment = line.trim_start().strip_prefix("//")?;
+    if !comment.trim_start().starts_with("^?") {
+        return None;
+    }
+    line.find("^?")
+}
+
+/// Turn the annotations answering `queries` into query results.
+///
+/// The plain annotation of each queried token is replaced by one of kind
+/// [`TokenKind::QueryResult`]. Fails if a query finds nothing, or if the
+/// signature doesn't match the asserted type (either exactly or as the type of a
+/// `name: Type` signature).
+pub fn resolve_queries(
+    queries: &[QueryComment],
+    mut annotations: Vec<TypeAnnotation>,
+) -> Result<Vec<TypeAnnotation>, QueryError> {
+    for query in queries {
+        let Some(ann) = annotations
+            .iter_mut()
+            .find(|ann| ann.start <= query.target && query.target < ann.start + ann.length)
+        else {
+            return Err(QueryError::NoAnnotation { line: query.line });
+        };
+        if let Some(expected) = &query.expected {
+            let actual = type_and_docs(&ann.type_text).0.trim();
+            if actual != expected && !actual.ends_with(&format!(": {expected}")) {
+                return Err(QueryError::Mismatch {
+                    line: query.line,
+                    expected: expected.clone(),
+                    actual: actual.to_string(),
+                });
+            }
+        }
+        ann.kind = TokenKind::QueryResult;
+    }
+    Ok(annotations)
+}
+
+/// Run `analyze` on `code` with its `// ^?` query lines and ignore directives removed,
+/// then map the annotations back onto `code` and answer the queries, failing with
+/// [`TwoslashError::QueryFailed`] if one doesn't come out as written.
+fn with_directives(
+    code: &str,
+    analyze: impl FnOnce(&str) -> Result<Vec<TypeAnnotation>, TwoslashError>,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let queries = parse_query_comments(code);
+    let mut ranges: Vec<_> = queries.iter().map(|q| q.range.clone()).collect();
+    ranges.extend(ignore_directives(code).into_iter().map(|d| d.range));
+    if ranges.is_empty() {
+        return analyze(code);
+    }
+    ranges.sort_by_key(|range| range.start);
+
+    let (stripped, offsets) = remove_ranges(code, &ranges);
+    let mut annotations = analyze(&stripped)?;
+    for ann in &mut annotations {
+        ann.start = offsets.to_original(ann.start);
+    }
+
+    resolve_queries(&queries, annotations).map_err(TwoslashError::QueryFailed)
+}
+
+/// A `// twoslash-ignore-next` or `// twoslash-ignore-line` comment
+#[derive(Debug, Clone, PartialEq)]
+struct IgnoreDirective {
+    /// Byte range of the comment to strip before analysis
+    range: Range<usize>,
+    /// Whether the comment applies to the next non-comment line rather than its own
+    next: bool,
+}
+
+/// Byte index of the `// {name}` comment in `line`, if it has one
+fn directive_comment(line: &str, name: &str) -> Option<usize> {
+    line.match_indices("//").map(|(i, _)| i).find(|&i| {
+        let rest = line[i + 2..].trim();
+        rest.strip_prefix(name).is_some_and(|after| after.is_empty())
+    })
+}
+
+/// Find the ignore directives of a code block.
+///
+/// `twoslash-ignore-next` must be on a line of its own, which is stripped entirely;
+/// `twoslash-ignore-line` follows code, and only the comment is stripped.
+fn ignore_directives(code: &str) -> Vec<IgnoreDirective> {
+    let mut directives = Vec::new();
+    for (range, line) in line_ranges(code) {
+        let content = line.trim_end_matches(['\r', '\n']);
+        if let Some(i) = directive_comment(content, "twoslash-ignore-next")
+            && content[..i].trim().is_empty()
+        {
+            directives.push(IgnoreDirective { range, next: true });
+        } else if let Some(i) = directive_comment(content, "twoslash-ignore-line") {
+            let start = range.start + i;
+            directives.push(IgnoreDirective { range: start..range.start + content.len(), next: false });
+        }
+    }
+    directives
+}
+
+/// Drop the annotations on lines silenced by `// twoslash-ignore-next` or
+/// `// twoslash-ignore-line`
+pub fn apply_ignore_directives(code: &str, annotations: Vec<TypeAnnotation>) -> Vec<TypeAnnotation> {
+    let directives = ignore_directives(code);
+    if directives.is_empty() {
+        return annotations;
+    }
+    let lines: Vec<_> = line_ranges(code).collect();
+    let mut ignored = Vec::new();
+    for directive in &directives {
+        let Some(line) = lines.iter().position(|(range, _)| range.contains(&directive.range.start))
+        else {
+            continue;
+        };
+        let target = if directive.next {
+            lines[line + 1..].iter().find(|(_, text)| {
+                let text = text.trim();
+                !text.is_empty() && !text.starts_with("//")
+            })
+        } else {
+            Some(&lines[line])
+        };
+        ignored.extend(target.map(|(range, _)| range.clone()));
+    }
+    annotations
+        .into_iter()
+        .filter(|ann| !ignored.iter().any(|range| range.contains(&(ann.start as usize))))
+        .collect()
+}
+
+/// Collapse annotations covering the same byte range into one.
+///
+/// The analyzer can report several quick infos for a token that is e.g. both a binding
+/// and a type name. Their distinct `type_text`s are joined with newlines, the first docs
+/// found are kept, and a specific kind wins over [`TokenKind::Unknown`]. Order is that of
+/// each range's first occurrence.
+pub fn dedup_annotations(annotations: Vec<TypeAnnotation>) -> Vec<TypeAnnotation> {
+    let mut deduped: Vec<TypeAnnotation> = Vec::with_capacity(annotations.len());
+    let mut index: FxHashMap<(u32, u32), usize> = FxHashMap::default();
+    for ann in annotations {
+        let Some(&i) = index.get(&(ann.start, ann.length)) else {
+            index.insert((ann.start, ann.length), deduped.len());
+            deduped.push(ann);
+            continue;
+        };
+        let first = &mut deduped[i];
+        if !first.type_text.split('\n').any(|line| line == ann.type_text) {
+            first.type_text.push('\n');
+            first.type_text.push_str(&ann.type_text);
+        }
+        if first.docs.is_none() {
+            first.docs = ann.docs;
+        }
+        if !first.kind.is_known() {
+            first.kind = ann.kind;
+        }
+        first.macro_expanded |= ann.macro_expanded;
+    }
+    deduped
+}
+
+/// Give each annotated `fn` item name the function's signature, `fn(param: Type, ...) ->
+/// ReturnType`, in place of the annotations of its parameters.
+///
+/// Parameter types are taken from the parameters' own annotations where there are any,
+/// and from the source otherwise. Query results and diagnostics in the parameter list are
+/// kept. Functions whose name has no annotation are left as they are.
+fn synthesize_fn_signatures(
+    code: &str,
+    mut annotations: Vec<TypeAnnotation>,
+) -> Vec<TypeAnnotation> {
+    static FN_ITEM: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"\bfn\s+((?:r#)?[A-Za-z_]\w*)").unwrap());
+
+    for captures in FN_ITEM.captures_iter(code) {
+        let name = captures.get(1).unwrap();
+        let line_start = code[..name.start()].rfind('\n').map_or(0, |i| i + 1);
+        if code[line_start..name.start()].contains("//") {
+            continue;
+        }
+        let Some(name_index) =
+            annotations.iter().position(|ann| ann.byte_range() == name.range())
+        else {
+            continue;
+        };
+        let Some(signature) = FnSignature::parse(code, name.end()) else { continue };
+
+        let params: Vec<String> = signature
+            .params
+            .iter()
+            .map(|param| {
+                let Some(source_type) = param.source_type else {
+                    // `self`, `&self` or `&mut self`
+                    return squash_whitespace(param.pattern);
+                };
+                let analyzed = annotations
+                    .iter()
+                    .filter(|ann| param.pattern_range.contains(&(ann.start as usize)))
+                    .find_map(|ann| declared_type(&ann.type_text));
+                let ty = analyzed.map_or_else(|| squash_whitespace(source_type), String::from);
+                format!("{}: {ty}", squash_whitespace(param.pattern))
+            })
+            .collect();
+        let ret = signature.ret.map(|ret| format!(" -> {}", squash_whitespace(ret)));
+        let synthesized = format!("fn({}){}", params.join(", "), ret.unwrap_or_default());
+
+        let ann = &mut annotations[name_index];
+        let docs = ann.type_text.find(DOCS_SEPARATOR).map_or("", |i| &ann.type_text[i..]);
+        ann.type_text = synthesized + docs;
+        ann.kind = TokenKind::Function;
+        let params_range = signature.params_range;
+        annotations.retain(|ann| {
+            !params_range.contains(&(ann.start as usize))
+                || matches!(
+                    ann.kind,
+                    TokenKind::QueryResult | TokenKind::CompileError | TokenKind::CompileWarning
+                )
+        });
+    }
+    annotations
+}
+
+/// Replace an annotation of a whole tuple pattern, as for `(a, b)` in
+/// `let (a, b) = pair;`, by one for each binding in it.
+///
+/// A binding gets the element of the tuple type at its position, as `let a: Type`.
+/// Bindings the analyzer annotated itself keep their annotation, and patterns that don't
+/// line up with their type are left as they are.
+fn split_tuple_patterns(
+    code: &str,
+    annotations: Vec<TypeAnnotation>,
+    config: &TwoslashConfig,
+) -> Vec<TypeAnnotation> {
+    let annotated: Vec<Range<usize>> = annotations.iter().map(TypeAnnotation::byte_range).collect();
+    let mut split = Vec::with_capacity(annotations.len());
+    for ann in annotations {
+        let bindings = ann
+            .source_text(code)
+            .zip(declared_type(&ann.type_text))
+            .and_then(|(pattern, ty)| tuple_bindings(pattern, ann.start as usize, ty));
+        let Some(bindings) = bindings else {
+            split.push(ann);
+            continue;
+        };
+        for (range, ty) in bindings {
+            if annotated.contains(&range)
+                || (range.len() == 1 && !config.annotate_single_char_bindings)
+            {
+                continue;
+            }
+            let name = &code[range.clone()];
+            let (start, length) = (range.start as u32, range.len() as u32);
+            split.push(TypeAnnotation {
+                kind: TokenKind::Variable,
+                priority: TokenKind::Variable.default_priority(),
+                token_class: TokenClass::Identifier,
+                ..TypeAnnotation::new(start, length, format!("let {name}: {ty}"))
+            });
+        }
+    }
+    split
+}
+
+/// Bindings in the tuple `pattern` at `offset` with their types from the tuple type `ty`,
+/// or `None` if `pattern` isn't a tuple pattern of that many elements
+fn tuple_bindings(pattern: &str, offset: usize, ty: &str) -> Option<Vec<(Range<usize>, String)>> {
+    let patterns = tuple_elements(pattern)?;
+    let types = tuple_elements(ty)?;
+    if patterns.len() != types.len() {
+        return None;
+    }
+    let mut bindings = Vec::new();
+    for ((start, pattern), (_, ty)) in patterns.into_iter().zip(types) {
+        let start = offset + start;
+        if pattern.starts_with('(') {
+            bindings.extend(tuple_bindings(pattern, start, ty)?);
+            continue;
+        }
+        // `ref mut name`, `mut name`, ...
+        let name = pattern.rsplit(' ').next().unwrap_or(pattern);
+        if name == "_" {
+            continue;
+        }
+        if !rustc_lexer::is_ident(name) {
+            return None;
+        }
+        let start = start + pattern.len() - name.len();
+        bindings.push((start..start + name.len(), squash_whitespace(ty)));
+    }
+    Some(bindings)
+}
+
+/// Elements of a parenthesized, comma-separated `text` such as `(a, b)` or `(i32, &str)`,
+/// with their offsets in `text`. `None` if `text` isn't a tuple of two or more elements.
+fn tuple_elements(text: &str) -> Option<Vec<(usize, &str)>> {
+    let inner = text.strip_prefix('(')?.strip_suffix(')')?;
+    let mut elements = Vec::new();
+    let mut start = 0;
+    for (i, c, depth) in bracket_depths(inner).chain([(inner.len(), ',', 0)]) {
+        if depth != 0 || c != ',' {
+            continue;
+        }
+        let element = &inner[start..i];
+        let trimmed = element.trim();
+        if !trimmed.is_empty() {
+            elements.push((1 + start + element.len() - element.trim_start().len(), trimmed));
+        }
+        start = i + 1;
+    }
+    (elements.len() >= 2).then_some(elements)
+}
+
+/// Annotate each intermediate call of a method chain with the type it gives, as
+/// `map(…) -> Map<Iter<'_, T>, F>` for `map` in `v.iter().map(f).collect::<Vec<_>>()`,
+/// if [`TwoslashConfig::show_iterator_steps`] is set.
+///
+/// The type is the return type in the method's signature, with `Self` standing for the
+/// type of the step before. The last call of a chain keeps its annotation, since that's
+/// the type of the whole chain, and so do calls whose signature has no return type.
+fn annotate_method_chains(
+    code: &str,
+    mut annotations: Vec<TypeAnnotation>,
+    config: &TwoslashConfig,
+) -> Vec<TypeAnnotation> {
+    static SELF_TYPE: Lazy<Regex> = Lazy::new(|| Regex::new(r"\bSelf\b(::)?").unwrap());
+
+    if !config.show_iterator_steps {
+        return annotations;
+    }
+    let mut calls: Vec<ChainCall> = annotations
+        .iter()
+        .enumerate()
+        .filter_map(|(index, ann)| ChainCall::find(code, index, ann))
+        .collect();
+    // Receivers before the calls on them
+    calls.sort_by_key(|call| call.name.start);
+
+    let mut step_types: Vec<Option<String>> = Vec::with_capacity(calls.len());
+    for call in &calls {
+        let receiver_type = calls
+            .iter()
+            .position(|receiver| call.follows(code, receiver))
+            .and_then(|i| step_types.get(i)?.as_deref());
+        let step_type = method_return_type(&annotations[call.index].type_text).map(|ret| {
+            let Some(receiver_type) = receiver_type else { return ret.to_string() };
+            // `Self::Item` and such are left alone, `Map<Self, F>::Item` would be no clearer
+            SELF_TYPE
+                .replace_all(ret, |captures: &regex::Captures<'_>| match captures.get(1) {
+                    Some(_) => captures[0].to_string(),
+                    None => receiver_type.to_string(),
+                })
+                .into_owned()
+        });
+        if let Some(step_type) = &step_type
+            && calls.iter().any(|next| next.follows(code, call))
+        {
+            let ann = &mut annotations[call.index];
+            let docs = ann.type_text.find(DOCS_SEPARATOR).map_or("", |i| &ann.type_text[i..]);
+            let args = if call.has_args { "(…)" } else { "()" };
+            ann.type_text = format!("{}{args} -> {step_type}{docs}", &code[call.name.clone()]);
+        }
+        step_types.push(step_type);
+    }
+    annotations
+}
+
+/// A method call in `code`, as `.name(args)` or `.name::<T>(args)`
+struct ChainCall {
+    /// Index of the annotation of the method name
+    index: usize,
+    name: Range<usize>,
+    has_args: bool,
+    /// End of the call's closing parenthesis
+    end: usize,
+}
+
+impl ChainCall {
+    fn find(code: &str, index: usize, ann: &TypeAnnotation) -> Option<ChainCall> {
+        let name = ann.byte_range();
+        if ann.kind != TokenKind::Method || !code.get(..name.start)?.trim_end().ends_with('.') {
+            return None;
+        }
+        let mut args_start = name.end;
+        if code[args_start..].starts_with("::<") {
+            let turbofish = &code[args_start + 2..];
+            let close = bracket_depths(turbofish).find(|&(_, c, depth)| depth == 0 && c == '>')?;
+            args_start += 3 + close.0;
+        }
+        // Only parentheses count here, closures such as `|x| x > 0` would unbalance `<>`
+        let args = code[args_start..].strip_prefix('(')?;
+        let mut depth = 0;
+        let close = args.char_indices().find_map(|(i, c)| {
+            match c {
+                '(' => depth += 1,
+                ')' if depth == 0 => return Some(i),
+                ')' => depth -= 1,
+                _ => {}
+            }
+            None
+        })?;
+        Some(ChainCall {
+            index,
+            name,
+            has_args: !args[..close].trim().is_empty(),
+            end: args_start + close + 2,
+        })
+    }
+
+    /// Whether this call is made on the result of `receiver`
+    fn follows(&self, code: &str, receiver: &ChainCall) -> bool {
+        receiver.end <= self.name.start && code[receiver.end..self.name.start].trim() == "."
+    }
+}
+
+/// The return type in a method's hover signature, such as `Map<Self, F>` in
+/// `pub fn map<B, F>(self, f: F) -> Map<Self, F>`
+fn method_return_type(type_text: &str) -> Option<&str> {
+    static FN_NAME: Lazy<Regex> = Lazy::new(|| Regex::new(r"\bfn\s+(?:r#)?\w+").unwrap());
+
+    let (signature, _) = type_and_docs(type_text);
+    let name = FN_NAME.find(signature)?;
+    FnSignature::parse(signature, name.end())?.ret
+}
+
+/// The parameters and return type of a `fn` item, as written
+struct FnSignature<'a> {
+    /// Byte range of the parenthesized parameter list in the code
+    params_range: Range<usize>,
+    params: Vec<FnParam<'a>>,
+    ret: Option<&'a str>,
+}
+
+struct FnParam<'a> {
+    pattern: &'a str,
+    /// Byte range of `pattern` in the code
+    pattern_range: Range<usize>,
+    /// `None` for a `self` parameter without a type
+    source_type: Option<&'a str>,
+}
+
+impl<'a> FnSignature<'a> {
+    /// Parse the signature of the function whose name ends at `name_end` in `code`
+    fn parse(code: &'a str, name_end: usize) -> Option<FnSignature<'a>> {
+        let rest = &code[name_end..];
+        let open = bracket_depths(rest).find(|&(_, c, depth)| depth == 0 && c == '(')?.0;
+        // Only generics may come between the name and the parameters
+        let generics = rest[..open].trim();
+        let is_generics = generics.starts_with('<') && generics.ends_with('>');
+        if !(generics.is_empty() || is_generics) {
+            return None;
+        }
+        let close = open
+            + bracket_depths(&rest[open..]).find(|&(_, c, depth)| depth == 0 && c == ')')?.0;
+
+        let inner_start = name_end + open + 1;
+        let inner = &rest[open + 1..close];
+        let mut params = Vec::new();
+        let mut param_start = 0;
+        for (i, c, depth) in bracket_depths(inner).chain([(inner.len(), ',', 0)]) {
+            if depth != 0 || c != ',' {
+                continue;
+            }
+            let param = &inner[param_start..i];
+            let offset = inner_start + param_start + (param.len() - param.trim_start().len());
+            params.extend(FnParam::parse(param.trim(), offset));
+            param_start = i + 1;
+        }
+
+        let ret = rest[close + 1..].trim_start().strip_prefix("->").map(|ret| {
+            let end = bracket_depths(ret)
+                .find(|&(i, c, depth)| {
+                    let is_where = ret[i..].starts_with("where")
+                        && ret[..i].ends_with(char::is_whitespace)
+                        && !ret[i + 5..].starts_with(|c: char| c.is_alphanumeric() || c == '_');
+                    depth == 0 && (c == '{' || c == ';' || is_where)
+                })
+                .map_or(ret.len(), |(i, ..)| i);
+            ret[..end].trim()
+        });
+        Some(FnSignature { params_range: name_end + open..name_end + close + 1, params, ret })
+    }
+}
+
+impl<'a> FnParam<'a> {
+    fn parse(param: &'a str, offset: usize) -> Option<FnParam<'a>> {
+        if param.is_empty() {
+            return None;
+        }
+        let bytes = param.as_bytes();
+        // The `:` between pattern and type, which isn't part of a `::` path
+        let colon = bracket_depths(param).find(|&(i, c, depth)| {
+            depth == 0
+                && c == ':'
+                && bytes.get(i + 1) != Some(&b':')
+                && (i == 0 || bytes[i - 1] != b':')
+        });
+        let (pattern, source_type) = match colon {
+            Some((i, ..)) => (param[..i].trim_end(), Some(param[i + 1..].trim())),
+            None => (param, None),
+        };
+        Some(FnParam { pattern, pattern_range: offset..offset + pattern.len(), source_type })
+    }
+}
+
+/// The characters of `text` with their byte offset and the depth of `()`, `[]`, `{}` and
+/// `<>` nesting they're at. Brackets are at the depth outside them, and the `>` of `->`
+/// isn't a bracket.
+fn bracket_depths(text: &str) -> impl Iterator<Item = (usize, char, i32)> + '_ {
+    let mut depth = 0;
+    let mut prev = '\0';
+    text.char_indices().map(move |(i, c)| {
+        let at = match c {
+            '(' | '[' | '{' | '<' => {
+                depth += 1;
+                depth - 1
+            }
+            '>' if prev == '-' => depth,
+            ')' | ']' | '}' | '>' => {
+                depth -= 1;
+                depth
+            }
+            _ => depth,
+        };
+        prev = c;
+        (i, c, at)
+    })
+}
+
+/// The type in a binding's hover text, such as `&str` in `name: &str` or `let name: &str`
+fn declared_type(type_text: &str) -> Option<&str> {
+    let (signature, _) = type_and_docs(type_text);
+    let line = signature.lines().map(str::trim).rfind(|line| !line.is_empty())?;
+    let line = line.strip_prefix("let ").unwrap_or(line);
+    Some(line.split_once(": ")?.1.trim())
+}
+
+/// `text` with each run of whitespace replaced by a single space
+fn squash_whitespace(text: &str) -> String {
+    text.split_whitespace().collect::<Vec<_>>().join(" ")
+}
+
+/// Byte offsets at which the lines of `code` start
+fn line_starts(code: &str) -> Vec<usize> {
+    iter::once(0).chain(code.match_indices('\n').map(|(i, _)| i + 1)).collect()
+}
+
+/// A code block made of several source files, as written in a `files` block
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MultiFileBlock {
+    /// `(name, content)` of each file, in order
+    pub files: Vec<(String, String)>,
+    /// Name of the file analysis starts from; the others are its modules
+    pub entry: String,
+}
+
+/// Name of the file holding the code in front of the first `// @filename:` line
+const DEFAULT_FILE_NAME: &str = "main.rs";
+
+/// `mod name;` declarations, which pull other files of a block into the entry file
+static MOD_DECLARATION: Lazy<Regex> =
+    Lazy::new(|| Regex::new(r"(?m)^\s*(?:pub(?:\([^)]*\))?\s+)?mod\s+(\w+)\s*(;)").unwrap());
+
+/// Name of the file a `// @filename: name` line starts
+fn filename_directive(line: &str) -> Option<&str> {
+    let name = line.trim().strip_prefix("//")?.trim_start().strip_prefix("@filename:")?.trim();
+    (!name.is_empty()).then_some(name)
+}
+
+/// Split a `files` block into its files.
+///
+/// The `// @filename:` lines are cut out, and the map takes offsets into the files'
+/// contents joined in order back to `code`. Code before the first marker is a file of its
+/// own, named [`DEFAULT_FILE_NAME`]. The entry is `main.rs` or `lib.rs` if there is one,
+/// otherwise the last file.
+fn split_files(code: &str) -> (MultiFileBlock, OffsetMap) {
+    let markers: Vec<_> = line_ranges(code)
+        .filter_map(|(range, line)| Some((range, filename_directive(line)?.to_string())))
+        .collect();
+    let first = markers.first().map_or(code.len(), |(range, _)| range.start);
+    let mut files = Vec::new();
+    let mut removed = Vec::new();
+    if code[..first].trim().is_empty() {
+        removed.push(0..first);
+    } else {
+        files.push((DEFAULT_FILE_NAME.to_string(), code[..first].to_string()));
+    }
+    for (i, (range, name)) in markers.iter().enumerate() {
+        let end = markers.get(i + 1).map_or(code.len(), |(next, _)| next.start);
+        files.push((name.clone(), code[range.end..end].to_string()));
+        removed.push(range.clone());
+    }
+
+    let entry = files
+        .iter()
+        .map(|(name, _)| name)
+        .find(|name| ["main.rs", "lib.rs"].contains(&name.as_str()))
+        .or(files.last().map(|(name, _)| name))
+        .cloned()
+        .unwrap_or_else(|| DEFAULT_FILE_NAME.to_string());
+    let (_, offsets) = remove_ranges(code, &removed);
+    (MultiFileBlock { files, entry }, offsets)
+}
+
+/// Name of the module a file of a [`MultiFileBlock`] becomes, as in `utils.rs` or `utils/mod.rs`
+fn module_name(file_name: &str) -> String {
+    let path = Path::new(file_name);
+    let stem = match path.file_stem().and_then(|stem| stem.to_str()) {
+        Some("mod") => path.parent().and_then(Path::file_name).and_then(|dir| dir.to_str()),
+        stem => stem,
+    };
+    stem.unwrap_or(file_name).replace('-', "_")
+}
+
+/// The files of a [`MultiFileBlock`] combined into a single source
+struct CombinedFiles {
+    code: String,
+    /// Ranges of `code` copied from the files, with the offset they start at in the
+    /// files' contents joined in order
+    segments: Vec<(Range<u32>, u32)>,
+}
+
+impl CombinedFiles {
+    /// Inline every other file into the entry file as a module, at its `mod name;`
+    /// declaration if the entry has one and in front of the entry's code otherwise
+    fn new(block: &MultiFileBlock) -> CombinedFiles {
+        let mut combined = CombinedFiles { code: String::new(), segments: Vec::new() };
+        let mut file_start = 0;
+        let mut files = Vec::new();
+        for (name, content) in &block.files {
+            files.push((name.as_str(), content.as_str(), file_start));
+            file_start += content.len() as u32;
+        }
+        let Some(&(_, entry, entry_start)) = files.iter().find(|(name, ..)| **name == block.entry)
+        else {
+            return combined;
+        };
+        let modules: Vec<_> = files
+            .iter()
+            .filter(|(name, ..)| **name != block.entry)
+            .map(|&(name, content, start)| (module_name(name), content, start))
+            .collect();
+
+        let declarations: Vec<_> = MOD_DECLARATION
+            .captures_iter(entry)
+            .filter_map(|caps| {
+                let module = modules.iter().find(|(name, ..)| *name == caps[1])?;
+                Some((caps.get(2)?.start(), module))
+            })
+            .collect();
+        for (name, content, start) in &modules {
+            if !declarations.iter().any(|(_, (declared, ..))| declared == name) {
+                combined.code.push_str(&format!("mod {name} {{\n"));
+                combined.push_file(content, *start);
+                combined.code.push_str("\n}\n");
+            }
+        }
+        let mut pos = 0;
+        for &(semicolon, (_, content, start)) in &declarations {
+            combined.push_file(&entry[pos..semicolon], entry_start + pos as u32);
+            combined.code.push_str(" {\n");
+            combined.push_file(content, *start);
+            combined.code.push_str("\n}");
+            pos = semicolon + 1;
+        }
+        combined.push_file(&entry[pos..], entry_start + pos as u32);
+        combined
+    }
+
+    fn push_file(&mut self, content: &str, file_offset: u32) {
+        let start = self.code.len() as u32;
+        self.code.push_str(content);
+        self.segments.push((start..self.code.len() as u32, file_offset));
+    }
+
+    /// Offset in the files' joined contents of `offset` in the combined code, `None` for
+    /// code that was added to combine them
+    fn to_files(&self, offset: u32) -> Option<u32> {
+        let (range, file_offset) = self.segments.iter().find(|(range, _)| range.contains(&offset))?;
+        Some(file_offset + offset - range.start)
+    }
+
+    /// Move annotations of the combined code onto the files' joined contents
+    fn to_files_annotations(&self, annotations: Vec<TypeAnnotation>) -> Vec<TypeAnnotation> {
+        annotations
+            .into_iter()
+            .filter_map(|mut ann| {
+                ann.start = self.to_files(ann.start)?;
+                Some(ann)
+            })
+            .collect()
+    }
+}
+
+/// Run `analyze` on the files of a `files` block combined into one source (see
+/// [`split_files`]), then map the annotations back onto `code`
+fn with_files(
+    code: &str,
+    analyze: impl FnOnce(&str) -> Result<Vec<TypeAnnotation>, TwoslashError>,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let (block, offsets) = split_files(code);
+    let combined = CombinedFiles::new(&block);
+    let mut annotations = combined.to_files_annotations(analyze(&combined.code)?);
+    for ann in &mut annotations {
+        ann.start = offsets.to_original(ann.start);
+    }
+    Ok(annotations)
+}
+
+/// A `// @errors` marker: the block is expected to fail to compile.
+///
+/// Rendering checks it against the block's [diagnostics](diagnostics_for_code), and warns
+/// about a block that doesn't fail the way it says.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorsDirective {
+    /// 1-based line of the marker
+    pub line: u32,
+    /// Number of errors asserted by `// @errors N`
+    pub expected: Option<usize>,
+}
+
+/// A `// @errors` block that doesn't fail the way it says
+#[derive(Debug, Clone, PartialEq)]
+pub enum DiagnosticError {
+    WrongErrorCount { expected: usize, actual: usize },
+}
+
+impl fmt::Display for DiagnosticError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DiagnosticError::WrongErrorCount { expected, actual } => {
+                write!(f, "expected {expected} compile errors, found {actual}")
+            }
+        }
+    }
+}
+
+/// Find the `// @errors` marker of a code block, if any
+pub fn parse_errors_directive(code: &str) -> Option<ErrorsDirective> {
+    code.lines().enumerate().find_map(|(i, line)| {
+        let rest = line.trim().strip_prefix("//")?.trim_start().strip_prefix("@errors")?;
+        if !rest.is_empty() && !rest.starts_with(char::is_whitespace) {
+            return None;
+        }
+        let expected = match rest.trim() {
+            "" => None,
+            count => Some(count.parse().ok()?),
+        };
+        Some(ErrorsDirective { line: i as u32 + 1, expected })
+    })
+}
+
+/// Check the compile errors among `annotations` against an `// @errors N` assertion
+pub fn check_errors(
+    directive: &ErrorsDirective,
+    annotations: &[TypeAnnotation],
+) -> Result<(), DiagnosticError> {
+    let actual = annotations.iter().filter(|ann| ann.kind == TokenKind::CompileError).count();
+    match directive.expected {
+        Some(expected) if expected != actual => {
+            Err(DiagnosticError::WrongErrorCount { expected, actual })
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Work for the [`WORKERS`]
+type Job = Box<dyn FnOnce() + Send>;
+
+/// Threads that analyses with a timeout run on, see [`run_with_timeout`]: one per analyzer
+/// that can be busy at a time, [`TwoslashConfig::parallelism`], taking jobs from a shared
+/// queue.
+///
+/// `None` if not a single one could be started, in which case jobs run on their caller.
+static WORKERS: Lazy<Option<mpsc::Sender<Job>>> = Lazy::new(|| {
+    let (sender, receiver) = mpsc::channel::<Job>();
+    let receiver = Arc::new(Mutex::new(receiver));
+    let mut started = 0;
+    for n in 0..CONFIG.parallelism {
+        let receiver = Arc::clone(&receiver);
+        let spawned = std::thread::Builder::new().name(format!("twoslash-worker-{n}")).spawn(
+            move || {
+                loop {
+                    // Only held while waiting, as jobs are run outside of it
+                    let job = receiver.lock().unwrap_or_else(PoisonError::into_inner).recv();
+                    let Ok(job) = job else { return };
+                    // The job's caller sees the panic as its result going missing
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                }
+            },
+        );
+        match spawned {
+            Ok(_) => started += 1,
+            Err(e) => twoslash_log!(LogLevel::Warn, "can't start twoslash worker {n}: {e}"),
+        }
+    }
+    (started > 0).then_some(sender)
+});
+
+/// Run `f` on one of the [`WORKERS`], giving up on it after `timeout`.
+///
+/// Time spent waiting for a worker counts against the timeout, and `f` isn't started at
+/// all once it's past. Analyses can't be interrupted though, so a timed out `f` keeps its
+/// worker, and whatever it holds (such as a pooled analyzer), until it finishes on its own.
+fn run_with_timeout<R: Send + 'static>(
+    timeout: Option<Duration>,
+    f: impl FnOnce() -> Result<R, TwoslashError> + Send + 'static,
+) -> Result<R, TwoslashError> {
+    let Some(timeout) = timeout else { return f() };
+    let Some(workers) = WORKERS.as_ref() else { return f() };
+    let deadline = Instant::now() + timeout;
+    let (sender, receiver) = mpsc::channel();
+    let job: Job = Box::new(move || {
+        let result =
+            if Instant::now() < deadline { f() } else { Err(TwoslashError::Timeout(timeout)) };
+        // The receiver is gone if we already timed out
+        let _ = sender.send(result);
+    });
+    // The workers only stop with the process
+    let _ = workers.send(job);
+    match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+        Ok(result) => result,
+        Err(RecvTimeoutError::Timeout) => Err(TwoslashError::Timeout(timeout)),
+        // `f` panicked
+        Err(RecvTimeoutError::Disconnected) => Err(TwoslashError::Panicked),
+    }
+}
+
+/// Analyze a code block with an analyzer from the pool, within the configured timeout.
+///
+/// With [`TwoslashConfig::thread_local_analyzers`], this thread's own analyzer is used
+/// instead, within the same timeout. Like a pooled one, it stays busy with an analysis that
+/// timed out until that finishes.
+fn annotate_pooled(
+    code: &str,
+    config: &TwoslashConfig,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    if CONFIG.thread_local_analyzers {
+        let Some(timeout) = config.timeout() else {
+            return with_local_analyzer(|analyzer| annotate(analyzer, code, config))?;
+        };
+        let analyzer = local_analyzer()?;
+        let (code, config) = (code.to_string(), config.clone());
+        return run_with_timeout(Some(timeout), move || {
+            let mut analyzer = analyzer.lock().unwrap_or_else(PoisonError::into_inner);
+            annotate(&mut *analyzer, &code, &config)
+        });
+    }
+    let mut analyzer = ANALYZERS.acquire()?;
+    let (code, config) = (code.to_string(), config.clone());
+    run_with_timeout(config.timeout(), move || annotate(&mut *analyzer, &code, &config))
+}
+
+/// Why a code block couldn't be analyzed
+#[derive(Debug, Clone, PartialEq)]
+pub enum TwoslashError {
+    /// The analyzer reported an error
+    AnalysisFailed(String),
+    /// Analysis took longer than [`TwoslashConfig::timeout`]
+    Timeout(Duration),
+    /// Analysis panicked
+    Panicked,
+    /// The analyzer returned an annotation that doesn't fit the code block
+    InvalidAnnotation(AnnotationError),
+    /// The Cargo.toml found earlier can't be read anymore
+    ManifestUnreadable(PathBuf),
+    /// In [offline](TwoslashConfig::offline) mode, a dependency isn't in Cargo's cache
+    OfflineMissingDep(String),
+    /// A `// ^?` query didn't come out as written
+    QueryFailed(QueryError),
+    /// An analyzer can't be created with the settings it was given
+    InvalidSettings(ConfigError),
+}
+
+impl fmt::Display for TwoslashError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TwoslashError::AnalysisFailed(e) => write!(f, "analysis failed: {e}"),
+            TwoslashError::Timeout(timeout) => {
+                write!(f, "analysis timed out after {}s", timeout.as_secs())
+            }
+            TwoslashError::Panicked => write!(f, "analysis panicked"),
+            TwoslashError::InvalidAnnotation(e) => write!(f, "invalid annotation: {e}"),
+            TwoslashError::ManifestUnreadable(path) => {
+                write!(f, "can't read {}, keeping the analyzers as they are", path.display())
+            }
+            TwoslashError::OfflineMissingDep(dep) => {
+                write!(f, "`{dep}` isn't available offline, it has to be fetched first")
+            }
+            TwoslashError::QueryFailed(e) => write!(f, "query failed: {e}"),
+            TwoslashError::InvalidSettings(e) => write!(f, "can't create an analyzer: {e}"),
+        }
+    }
+}
+
+impl std::error::Error for TwoslashError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            TwoslashError::InvalidAnnotation(e) => Some(e),
+            TwoslashError::QueryFailed(e) => Some(e),
+            TwoslashError::InvalidSettings(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+/// Annotations of a code block, or why it couldn't be analyzed
+fn analyze_block(
+    code: &str,
+    attrs: &BlockAttrs,
+    config: &TwoslashConfig,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let analyze = |code: &str| {
+        // Only blocks that are wrapped in an async main get the runtime as a dependency
+        let runtime = WrappedCode::is_async_main(code, config).then_some(config.async_runtime);
+        let cargo_toml = block_cargo_toml(attrs.edition.as_deref(), runtime);
+        let analyzed = cached(code, cargo_toml.as_deref(), CacheKind::Annotations, config, || {
+            if attrs.edition.is_none() && runtime.is_none() {
+                return annotate_pooled(code, config);
+            }
+            let (edition, cargo_toml) = (attrs.edition.clone(), cargo_toml.clone());
+            let (code, config) = (code.to_string(), config.clone());
+            run_with_timeout(config.timeout(), move || {
+                with_block_analyzer(edition.as_deref(), runtime, cargo_toml, |analyzer| {
+                    annotate(analyzer, &code, &config)
+                })?
+            })
+        });
+        unannotated_if_cut_short(analyzed, config)
+    };
+    analyze_block_with(code, attrs, config, analyze)
+}
+
+/// `analyzed`, with a panicked analysis as no annotations, and one that timed out too with
+/// [`TwoslashConfig::partial_on_timeout`].
+///
+/// The panic is logged by then, the timeout is logged here. Since [`cached`] doesn't store
+/// errors, the block is analyzed, and logged, again on the next run.
+fn unannotated_if_cut_short(
+    analyzed: Result<Vec<TypeAnnotation>, TwoslashError>,
+    config: &TwoslashConfig,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    match analyzed {
+        Err(TwoslashError::Panicked) => Ok(vec![]),
+        Err(e @ TwoslashError::Timeout(_)) if config.partial_on_timeout => {
+            twoslash_log!(LogLevel::Warn, "{e}, leaving the block unannotated");
+            Ok(vec![])
+        }
+        analyzed => analyzed,
+    }
+}
+
+/// Annotations of a code block analyzed by `analyze`, with the block's directives applied
+/// and the annotations post-processed
+fn analyze_block_with(
+    code: &str,
+    attrs: &BlockAttrs,
+    config: &TwoslashConfig,
+    analyze: impl FnOnce(&str) -> Result<Vec<TypeAnnotation>, TwoslashError>,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    if attrs.dedent || config.dedent {
+        let (dedented, indent) = dedent(code);
+        if indent > 0 {
+            let annotations = analyze_code(&dedented, attrs, config, analyze)?;
+            return Ok(redent_annotations(code, indent, annotations));
+        }
+    }
+    analyze_code(code, attrs, config, analyze)
+}
+
+/// [`analyze_block_with`] for a block that's been dedented if it's going to be
+fn analyze_code(
+    code: &str,
+    attrs: &BlockAttrs,
+    config: &TwoslashConfig,
+    analyze: impl FnOnce(&str) -> Result<Vec<TypeAnnotation>, TwoslashError>,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let annotations = with_hidden_lines(code, |code| {
+        with_directives(code, |code| {
+            if attrs.files { with_files(code, analyze) } else { analyze(code) }
+        })
+    })?;
+    let annotations = dedup_annotations(apply_ignore_directives(code, annotations));
+    let annotations = split_tuple_patterns(code, annotations, config);
+    let annotations = synthesize_fn_signatures(code, annotations);
+    let mut annotations = annotate_method_chains(code, annotations, config);
+    for ann in &mut annotations {
+        ann.type_text = normalize_type_text(&ann.type_text, &config.type_text);
+    }
+    // The analyzer's order is unspecified. The sort is stable, so annotations sharing a
+    // range keep their relative order.
+    annotations.sort();
+    // Rendering slices the code by these ranges, so don't pass a bad one on
+    if let Some(e) = validate_annotations(code, &annotations).into_iter().next() {
+        return Err(TwoslashError::InvalidAnnotation(e));
+    }
+    Ok(annotations)
+}
+
+/// Check every annotation against `code` (see [`TypeAnnotation::validate`]), returning
+/// all the problems found
+pub fn validate_annotations(code: &str, annotations: &[TypeAnnotation]) -> Vec<AnnotationError> {
+    annotations.iter().filter_map(|ann| ann.validate(code).err()).collect()
+}
+
+/// Process a code block and extract type annotations, sorted by range.
+///
+/// `attrs` are the block's own settings from its info string (see [`parse_block_attrs`]),
+/// which take precedence over `config`. Usually `config` is [`TwoslashConfig::global`].
+pub fn process_code_block(
+    code: &str,
+    attrs: Option<&BlockAttrs>,
+    config: &TwoslashConfig,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let mut annotations = vec![];
+    process_code_block_streaming(code, attrs, config, |ann| annotations.push(ann))?;
+    Ok(annotations)
+}
+
+/// Like [`process_code_block`], but passing each annotation to `callback`, in order, instead
+/// of collecting them.
+///
+/// The analyzer answers for the whole block at once, and annotations are deduplicated,
+/// sorted and trimmed across the block, so the first call only comes once the block is
+/// analyzed. Nothing is passed to `callback` for a block that fails.
+pub fn process_code_block_streaming(
+    code: &str,
+    attrs: Option<&BlockAttrs>,
+    config: &TwoslashConfig,
+    callback: impl FnMut(TypeAnnotation),
+) -> Result<(), TwoslashError> {
+    let attrs = attrs.cloned().unwrap_or_default();
+    let annotations = analyze_block(code, &attrs, config)?;
+    block_output(code, &attrs, config, annotations).into_iter().for_each(callback);
+    Ok(())
+}
+
+/// Like [`process_code_block`], but analyzing with `analyzer` instead of the shared
+/// analyzers, as tests do with a stand-in.
+///
+/// The cache and the timeout are bypassed, and a block's `edition` is left to however
+/// `analyzer` was set up.
+#[cfg(test)]
+pub fn process_code_block_with<A: AnalyzerTrait + ?Sized>(
+    code: &str,
+    attrs: Option<&BlockAttrs>,
+    config: &TwoslashConfig,
+    analyzer: &mut A,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let attrs = attrs.cloned().unwrap_or_default();
+    let annotations = analyze_block_with(code, &attrs, config, |code| {
+        unannotated_if_cut_short(annotate(analyzer, code, config), config)
+    })?;
+    Ok(block_output(code, &attrs, config, annotations))
+}
+
+/// Like [`process_code_block`], but logs failures and returns no annotations for them
+pub fn process_code_block_lossy(
+    code: &str,
+    attrs: Option<&BlockAttrs>,
+    config: &TwoslashConfig,
+) -> Vec<TypeAnnotation> {
+    process_code_block(code, attrs, config).unwrap_or_else(|e| {
+        twoslash_log!(LogLevel::Error, "{e}");
+        vec![]
+    })
+}
+
+/// Length of the `name!` at `start` if a macro call begins there
+fn macro_call_len(code: &str, start: usize) -> Option<usize> {
+    let rest = code.get(start..)?;
+    let name_len = rest.find(|c: char| !(c.is_alphanumeric() || c == '_')).unwrap_or(rest.len());
+    let after = &rest[name_len..];
+    // `name != other` is a comparison, not a call
+    (name_len > 0 && after.starts_with('!') && !after.starts_with("!=")).then_some(name_len + 1)
+}
+
+/// Runtime driving the `async fn main` that examples using `.await` are wrapped in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AsyncRuntime {
+    #[default]
+    Tokio,
+    AsyncStd,
+    Smol,
+}
+
+impl AsyncRuntime {
+    /// RUSTDOC_TWOSLASH_ASYNC_RUNTIME: `tokio` (the default), `async-std` or `smol`
+    fn from_env(resolver: &dyn CargoResolver) -> AsyncRuntime {
+        match resolver.env_var("RUSTDOC_TWOSLASH_ASYNC_RUNTIME").as_deref().map(str::trim) {
+            None | Some("" | "tokio") => AsyncRuntime::Tokio,
+            Some("async-std" | "async_std") => AsyncRuntime::AsyncStd,
+            Some("smol") => AsyncRuntime::Smol,
+            Some(other) => {
+                twoslash_log!(LogLevel::Warn, "unknown async runtime `{other}`, using tokio");
+                AsyncRuntime::Tokio
+            }
+        }
+    }
+
+    fn crate_name(self) -> &'static str {
+        match self {
+            AsyncRuntime::Tokio => "tokio",
+            AsyncRuntime::AsyncStd => "async-std",
+            AsyncRuntime::Smol => "smol",
+        }
+    }
+
+    /// Dependency spec giving the temp project what [`main_wrapper`](Self::main_wrapper) needs
+    fn dependency(self) -> &'static str {
+        match self {
+            AsyncRuntime::Tokio => r#"{ version = "1", features = ["macros", "rt"] }"#,
+            AsyncRuntime::AsyncStd => r#"{ version = "1", features = ["attributes"] }"#,
+            AsyncRuntime::Smol => r#""2""#,
+        }
+    }
+
+    /// Code going before and after the statements of an async example.
+    ///
+    /// smol has no `main` attribute of its own, so its body runs in `smol::block_on`.
+    fn main_wrapper(self) -> (&'static str, &'static str) {
+        match self {
+            AsyncRuntime::Tokio => {
+                ("#[tokio::main(flavor = \"current_thread\")]\nasync fn main() {\n", "\n}")
+            }
+            AsyncRuntime::AsyncStd => ("#[async_std::main]\nasync fn main() {\n", "\n}"),
+            AsyncRuntime::Smol => ("fn main() { smol::block_on(async {\n", "\n}) }"),
+        }
+    }
+}
+
+/// `code` without the indentation its non-blank lines share, and how many spaces that is.
+///
+/// Code blocks in indented markdown, such as in a list item, can keep the indentation of
+/// their surroundings. Blank lines without that much indentation are left as they are.
+pub fn dedent(code: &str) -> (String, u32) {
+    let indent = code
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| line.len() - line.trim_start_matches(' ').len())
+        .min()
+        .unwrap_or(0);
+    if indent == 0 {
+        return (code.to_string(), 0);
+    }
+    let prefix = " ".repeat(indent);
+    let dedented =
+        code.split_inclusive('\n').map(|line| line.strip_prefix(&prefix).unwrap_or(line)).collect();
+    (dedented, indent as u32)
+}
+
+/// Move the annotations of [`dedent`]ed `code` to where their tokens are in `code`
+fn redent_annotations(
+    code: &str,
+    indent: u32,
+    annotations: Vec<TypeAnnotation>,
+) -> Vec<TypeAnnotation> {
+    let prefix = " ".repeat(indent as usize);
+    // Where each line starts in the dedented code, with the indentation removed up to there
+    let mut lines = vec![];
+    let (mut dedented_start, mut removed) = (0, 0);
+    for line in code.split_inclusive('\n') {
+        let stripped = if line.starts_with(&prefix) { indent } else { 0 };
+        removed += stripped;
+        lines.push((dedented_start, removed));
+        dedented_start += line.len() as u32 - stripped;
+    }
+    let to_original = |offset: u32| {
+        let line = lines.partition_point(|&(start, _)| start <= offset).saturating_sub(1);
+        offset + lines.get(line).map_or(0, |&(_, removed)| removed)
+    };
+    annotations
+        .into_iter()
+        .map(|mut ann| {
+            // The last byte rather than the end, which may be the start of the next line
+            let last = to_original(ann.start + ann.length.saturating_sub(1));
+            ann.start = to_original(ann.start);
+            ann.length = if ann.length == 0 { 0 } else { last + 1 - ann.start };
+            ann
+        })
+        .collect()
+}
+
+/// Where [`normalize_line_endings`] removed the `\r` of a `\r\n`
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LineEndingMap {
+    /// Offsets in the normalized code of the `\n`s that ended in `\r\n`, ascending
+    crlf_newlines: Vec<usize>,
+}
+
+impl LineEndingMap {
+    /// Offset in the original code of `offset` in the normalized code
+    pub fn to_original(&self, offset: u32) -> u32 {
+        let removed = self.crlf_newlines.partition_point(|&newline| newline < offset as usize);
+        offset + removed as u32
+    }
+
+    /// `ann`, made for the normalized code, with its range in the original code
+    fn restore(&self, mut ann: TypeAnnotation) -> TypeAnnotation {
+        let end = self.to_original(ann.start + ann.length);
+        ann.start = self.to_original(ann.start);
+        ann.length = end - ann.start;
+        ann
+    }
+}
+
+/// `code` with its `\r\n` line endings replaced by `\n`, and where they were.
+///
+/// Code blocks from files with Windows line endings keep them, but splitting items from
+/// statements and the analyzer's offsets assume lines end in a single `\n`. A lone `\r`
+/// is left as it is.
+pub fn normalize_line_endings(code: &str) -> (String, LineEndingMap) {
+    let mut map = LineEndingMap::default();
+    if !code.contains("\r\n") {
+        return (code.to_string(), map);
+    }
+    let mut normalized = String::with_capacity(code.len());
+    for line in code.split_inclusive('\n') {
+        match line.strip_suffix("\r\n") {
+            Some(content) => {
+                normalized.push_str(content);
+                map.crlf_newlines.push(normalized.len());
+                normalized.push('\n');
+            }
+            None => normalized.push_str(line),
+        }
+    }
+    (normalized, map)
+}
+
+/// The cfg that holds for the code twoslash analyzes, for examples that only make sense
+/// with annotations: `#[cfg(twoslash)]` items are analyzed, `#[cfg(not(twoslash))]` ones
+/// aren't
+const TWOSLASH_CFG: &str = "twoslash";
+
+/// Make `cfg(twoslash)` hold in `content`, as if it were compiled with `--cfg twoslash`.
+///
+/// The analyzer can't be given rustc flags, so each `twoslash` in the predicate of a
+/// `cfg`, `cfg_attr` or `cfg!` becomes `all()`, which always holds, padded to the same
+/// length so that offsets into `content` stay the same.
+pub fn inject_cfg_flag(content: &mut String) {
+    static CFG_START: Lazy<Regex> = Lazy::new(|| Regex::new(r"\bcfg(_attr|!)?\s*\(").unwrap());
+    static TWOSLASH: Lazy<Regex> = Lazy::new(|| Regex::new(r"\btwoslash\b").unwrap());
+
+    let mut predicates = vec![];
+    for start in CFG_START.captures_iter(content) {
+        let is_cfg_attr = start.get(1).is_some_and(|kind| kind.as_str() == "_attr");
+        let predicate_start = start.get(0).unwrap().end();
+        let mut depth = 1;
+        let mut end = content.len();
+        for (i, c) in content[predicate_start..].char_indices() {
+            match c {
+                '(' => depth += 1,
+                ')' => depth -= 1,
+                // The attributes `cfg_attr` applies come after its predicate
+                ',' if depth == 1 && is_cfg_attr => depth = 0,
+                _ => {}
+            }
+            if depth == 0 {
+                end = predicate_start + i;
+                break;
+            }
+        }
+        predicates.push(predicate_start..end);
+    }
+    for predicate in predicates {
+        let predicate_text = &content[predicate.clone()];
+        let found: Vec<usize> = TWOSLASH
+            .find_iter(predicate_text)
+            // Not the values of `feature = "twoslash"` and such
+            .filter(|m| !predicate_text[..m.start()].ends_with('"'))
+            .map(|m| predicate.start + m.start())
+            .collect();
+        for at in found {
+            let always = format!("{:<1$}", "all()", TWOSLASH_CFG.len());
+            content.replace_range(at..at + TWOSLASH_CFG.len(), &always);
+        }
+    }
+}
+
+/// `.await`, in the statements of a block that [`WrappedCode`] puts in an `async fn main`
+static AWAIT: Lazy<Regex> = Lazy::new(|| Regex::new(r"\.await\b").unwrap());
+
+/// A code block wrapped into a compilable program, statements going into a `fn main`
+struct WrappedCode {
+    code: String,
+    /// Length of the top-level items kept in front of `fn main`
+    preamble_len: u32,
+    /// Length of the inserted `fn main() {` line, 0 if the block wasn't wrapped
+    fn_main_offset: u32,
+}
+
+impl WrappedCode {
+    /// Wrap `code`, unless it's `#![no_std]` or `config` says to treat it as such
+    fn new(code: &str, config: &TwoslashConfig) -> WrappedCode {
+        if config.no_std || is_no_std(code) {
+            if !split_items_and_statements(code).1.is_empty() {
+                twoslash_log!(
+                    LogLevel::Warn,
+                    "not wrapping the statements of a `#![no_std]` code block in `fn main`, add \
+                     one explicitly: `#![no_std] fn main() {{ ... }}`"
+                );
+            }
+            return WrappedCode { code: code.to_string(), preamble_len: 0, fn_main_offset: 0 };
+        }
+        WrappedCode::with_runtime(code, config.async_runtime)
+    }
+
+    /// Whether [`new`](Self::new) wraps `code` in an `async fn main`, which needs the
+    /// [`TwoslashConfig::async_runtime`] as a dependency
+    fn is_async_main(code: &str, config: &TwoslashConfig) -> bool {
+        if config.no_std || is_no_std(code) {
+            return false;
+        }
+        AWAIT.is_match(&split_items_and_statements(code).1)
+    }
+
+    /// Wrap `code`, using `runtime` for an `async fn main` if the statements use `.await`
+    fn with_runtime(code: &str, runtime: AsyncRuntime) -> WrappedCode {
+        // Split into preamble (top-level items) and body (statements needing fn main)
+        let (preamble, body) = split_items_and_statements(code);
+        if body.is_empty() {
+            return WrappedCode { code: code.to_string(), preamble_len: 0, fn_main_offset: 0 };
+        }
+        let (fn_main_prefix, suffix) =
+            if AWAIT.is_match(&body) { runtime.main_wrapper() } else { ("fn main() {\n", "\n}") };
+        WrappedCode {
+            code: format!("{}{}{}{}", preamble, fn_main_prefix, body, suffix),
+            preamble_len: preamble.len() as u32,
+            fn_main_offset: fn_main_prefix.len() as u32,
+        }
+    }
+
+    /// Offset in the original code block of `offset` in the wrapped code, `None` for the wrapper
+    fn to_original(&self, offset: u32) -> Option<u32> {
+        // Positions in the wrapped code:
+        // - 0..preamble_len: preamble items (same positions in original)
+        // - preamble_len..(preamble_len+fn_main_offset): "fn main() {\n" (skip)
+        // - (preamble_len+fn_main_offset)..end: body code (subtract fn_main_offset)
+        let wrapper_start = self.preamble_len + self.fn_main_offset;
+        if self.fn_main_offset == 0 || offset < self.preamble_len {
+            Some(offset)
+        } else if offset < wrapper_start {
+            None
+        } else {
+            Some(offset - self.fn_main_offset)
+        }
+    }
+}
+
+/// Total time spent in [`AnalyzerTrait::analyze`] by this process, in microseconds
+static ANALYSIS_MICROS: AtomicU64 = AtomicU64::new(0);
+
+/// Number of analyses [`ANALYSIS_MICROS`] is the total of
+static ANALYSIS_COUNT: AtomicU64 = AtomicU64::new(0);
+
+/// Add an analysis of `code` that took `elapsed` to the totals, logging it if it was slow
+fn record_analysis_time(code: &str, elapsed: Duration, config: &TwoslashConfig) {
+    ANALYSIS_MICROS.fetch_add(elapsed.as_micros() as u64, Ordering::Relaxed);
+    ANALYSIS_COUNT.fetch_add(1, Ordering::Relaxed);
+    if elapsed > Duration::from_millis(config.slow_threshold_ms) {
+        let start: String = code.chars().take(80).collect();
+        twoslash_log!(LogLevel::Warn, "analysis took {}ms: {start:?}", elapsed.as_millis());
+    }
+}
+
+/// Average time an analysis has taken in this process so far, 0 before the first
+fn avg_analysis_ms() -> f32 {
+    // Not a consistent snapshot of the two, which only matters while analyses finish
+    let count = ANALYSIS_COUNT.load(Ordering::Relaxed);
+    let micros = ANALYSIS_MICROS.load(Ordering::Relaxed);
+    if count == 0 { 0.0 } else { micros as f32 / count as f32 / 1000.0 }
+}
+
+/// Analyze a single code block with the given analyzer.
+///
+/// If the analyzer panics, which rust-analyzer does on some malformed code, this logs the
+/// block and returns [`TwoslashError::Panicked`], which [`unannotated_if_cut_short`] turns
+/// into no annotations once past the cache.
+fn annotate<A: AnalyzerTrait + ?Sized>(
+    analyzer: &mut A,
+    code: &str,
+    config: &TwoslashConfig,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let (normalized, line_endings) = normalize_line_endings(code);
+    let code = normalized.as_str();
+    let mut wrapped = WrappedCode::new(code, config);
+    inject_cfg_flag(&mut wrapped.code);
+    twoslash_log!(LogLevel::Debug, "analyzing:\n{}", wrapped.code);
+
+    let started = Instant::now();
+    let result = panic::catch_unwind(AssertUnwindSafe(|| analyzer.analyze(&wrapped.code)));
+    record_analysis_time(code, started.elapsed(), config);
+    let Ok(result) = result else {
+        let start: String = code.chars().take(80).collect();
+        twoslash_log!(LogLevel::Warn, "analysis panicked, leaving unannotated: {start:?}");
+        return Err(TwoslashError::Panicked);
+    };
+    // Such as the names in doc comments, which the analyzer may give hovers for
+    let comments = find_comment_spans(code);
+    let in_comment = |ann: &TypeAnnotation| {
+        comments.iter().any(|comment| comment.contains(&(ann.start as usize)))
+    };
+    match result {
+        Ok(result) => Ok(result
+            .static_quick_infos
+            .into_iter()
+            .filter_map(|info| {
+                twoslash_log!(
+                    LogLevel::Debug,
+                    "analyzer output at {}+{}: {:?}",
+                    info.start,
+                    info.length,
+                    info.text
+                );
+                quick_info_annotation(
+                    code,
+                    &wrapped,
+                    (info.start, info.length),
+                    info.text,
+                    info.docs,
+                    config,
+                )
+            })
+            .filter(|ann| !in_comment(ann))
+            .map(|ann| line_endings.restore(ann))
+            .collect()),
+        Err(e) => match offline_missing_dep(&e).filter(|_| config.offline) {
+            Some(dep) => Err(TwoslashError::OfflineMissingDep(dep)),
+            None => Err(TwoslashError::AnalysisFailed(e)),
+        },
+    }
+}
+
+/// The dependency Cargo's `stderr` says it couldn't get offline, if that's why it failed
+fn offline_missing_dep(stderr: &str) -> Option<String> {
+    static MISSING: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"no matching package named `([^`]+)` found|failed to download `([^` ]+)")
+            .unwrap()
+    });
+    let captures = MISSING.captures(stderr)?;
+    let dep = captures.get(1).or_else(|| captures.get(2))?;
+    Some(dep.as_str().to_string())
+}
+
+/// Annotation for the analyzer's hover info at `(start, length)` in the wrapped code.
+///
+/// `None` for ranges that aren't entirely in `code`, such as in or across the `fn main`
+/// wrapper, and for keywords, operators and punctuation (see [`classify_token`]), which
+/// are stray hovers. One-letter names, like the fields of `Point { x: 1.0, y: 2.0 }`, are
+/// kept, bindings only with [`TwoslashConfig::annotate_single_char_bindings`].
+fn quick_info_annotation(
+    code: &str,
+    wrapped: &WrappedCode,
+    (start, length): (u32, u32),
+    text: String,
+    docs: Option<String>,
+    config: &TwoslashConfig,
+) -> Option<TypeAnnotation> {
+    // Adjust offsets for wrapped code, skipping the "fn main() {\n" part
+    let adjusted_start = wrapped.to_original(start)?;
+    let adjusted_last = wrapped.to_original(start.checked_add(length.checked_sub(1)?)?)?;
+    // Skip annotations that extend past the original code or span the wrapper
+    if adjusted_last as usize >= code.len() || adjusted_last - adjusted_start != length - 1 {
+        return None;
+    }
+    // The analyzer's range for a macro call may cover its arguments too
+    let macro_len = macro_call_len(code, adjusted_start as usize);
+    // Skip operators, punctuation and keywords. Ranges of several tokens are patterns like
+    // `(x, y)`, which `split_tuple_patterns` deals with.
+    let token_class = match macro_len {
+        Some(_) => TokenClass::Identifier,
+        None => classify_token(code, adjusted_start as usize, length as usize),
+    };
+    if matches!(token_class, TokenClass::Keyword | TokenClass::Operator | TokenClass::Punctuation)
+    {
+        return None;
+    }
+
+    // Hovers may consist of docs alone
+    let kind = TokenKind::from_opt_kind(Some(type_and_docs(&text).0));
+    if length == 1 && kind == TokenKind::Variable && !config.annotate_single_char_bindings {
+        return None;
+    }
+    let length = macro_len.map_or(length, |len| len as u32);
+
+    Some(TypeAnnotation {
+        start: adjusted_start,
+        length,
+        kind,
+        url: if config.resolve_urls { doc_url(&text) } else { None },
+        type_text: text,
+        docs,
+        macro_expanded: macro_len.is_some(),
+        priority: kind.default_priority(),
+        token_class,
+    })
+}
+
+/// Compiler errors and warnings for a code block, as [`TokenKind::CompileError`] and
+/// [`TokenKind::CompileWarning`] annotations.
+///
+/// The analyzer only reports hover information, so this runs `cargo check` on a scaffold
+/// of its own, with the same manifest the analyzers use. Each diagnostic annotates its
+/// primary span, with the message as `type_text` and its notes and help as `docs`.
+/// This is independent of [`process_code_block`], so callers can show either or both.
+/// Like it, this checks rustdoc's hidden lines too (see [`strip_hidden_lines`]), and goes
+/// through the same cache and timeout.
+pub fn diagnostics_for_code(code: &str) -> Vec<TypeAnnotation> {
+    let diagnostics = with_hidden_lines(code, |code| {
+        let (normalized, line_endings) = normalize_line_endings(code);
+        let runtime =
+            WrappedCode::is_async_main(&normalized, &CONFIG).then_some(CONFIG.async_runtime);
+        let cargo_toml = diagnostics_cargo_toml(runtime);
+        let diagnostics =
+            cached(&normalized, Some(&cargo_toml), CacheKind::Diagnostics, &CONFIG, || {
+                let wrapped = WrappedCode::new(&normalized, &CONFIG);
+                let (code, cargo_toml) = (wrapped.code.clone(), cargo_toml.clone());
+                let messages = run_with_timeout(CONFIG.timeout(), move || {
+                    cargo_check(&code, &cargo_toml, &CONFIG)
+                })?;
+                Ok(parse_diagnostics(&messages, &normalized, &wrapped))
+            })?;
+        Ok(diagnostics.into_iter().map(|ann| line_endings.restore(ann)).collect())
+    });
+    match diagnostics {
+        Ok(diagnostics) => diagnostics,
+        Err(e) => {
+            twoslash_log!(LogLevel::Error, "{e}");
+            vec![]
+        }
+    }
+}
+
+/// Run `cargo check` with `code` as the main of a temp project with `cargo_toml` as its
+/// manifest, returning the JSON messages
+fn cargo_check(
+    code: &str,
+    cargo_toml: &str,
+    config: &TwoslashConfig,
+) -> Result<String, TwoslashError> {
+    let io_error = |e: std::io::Error| TwoslashError::AnalysisFailed(e.to_string());
+    let scaffold = tempfile::tempdir().map_err(io_error)?;
+    std::fs::create_dir(scaffold.path().join("src")).map_err(io_error)?;
+    std::fs::write(scaffold.path().join("Cargo.toml"), cargo_toml).map_err(io_error)?;
+    std::fs::write(scaffold.path().join("src/main.rs"), code).map_err(io_error)?;
+
+    let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+    // Declared as well, so that it isn't reported as an unexpected cfg
+    let cfg_flags = format!("--cfg {TWOSLASH_CFG} --check-cfg cfg({TWOSLASH_CFG})");
+    let rustflags = match std::env::var("RUSTFLAGS") {
+        Ok(flags) if !flags.trim().is_empty() => format!("{flags} {cfg_flags}"),
+        _ => cfg_flags,
+    };
+    let mut command = Command::new(cargo);
+    command.args(["check", "--quiet", "--message-format=json", "--target-dir"]);
+    command.arg(config.target_dir_with_suffix("-diagnostics"));
+    if config.offline {
+        command.arg("--offline");
+    }
+    let output = command
+        .env("RUSTFLAGS", rustflags)
+        .current_dir(scaffold.path())
+        .stdin(Stdio::null())
+        .output()
+        .map_err(io_error)?;
+    // Failing is expected for code with errors, but then there are messages explaining why
+    if !output.status.success() && output.stdout.is_empty() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        if config.offline
+            && let Some(dep) = offline_missing_dep(&stderr)
+        {
+            return Err(TwoslashError::OfflineMissingDep(dep));
+        }
+        return Err(TwoslashError::AnalysisFailed(stderr.trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// Annotations for the compiler messages in `cargo check --message-format=json` output
+fn parse_diagnostics(messages: &str, code: &str, wrapped: &WrappedCode) -> Vec<TypeAnnotation> {
+    messages
+        .lines()
+        .filter_map(|line| serde_json::from_str::<serde_json::Value>(line).ok())
+        .filter(|message| message["reason"] == "compiler-message")
+        .filter_map(|message| diagnostic_annotation(&message["message"], code, wrapped))
+        .collect()
+}
+
+/// Annotation for a rustc JSON diagnostic at its primary span in `code`.
+///
+/// `None` for diagnostics without a span in the code block itself, such as the final
+/// "aborting due to" error or anything pointing into the `fn main` wrapper.
+fn diagnostic_annotation(
+    diagnostic: &serde_json::Value,
+    code: &str,
+    wrapped: &WrappedCode,
+) -> Option<TypeAnnotation> {
+    let kind = match diagnostic["level"].as_str()? {
+        "error" => TokenKind::CompileError,
+        "warning" => TokenKind::CompileWarning,
+        _ => return None,
+    };
+    let span = diagnostic["spans"]
+        .as_array()?
+        .iter()
+        .find(|span| span["is_primary"] == true && span["file_name"] == "src/main.rs")?;
+    let byte_start = u32::try_from(span["byte_start"].as_u64()?).ok()?;
+    let byte_end = u32::try_from(span["byte_end"].as_u64()?).ok()?;
+    let mut start = wrapped.to_original(byte_start)? as usize;
+    let mut end = (start + byte_end.saturating_sub(byte_start) as usize).min(code.len());
+    if !code.is_char_boundary(start) || !code.is_char_boundary(end) {
+        return None;
+    }
+    // Point spans, such as for a missing `;`, cover the character they point at
+    if start == end {
+        match code[start..].chars().next() {
+            Some(c) => end += c.len_utf8(),
+            None => start -= code[..start].chars().next_back()?.len_utf8(),
+        }
+    }
+
+    let notes: Vec<String> = diagnostic["children"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .filter_map(|child| {
+            Some(format!("{}: {}", child["level"].as_str()?, child["message"].as_str()?))
+        })
+        .collect();
+    let message = diagnostic["message"].as_str()?;
+    Some(TypeAnnotation {
+        docs: (!notes.is_empty()).then(|| notes.join("\n")),
+        kind,
+        priority: kind.default_priority(),
+        token_class: classify_token(code, start, end - start),
+        ..TypeAnnotation::new(start as u32, (end - start) as u32, message)
+    })
+}
+
+/// What tools embedding rustdoc can use of twoslash besides rendering, such as
+/// [`set_annotation_postprocessor`](render::set_annotation_postprocessor) for themes
+pub mod api {
+    pub use super::render::{
+        AnnotationLine, AnnotationPostprocessor, clear_annotation_postprocessor, group_by_line,
+        set_annotation_postprocessor,
+    };
+    pub use super::{AnnotationDisplay, TwoslashConfig, TwoslashError, TypeAnnotation};
+}
+
+/// Entry points for the fuzz targets in `fuzz/`, which build with `--cfg fuzzing`
+#[cfg(fuzzing)]
+pub mod fuzzing {
+    pub const TMP_PACKAGE_NAME: &str = super::manifest::TMP_PACKAGE_NAME;
+
+    /// A [`CargoResolver`](super::manifest::CargoResolver) without files, so virtual manifests are
+    /// always skipped
+    struct NoFiles;
+
+    impl super::manifest::CargoResolver for NoFiles {
+        fn read_file(&self, _: &std::path::Path) -> std::io::Result<String> {
+            Err(std::io::ErrorKind::NotFound.into())
+        }
+
+        fn current_dir(&self) -> std::io::Result<std::path::PathBuf> {
+            Ok("/".into())
+        }
+
+        fn env_var(&self, _: &str) -> Option<String> {
+            None
+        }
+    }
+
+    pub fn inject_self_dependency(
+        cargo_toml: &str,
+        crate_path: &str,
+        features: &[&str],
+    ) -> (String, bool) {
+        let (output, result) =
+            super::manifest::inject_self_dependency(&NoFiles, cargo_toml, crate_path, features);
+        (output, result == super::manifest::InjectionResult::Injected)
+    }
+
+    pub fn same_crate(a: &str, b: &str) -> bool {
+        super::manifest::same_crate(a, b)
+    }
+}
+
+#[cfg(test)]
+mod tests;
@@ -0,0 +1,54 @@
+//! Async entry points for twoslash, enabled by the `async` feature.
+//!
+//! Analysis itself is blocking, so it always runs on tokio's blocking thread pool. Async
+//! callers only ever wait on a tokio mutex, never on a `std::sync` lock.
+
+use std::sync::Arc;
+
+use tokio::sync::Mutex;
+use twoslash_rust::{Analyzer, AnalyzerSettings};
+
+use super::{
+    BlockAttrs, TwoslashConfig, TwoslashError, TypeAnnotation, annotate, new_analyzer_with,
+    process_code_block,
+};
+
+/// An analyzer that can be shared between tasks
+#[derive(Clone)]
+pub struct AsyncAnalyzer {
+    inner: Arc<Mutex<Analyzer>>,
+}
+
+impl AsyncAnalyzer {
+    pub fn new(settings: AnalyzerSettings) -> Self {
+        AsyncAnalyzer { inner: Arc::new(Mutex::new(new_analyzer_with(settings))) }
+    }
+
+    /// Analyze a code block, waiting for the analyzer if another task is using it
+    pub async fn analyze(
+        &self,
+        code: &str,
+        config: &TwoslashConfig,
+    ) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+        let mut analyzer = Arc::clone(&self.inner).lock_owned().await;
+        let (code, config) = (code.to_string(), config.clone());
+        tokio::task::spawn_blocking(move || annotate(&mut *analyzer, &code, &config))
+            .await
+            .unwrap_or(Err(TwoslashError::Panicked))
+    }
+}
+
+/// Async version of [`process_code_block`].
+///
+/// Runs the same pipeline (cache, queries, timeout) on the shared analyzer pool, so
+/// results are identical to the blocking API.
+pub async fn process_code_block_async(
+    code: &str,
+    attrs: Option<&BlockAttrs>,
+    config: &TwoslashConfig,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    let (code, attrs, config) = (code.to_string(), attrs.cloned(), config.clone());
+    tokio::task::spawn_blocking(move || process_code_block(&code, attrs.as_ref(), &config))
+        .await
+        .unwrap_or(Err(TwoslashError::Panicked))
+}
@@ -0,0 +1,245 @@
+//! Analysis results cached on disk, keyed by everything the analysis depends on
+
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::fmt;
+use std::path::{Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+
+use super::config::{TARGET_DIR, TwoslashConfig};
+use super::manifest::MANIFESTS;
+use super::{TwoslashError, TypeAnnotation};
+
+/// Directory under the user's cache directory holding cached analysis results
+pub(super) const CACHE_DIR_NAME: &str = "rustdoc-twoslash";
+
+/// Layout version of [`TypeAnnotationCache`]; bump when `TypeAnnotation` changes incompatibly
+pub(super) const CACHE_SCHEMA_VERSION: u32 = 7;
+
+/// The annotations of a code block in a versioned, serializable form.
+///
+/// This is what the disk cache stores, and lets annotations be passed between processes
+/// (e.g. precomputed by a build script and consumed by rustdoc).
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TypeAnnotationCache {
+    /// Schema version the annotations were written with
+    pub version: u32,
+    pub annotations: Vec<TypeAnnotation>,
+}
+
+/// Failure to read a [`TypeAnnotationCache`]
+#[derive(Debug)]
+pub enum CacheFormatError {
+    /// Not valid JSON for the cache layout
+    Json(serde_json::Error),
+    /// Written by a different version of the cache layout
+    UnsupportedVersion(u32),
+}
+
+impl fmt::Display for CacheFormatError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CacheFormatError::Json(e) => write!(f, "invalid annotation cache: {e}"),
+            CacheFormatError::UnsupportedVersion(v) => write!(
+                f,
+                "annotation cache has schema version {v}, expected {CACHE_SCHEMA_VERSION}"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for CacheFormatError {}
+
+impl TypeAnnotationCache {
+    pub fn new(annotations: Vec<TypeAnnotation>) -> Self {
+        TypeAnnotationCache { version: CACHE_SCHEMA_VERSION, annotations }
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("type annotations always serialize")
+    }
+
+    /// Parse a cache from JSON, rejecting other schema versions
+    pub fn from_json(s: &str) -> Result<Self, CacheFormatError> {
+        let cache: Self = serde_json::from_str(s).map_err(CacheFormatError::Json)?;
+        if cache.version != CACHE_SCHEMA_VERSION {
+            return Err(CacheFormatError::UnsupportedVersion(cache.version));
+        }
+        Ok(cache)
+    }
+}
+
+/// Subdirectory of the default target dir holding cached analysis results when there's
+/// no home directory to cache them in
+pub(super) const CACHE_SUBDIR: &str = "annotations";
+
+/// Default for [`TwoslashConfig::cache_dir`]
+pub(super) fn default_cache_dir() -> PathBuf {
+    let xdg_cache = std::env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty());
+    let home_cache = || std::env::var_os("HOME").map(|home| Path::new(&home).join(".cache"));
+    match xdg_cache.map(PathBuf::from).or_else(home_cache) {
+        Some(dir) => dir.join(CACHE_DIR_NAME),
+        None => Path::new(TARGET_DIR).join(CACHE_SUBDIR),
+    }
+}
+
+/// Cargo.lock next to the resolved Cargo.toml, if any.
+///
+/// Part of the cache key, so that dependency updates invalidate cached results.
+pub(super) static CARGO_LOCK: Lazy<RwLock<Option<String>>> =
+    Lazy::new(|| RwLock::new(read_cargo_lock()));
+
+pub(super) fn read_cargo_lock() -> Option<String> {
+    std::fs::read_to_string(MANIFESTS.lock_file()?).ok()
+}
+
+/// Version of rustdoc, part of the cache key so that entries written by another build of
+/// the analysis are never read. Bootstrap sets `CFG_VERSION` for the compiler's crates.
+pub(super) const CACHE_RUSTDOC_VERSION: &str = match option_env!("CFG_VERSION") {
+    Some(version) => version,
+    None => env!("CARGO_PKG_VERSION"),
+};
+
+/// The settings of `config` that change what the analysis of a code block returns, as part
+/// of the block's cache key.
+///
+/// The fields are listed exhaustively so that a new one has to be sorted into one or the
+/// other. Those left out only apply once the annotations are out of the cache, are part of
+/// the manifest, or change nothing but how or whether a block is analyzed.
+pub(super) fn cache_settings(config: &TwoslashConfig) -> String {
+    let TwoslashConfig {
+        no_std,
+        async_runtime,
+        resolve_urls,
+        annotate_single_char_bindings,
+        enabled: _,
+        cargo_toml_path: _,
+        target_dir: _,
+        cache_dir: _,
+        timeout_secs: _,
+        partial_on_timeout: _,
+        slow_threshold_ms: _,
+        features: _,
+        log_level: _,
+        parallelism: _,
+        no_cache: _,
+        dedent: _,
+        watch_cargo: _,
+        thread_local_analyzers: _,
+        offline: _,
+        extra_deps: _,
+        type_text: _,
+        show_iterator_steps: _,
+        output: _,
+        output_file: _,
+        stats_file: _,
+        max_annotations: _,
+        trim_strategy: _,
+    } = config;
+    format!(
+        "no_std={no_std} async_runtime={async_runtime:?} resolve_urls={resolve_urls} \
+         single_char_bindings={annotate_single_char_bindings}"
+    )
+}
+
+/// What a cache entry holds
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum CacheKind {
+    /// The analyzer's annotations, see [`analyze_block`](super::analyze_block)
+    Annotations,
+    /// The compiler's diagnostics, see [`diagnostics_for_code`](super::diagnostics_for_code)
+    Diagnostics,
+}
+
+/// Location of the cache entry of `kind` for a code block, in [`TwoslashConfig::cache_dir`].
+///
+/// Analysis is deterministic given the code, the augmented Cargo.toml, the Cargo.lock, the
+/// [settings](cache_settings) it's done with and the version of rustdoc, so the entry is
+/// keyed by a hash of all of them. Changing any of them moves every block to a new key
+/// rather than requiring explicit invalidation. The kind is part of the key as well, since
+/// both kinds are computed from the same code and, often, the same manifest.
+pub(super) fn cache_path(
+    code: &str,
+    cargo_toml: Option<&str>,
+    kind: CacheKind,
+    config: &TwoslashConfig,
+) -> PathBuf {
+    let cargo_lock = CARGO_LOCK.read().unwrap_or_else(PoisonError::into_inner);
+    let settings = cache_settings(config);
+    let kind = format!("{kind:?}");
+    let parts = [
+        code,
+        cargo_toml.unwrap_or_default(),
+        cargo_lock.as_deref().unwrap_or_default(),
+        &settings,
+        &kind,
+        CACHE_RUSTDOC_VERSION,
+    ];
+    let hash = hash_parts(parts.map(str::as_bytes));
+    let key: String = hash.iter().map(|b| format!("{b:02x}")).collect();
+    config.cache_dir.join(format!("{key}.json"))
+}
+
+/// SHA-256 of `parts`, each prefixed with its length so that different splits of the same
+/// bytes can't collide
+pub(super) fn hash_parts<'a>(parts: impl IntoIterator<Item = &'a [u8]>) -> [u8; 32] {
+    let mut hasher = Sha256::new();
+    for part in parts {
+        hasher.update((part.len() as u64).to_le_bytes());
+        hasher.update(part);
+    }
+    hasher.finalize().into()
+}
+
+/// Look up previously computed annotations for a code block
+pub(super) fn load_cached(
+    code: &str,
+    cargo_toml: Option<&str>,
+    kind: CacheKind,
+    config: &TwoslashConfig,
+) -> Option<Vec<TypeAnnotation>> {
+    let content = std::fs::read_to_string(cache_path(code, cargo_toml, kind, config)).ok()?;
+    // Unreadable entries (e.g. from an older format) are treated as misses
+    TypeAnnotationCache::from_json(&content).ok().map(|cache| cache.annotations)
+}
+
+/// Persist the annotations for a code block. Failures only cost a future cache miss.
+pub(super) fn store_cached(
+    code: &str,
+    cargo_toml: Option<&str>,
+    kind: CacheKind,
+    config: &TwoslashConfig,
+    annotations: &[TypeAnnotation],
+) {
+    let path = cache_path(code, cargo_toml, kind, config);
+    let json = TypeAnnotationCache::new(annotations.to_vec()).to_json();
+    if let Some(dir) = path.parent()
+        && std::fs::create_dir_all(dir).is_ok()
+    {
+        let _ = std::fs::write(path, json);
+    }
+}
+
+/// Return the cached annotations of `kind` for `code` analyzed against `cargo_toml`, or
+/// compute them with `analyze` and cache them.
+///
+/// Failed analyses are not cached, and the cache is bypassed with
+/// [`TwoslashConfig::no_cache`].
+pub(super) fn cached(
+    code: &str,
+    cargo_toml: Option<&str>,
+    kind: CacheKind,
+    config: &TwoslashConfig,
+    analyze: impl FnOnce() -> Result<Vec<TypeAnnotation>, TwoslashError>,
+) -> Result<Vec<TypeAnnotation>, TwoslashError> {
+    if config.no_cache {
+        return analyze();
+    }
+    if let Some(annotations) = load_cached(code, cargo_toml, kind, config) {
+        return Ok(annotations);
+    }
+    let annotations = analyze()?;
+    store_cached(code, cargo_toml, kind, config, &annotations);
+    Ok(annotations)
+}
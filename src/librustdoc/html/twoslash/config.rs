@@ -0,0 +1,444 @@
+//! Configuration of twoslash, read from the `RUSTDOC_TWOSLASH_*` environment variables
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use std::fmt;
+use std::num::NonZeroUsize;
+use std::path::PathBuf;
+use std::time::Duration;
+
+use super::AsyncRuntime;
+use super::cache::default_cache_dir;
+use super::manifest::{CargoResolver, DefaultCargoResolver};
+
+/// Default directory the scaffolded temp projects are built in
+pub(super) const TARGET_DIR: &str = "/tmp/rustdoc-twoslash-cache";
+
+/// Default for [`TwoslashConfig::timeout_secs`]
+pub(super) const DEFAULT_TIMEOUT_SECS: u64 = 10;
+
+/// Default for [`TwoslashConfig::slow_threshold_ms`]
+pub(super) const DEFAULT_SLOW_THRESHOLD_MS: u64 = 2000;
+
+/// Configuration of the process, read from the environment once
+pub(super) static CONFIG: Lazy<TwoslashConfig> = Lazy::new(TwoslashConfig::from_env);
+
+/// [`TwoslashConfig::log_level`] of the environment.
+///
+/// This is read by itself rather than from [`CONFIG`], which logs while it's being read.
+pub(super) static LOG_LEVEL: Lazy<LogLevel> = Lazy::new(|| {
+    let level = DefaultCargoResolver.env_var("RUSTDOC_TWOSLASH_LOG_LEVEL");
+    level.and_then(|level| level.trim().parse().ok()).unwrap_or_default()
+});
+
+/// Twoslash settings, normally read from the RUSTDOC_TWOSLASH_* environment variables.
+///
+/// The shared analyzers and the manifest they use are set up once per process from
+/// [`TwoslashConfig::global`], so `cargo_toml_path`, `target_dir`, `features`,
+/// `parallelism`, `extra_deps`, `watch_cargo`, `thread_local_analyzers` and `offline` only
+/// take effect there, as does `log_level`, which every message is filtered by.
+/// The rest apply to whichever config a code block is processed with.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TwoslashConfig {
+    /// RUSTDOC_TWOSLASH: process code blocks that don't opt in with `twoslash`, see
+    /// [`env_switch`] for the values
+    pub enabled: bool,
+    /// RUSTDOC_TWOSLASH_CARGO_TOML: manifest to analyze against, instead of locating one
+    pub cargo_toml_path: Option<PathBuf>,
+    /// RUSTDOC_TWOSLASH_TARGET_DIR: where Cargo builds the temp projects
+    pub target_dir: PathBuf,
+    /// RUSTDOC_TWOSLASH_CACHE_DIR: where analysis results are cached, by default
+    /// `rustdoc-twoslash` in `$XDG_CACHE_HOME` or `~/.cache`
+    pub cache_dir: PathBuf,
+    /// RUSTDOC_TWOSLASH_TIMEOUT_SECS: how long a code block may take to analyze, 0 for no limit
+    pub timeout_secs: u64,
+    /// RUSTDOC_TWOSLASH_PARTIAL_ON_TIMEOUT: keep the annotations a code block that timed out
+    /// got so far, with a warning, rather than failing it. The analyzer answers for a whole
+    /// block at once, so there are none yet and the block is left unannotated.
+    pub partial_on_timeout: bool,
+    /// RUSTDOC_TWOSLASH_SLOW_THRESHOLD_MS: how long a code block may take to analyze before
+    /// it's logged as slow
+    pub slow_threshold_ms: u64,
+    /// RUSTDOC_TWOSLASH_FEATURES: comma-separated features of the documented crate to enable
+    pub features: Vec<String>,
+    /// RUSTDOC_TWOSLASH_LOG_LEVEL: `off`, `error`, `warn`, `info` or `debug`, where `debug`
+    /// adds the manifest, wrapped code and analyzer output of each code block
+    pub log_level: LogLevel,
+    /// RUSTDOC_TWOSLASH_PARALLELISM: number of analyzers to run concurrently
+    pub parallelism: usize,
+    /// RUSTDOC_TWOSLASH_NO_CACHE: bypass the annotation cache
+    pub no_cache: bool,
+    /// RUSTDOC_TWOSLASH_DEDENT: [`dedent`](super::dedent) every code block before analyzing
+    /// it, like the `dedent` block attribute
+    pub dedent: bool,
+    /// RUSTDOC_TWOSLASH_NO_STD: leave every code block out of `fn main`, as the ones starting
+    /// with `#![no_std]` are, for when that isn't detected (see [`is_no_std`](super::is_no_std))
+    pub no_std: bool,
+    /// RUSTDOC_TWOSLASH_WATCH_CARGO: [reinitialize](super::reinitialize_analyzer) the analyzers
+    /// whenever Cargo.toml or Cargo.lock changes
+    pub watch_cargo: bool,
+    /// RUSTDOC_TWOSLASH_THREAD_LOCAL: give each thread its own analyzer, see
+    /// [`with_local_analyzer`](super::with_local_analyzer), instead of sharing the pool of
+    /// `parallelism` analyzers
+    pub thread_local_analyzers: bool,
+    /// RUSTDOC_TWOSLASH_OFFLINE: run Cargo offline, and report dependencies that aren't in
+    /// Cargo's cache as [`OfflineMissingDep`](super::TwoslashError::OfflineMissingDep).
+    /// The `cargo check` of [`diagnostics_for_code`](super::diagnostics_for_code) gets
+    /// `--offline`; the analyzers' own Cargo can't be passed that, so CARGO_NET_OFFLINE=true is
+    /// set for it to inherit, see [`new_analyzer_with`](super::new_analyzer_with).
+    pub offline: bool,
+    /// RUSTDOC_TWOSLASH_EXTRA_DEPS: `name=version` dependencies to add to the manifest
+    pub extra_deps: Vec<(String, String)>,
+    /// RUSTDOC_TWOSLASH_ASYNC_RUNTIME: runtime for the `async fn main` of examples using `.await`
+    pub async_runtime: AsyncRuntime,
+    /// RUSTDOC_TWOSLASH_RESOLVE_URLS: derive documentation URLs for annotations.
+    ///
+    /// Off by default since the URLs are guessed from hover text.
+    pub resolve_urls: bool,
+    /// How hover text is shortened, see [`TypeTextConfig::from_resolver`]
+    pub type_text: TypeTextConfig,
+    /// RUSTDOC_TWOSLASH_SINGLE_CHAR_BINDINGS: annotate bindings with one-character names,
+    /// such as `a` in `let (a, b) = pair;` (`true` or `false`)
+    pub annotate_single_char_bindings: bool,
+    /// RUSTDOC_TWOSLASH_ITERATOR_STEPS: annotate each intermediate call of a method chain,
+    /// such as `map` in `v.iter().map(f).collect()`, with the type it gives (`true` or
+    /// `false`). Off by default since it puts a tooltip on every step.
+    pub show_iterator_steps: bool,
+    /// RUSTDOC_TWOSLASH_OUTPUT: `jsonlines` to also write the annotations of each code block
+    /// as JSON lines, for CI tooling
+    pub output: OutputFormat,
+    /// RUSTDOC_TWOSLASH_OUTPUT_FILE: file the JSON lines are written to, stdout if `None`
+    /// (set as `-`)
+    pub output_file: Option<PathBuf>,
+    /// RUSTDOC_TWOSLASH_STATS_FILE: file the [`AnnotationStats`](super::render::AnnotationStats)
+    /// of each code block are written to as JSON lines
+    pub stats_file: Option<PathBuf>,
+    /// RUSTDOC_TWOSLASH_MAX_ANNOTATIONS: most annotations to keep per code block, 0 or
+    /// `None` for no limit
+    pub max_annotations: Option<usize>,
+    /// RUSTDOC_TWOSLASH_TRIM_STRATEGY: which annotations are kept over `max_annotations`,
+    /// see [`AnnotationTrimStrategy`]
+    pub trim_strategy: AnnotationTrimStrategy,
+}
+
+/// How much twoslash logs
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Nothing at all
+    Off,
+    Error,
+    Warn,
+    #[default]
+    Info,
+    Debug,
+}
+
+/// Where annotations go besides the rendered pages
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Only into the HTML
+    #[default]
+    Html,
+    /// Also one JSON object per annotation, see [`JsonLinesRecord`](super::render::JsonLinesRecord)
+    JsonLines,
+}
+
+impl Default for TwoslashConfig {
+    fn default() -> Self {
+        TwoslashConfig {
+            enabled: false,
+            cargo_toml_path: None,
+            target_dir: TARGET_DIR.into(),
+            cache_dir: default_cache_dir(),
+            timeout_secs: DEFAULT_TIMEOUT_SECS,
+            partial_on_timeout: false,
+            slow_threshold_ms: DEFAULT_SLOW_THRESHOLD_MS,
+            features: vec![],
+            log_level: LogLevel::default(),
+            parallelism: std::thread::available_parallelism().map_or(1, NonZeroUsize::get),
+            no_cache: false,
+            dedent: false,
+            no_std: false,
+            watch_cargo: false,
+            thread_local_analyzers: false,
+            offline: false,
+            extra_deps: vec![],
+            async_runtime: AsyncRuntime::default(),
+            resolve_urls: false,
+            type_text: TypeTextConfig::default(),
+            annotate_single_char_bindings: true,
+            show_iterator_steps: false,
+            output: OutputFormat::default(),
+            output_file: None,
+            stats_file: None,
+            max_annotations: None,
+            trim_strategy: AnnotationTrimStrategy::default(),
+        }
+    }
+}
+
+impl TwoslashConfig {
+    /// The configuration of the process, read from the environment on first use
+    pub fn global() -> &'static TwoslashConfig {
+        &CONFIG
+    }
+
+    /// Read the configuration from the environment.
+    ///
+    /// Invalid values are reported and replaced by their default.
+    pub fn from_env() -> TwoslashConfig {
+        TwoslashConfig::from_resolver(&DefaultCargoResolver)
+    }
+
+    pub(super) fn from_resolver(resolver: &dyn CargoResolver) -> TwoslashConfig {
+        let default = TwoslashConfig::default();
+        let var = |key: &str| resolver.env_var(&format!("RUSTDOC_TWOSLASH{key}"));
+        let non_empty = |key: &str| var(key).filter(|v| !v.trim().is_empty());
+        // A value that doesn't parse falls back to `default`, with a warning
+        fn parse<T: std::str::FromStr>(resolver: &dyn CargoResolver, key: &str, default: T) -> T {
+            let value = resolver.env_var(&format!("RUSTDOC_TWOSLASH{key}"));
+            let Some(value) = value.filter(|v| !v.trim().is_empty()) else { return default };
+            value.trim().parse().unwrap_or_else(|_| {
+                twoslash_log!(LogLevel::Warn, "ignoring invalid RUSTDOC_TWOSLASH{key}=`{value}`");
+                default
+            })
+        }
+
+        let parallelism = match parse(resolver, "_PARALLELISM", 0) {
+            0 => default.parallelism,
+            n => n,
+        };
+        let extra_deps = match non_empty("_EXTRA_DEPS").as_deref().map(parse_extra_deps) {
+            Some(Ok(deps)) => deps,
+            Some(Err(e)) => {
+                twoslash_log!(LogLevel::Warn, "ignoring RUSTDOC_TWOSLASH_EXTRA_DEPS: {e}");
+                vec![]
+            }
+            None => vec![],
+        };
+        TwoslashConfig {
+            enabled: var("").is_some_and(|v| {
+                env_switch(&v).unwrap_or_else(|| {
+                    twoslash_log!(LogLevel::Warn, "ignoring invalid RUSTDOC_TWOSLASH=`{v}`");
+                    false
+                })
+            }),
+            cargo_toml_path: non_empty("_CARGO_TOML").map(PathBuf::from),
+            target_dir: non_empty("_TARGET_DIR").map_or(default.target_dir, PathBuf::from),
+            cache_dir: non_empty("_CACHE_DIR").map_or(default.cache_dir, PathBuf::from),
+            timeout_secs: parse(resolver, "_TIMEOUT_SECS", DEFAULT_TIMEOUT_SECS),
+            partial_on_timeout: var("_PARTIAL_ON_TIMEOUT").is_some(),
+            slow_threshold_ms: parse(resolver, "_SLOW_THRESHOLD_MS", DEFAULT_SLOW_THRESHOLD_MS),
+            features: var("_FEATURES").map(|v| parse_features(&v)).unwrap_or_default(),
+            log_level: parse(resolver, "_LOG_LEVEL", default.log_level),
+            parallelism,
+            no_cache: var("_NO_CACHE").is_some(),
+            dedent: var("_DEDENT").is_some(),
+            no_std: var("_NO_STD").is_some(),
+            watch_cargo: var("_WATCH_CARGO").is_some(),
+            thread_local_analyzers: var("_THREAD_LOCAL").is_some(),
+            offline: var("_OFFLINE").is_some(),
+            extra_deps,
+            async_runtime: AsyncRuntime::from_env(resolver),
+            resolve_urls: var("_RESOLVE_URLS").is_some(),
+            type_text: TypeTextConfig::from_resolver(resolver),
+            annotate_single_char_bindings: parse(resolver, "_SINGLE_CHAR_BINDINGS", true),
+            show_iterator_steps: parse(resolver, "_ITERATOR_STEPS", false),
+            output: parse(resolver, "_OUTPUT", default.output),
+            output_file: non_empty("_OUTPUT_FILE")
+                .filter(|file| file.trim() != "-")
+                .map(PathBuf::from),
+            stats_file: non_empty("_STATS_FILE").map(PathBuf::from),
+            max_annotations: Some(parse(resolver, "_MAX_ANNOTATIONS", 0)).filter(|&max| max > 0),
+            trim_strategy: parse(resolver, "_TRIM_STRATEGY", default.trim_strategy),
+        }
+    }
+
+    /// How long a single code block may take to analyze, or `None` for no limit
+    pub fn timeout(&self) -> Option<Duration> {
+        (self.timeout_secs > 0).then(|| Duration::from_secs(self.timeout_secs))
+    }
+
+    /// [`target_dir`](Self::target_dir) with `suffix` appended, for analyzers that need a
+    /// build directory of their own
+    pub(super) fn target_dir_with_suffix(&self, suffix: &str) -> PathBuf {
+        let mut dir = self.target_dir.clone().into_os_string();
+        dir.push(suffix);
+        dir.into()
+    }
+}
+
+impl std::str::FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<LogLevel, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "off" => Ok(LogLevel::Off),
+            "error" => Ok(LogLevel::Error),
+            "warn" | "warning" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Which annotations of a code block are kept when it has more than
+/// [`TwoslashConfig::max_annotations`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AnnotationTrimStrategy {
+    /// The ones with the longest type text, which tend to say the most
+    #[default]
+    MostInformative,
+    /// The first ones in the code
+    First,
+    /// The last ones in the code
+    Last,
+    /// The `n` most informative of each line, then the first ones of those
+    ByLine(usize),
+}
+
+impl std::str::FromStr for AnnotationTrimStrategy {
+    type Err = ();
+
+    /// `most-informative`, `first`, `last` or `by-line:N`
+    fn from_str(s: &str) -> Result<AnnotationTrimStrategy, ()> {
+        match s.to_ascii_lowercase().replace('_', "-").as_str() {
+            "most-informative" => Ok(AnnotationTrimStrategy::MostInformative),
+            "first" => Ok(AnnotationTrimStrategy::First),
+            "last" => Ok(AnnotationTrimStrategy::Last),
+            s => match s.strip_prefix("by-line:").map(|n| n.trim().parse()) {
+                Some(Ok(n)) => Ok(AnnotationTrimStrategy::ByLine(n)),
+                _ => Err(()),
+            },
+        }
+    }
+}
+
+impl std::str::FromStr for OutputFormat {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<OutputFormat, ()> {
+        match s.to_ascii_lowercase().as_str() {
+            "html" => Ok(OutputFormat::Html),
+            "jsonlines" | "jsonl" => Ok(OutputFormat::JsonLines),
+            _ => Err(()),
+        }
+    }
+}
+
+pub(super) fn parse_features(list: &str) -> Vec<String> {
+    list.split(',').map(str::trim).filter(|f| !f.is_empty()).map(String::from).collect()
+}
+
+/// A malformed RUSTDOC_TWOSLASH_EXTRA_DEPS entry
+#[derive(Debug, Clone, PartialEq)]
+pub(super) enum ExtraDepError {
+    /// An entry that isn't `name=version`
+    Malformed(String),
+    InvalidName(String),
+    InvalidVersion { name: String, version: String },
+}
+
+impl fmt::Display for ExtraDepError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ExtraDepError::Malformed(entry) => {
+                write!(f, "expected `name=version`, found `{entry}`")
+            }
+            ExtraDepError::InvalidName(name) => write!(f, "invalid crate name `{name}`"),
+            ExtraDepError::InvalidVersion { name, version } => {
+                write!(f, "invalid version requirement `{version}` for `{name}`")
+            }
+        }
+    }
+}
+
+/// A single-comparator semver requirement, such as `1.0`, `^0.4.1`, `>=2` or `=1.2.3-rc.1`
+pub(super) static VERSION_REQ: Lazy<Regex> = Lazy::new(|| {
+    Regex::new(concat!(
+        r"^(?:\*|(?:[=^~]|[<>]=?)?\s*\d+(?:\.(?:\d+|\*|x)){0,2}",
+        r"(?:-[0-9A-Za-z.-]+)?(?:\+[0-9A-Za-z.-]+)?)$",
+    ))
+    .unwrap()
+});
+
+/// Parse a comma-separated list of `name=version` dependencies, as in
+/// `serde_json=1.0,anyhow=1.0`
+pub(super) fn parse_extra_deps(list: &str) -> Result<Vec<(String, String)>, ExtraDepError> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(|entry| {
+            let (name, version) = entry
+                .split_once('=')
+                .ok_or_else(|| ExtraDepError::Malformed(entry.to_string()))?;
+            let (name, version) = (name.trim(), version.trim());
+            if name.is_empty()
+                || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+            {
+                return Err(ExtraDepError::InvalidName(name.to_string()));
+            }
+            if !VERSION_REQ.is_match(version) {
+                let (name, version) = (name.to_string(), version.to_string());
+                return Err(ExtraDepError::InvalidVersion { name, version });
+            }
+            Ok((name.to_string(), version.to_string()))
+        })
+        .collect()
+}
+
+/// Check if twoslash processing is enabled ([`TwoslashConfig::enabled`]).
+///
+/// It is when RUSTDOC_TWOSLASH is set to `1`, `true`, `on`, `yes` or nothing, and isn't
+/// when it's unset or set to `0`, `false`, `off` or `no`, in any case.
+pub fn is_enabled() -> bool {
+    CONFIG.enabled
+}
+
+/// The value of an on/off environment variable: `true` for `1`, `true`, `on`, `yes` and
+/// the empty string (the variable is just set), `false` for `0`, `false`, `off` and `no`,
+/// in any case, or `None` for anything else
+pub(super) fn env_switch(value: &str) -> Option<bool> {
+    match value.trim().to_ascii_lowercase().as_str() {
+        "" | "1" | "true" | "on" | "yes" => Some(true),
+        "0" | "false" | "off" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+/// How [`normalize_type_text`](super::render::normalize_type_text) shortens the hover text of
+/// annotations
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TypeTextConfig {
+    /// Longest signature to show, in characters; longer ones are cut off with `…`
+    pub max_length: Option<usize>,
+    /// Module paths to remove from the start of the paths in a signature, such as
+    /// `std::collections::hash::map`
+    pub strip_prefixes: Vec<String>,
+    /// Show types from `std`, `core` and `alloc` by their name alone, so that
+    /// `alloc::string::String` becomes `String`
+    pub collapse_std: bool,
+}
+
+impl TypeTextConfig {
+    /// Read the config from RUSTDOC_TWOSLASH_STRIP_PREFIXES (comma-separated) and
+    /// RUSTDOC_TWOSLASH_MAX_TYPE_LEN (0 for no limit)
+    fn from_resolver(resolver: &dyn CargoResolver) -> TypeTextConfig {
+        let max_length = resolver.env_var("RUSTDOC_TWOSLASH_MAX_TYPE_LEN").and_then(|v| {
+            let len = v.trim().parse::<usize>();
+            if len.is_err() {
+                twoslash_log!(
+                    LogLevel::Warn,
+                    "ignoring invalid RUSTDOC_TWOSLASH_MAX_TYPE_LEN=`{v}`"
+                );
+            }
+            len.ok().filter(|&len| len > 0)
+        });
+        let strip_prefixes = resolver
+            .env_var("RUSTDOC_TWOSLASH_STRIP_PREFIXES")
+            .map(|v| parse_features(&v))
+            .unwrap_or_default();
+        TypeTextConfig { max_length, strip_prefixes, collapse_std: false }
+    }
+}
@@ -0,0 +1,610 @@
+//! The Cargo.toml the temp projects are analyzed with, derived from the documented crate's
+
+use once_cell::sync::Lazy;
+use std::io;
+use std::path::{Component, Path, PathBuf};
+use std::sync::{PoisonError, RwLock};
+use toml_edit::{Array, DocumentMut, InlineTable, Item, Table, TableLike, table, value};
+
+use super::AsyncRuntime;
+use super::config::{CONFIG, LogLevel, TwoslashConfig};
+
+/// Resolved Cargo.toml shared by every analyzer instance, see [`shared_cargo_toml`]
+pub(super) static CARGO_TOML: Lazy<RwLock<Option<String>>> =
+    Lazy::new(|| RwLock::new(resolve_cargo_toml(&DefaultCargoResolver, &CONFIG)));
+
+/// The current Cargo.toml of the shared analyzers
+pub(super) fn shared_cargo_toml() -> Option<String> {
+    CARGO_TOML.read().unwrap_or_else(PoisonError::into_inner).clone()
+}
+
+/// Manifest for a block that's in `edition` and wrapped in an `async fn main` for `runtime`,
+/// if it is: the shared one, with the edition pinned and the runtime added as a dependency.
+///
+/// Without a shared manifest, a block that needs a runtime gets a fallback one.
+pub(super) fn block_cargo_toml(
+    edition: Option<&str>,
+    runtime: Option<AsyncRuntime>,
+) -> Option<String> {
+    let shared_toml = shared_cargo_toml();
+    let cargo_toml = match runtime {
+        Some(runtime) => Some(with_runtime_dependency(
+            shared_toml.as_deref().unwrap_or(FALLBACK_CARGO_TOML),
+            runtime,
+        )),
+        None => shared_toml,
+    };
+    match edition {
+        Some(edition) => cargo_toml.map(|cargo_toml| {
+            edit_manifest(&cargo_toml, |doc| with_edition(doc, edition))
+        }),
+        None => cargo_toml,
+    }
+}
+
+/// `cargo_toml` with `runtime` added as a dependency, unless it already depends on it
+pub(super) fn with_runtime_dependency(cargo_toml: &str, runtime: AsyncRuntime) -> String {
+    edit_manifest(cargo_toml, |doc| {
+        let dependency = (runtime.crate_name().to_string(), runtime.dependency().to_string());
+        inject_extra_dependencies(doc, &[dependency]);
+    })
+}
+
+/// Manifests relevant to the crate being documented
+#[derive(Debug, Default, Clone, PartialEq)]
+pub(super) struct ManifestLocation {
+    /// Nearest manifest with a `[package]` section
+    pub(super) package: Option<PathBuf>,
+    /// Nearest enclosing manifest with a `[workspace]` section (may be `package` itself)
+    pub(super) workspace: Option<PathBuf>,
+}
+
+impl ManifestLocation {
+    /// Cargo.lock of the build, which lives next to the workspace root if there is one
+    pub(super) fn lock_file(&self) -> Option<PathBuf> {
+        let root = self.workspace.as_ref().or(self.package.as_ref())?;
+        Some(root.with_file_name("Cargo.lock"))
+    }
+}
+
+/// The file system and environment access needed to resolve the Cargo.toml to analyze with
+pub trait CargoResolver {
+    fn read_file(&self, path: &Path) -> io::Result<String>;
+    fn current_dir(&self) -> io::Result<PathBuf>;
+    fn env_var(&self, key: &str) -> Option<String>;
+}
+
+/// A [`CargoResolver`] for the real file system and process environment
+pub struct DefaultCargoResolver;
+
+impl CargoResolver for DefaultCargoResolver {
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        std::fs::read_to_string(path)
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        std::env::current_dir()
+    }
+
+    fn env_var(&self, key: &str) -> Option<String> {
+        std::env::var(key).ok()
+    }
+}
+
+/// Manifests of the crate being documented, located once per process
+pub(super) static MANIFESTS: Lazy<ManifestLocation> =
+    Lazy::new(|| locate_manifests(&DefaultCargoResolver, &CONFIG));
+
+/// Locate the Cargo.toml to use for twoslash analysis.
+///
+/// The first of these that exists is used:
+///
+/// 1. [`TwoslashConfig::cargo_toml_path`], relative to the current directory
+/// 2. `$CARGO_MANIFEST_DIR/Cargo.toml`, which Cargo sets when it runs rustdoc
+/// 3. `./Cargo.toml`
+/// 4. the nearest Cargo.toml with a `[package]` in a parent directory
+///
+/// Either way the enclosing workspace root (if any) is also located.
+pub(super) fn locate_manifests(
+    resolver: &dyn CargoResolver,
+    config: &TwoslashConfig,
+) -> ManifestLocation {
+    let cwd = resolver.current_dir().unwrap_or_default();
+    if let Some(path) = &config.cargo_toml_path {
+        let path = cwd.join(path);
+        let mut location =
+            path.parent().map(|dir| find_manifests(resolver, dir)).unwrap_or_default();
+        location.package = Some(path);
+        return location;
+    }
+    if let Some(dir) = resolver.env_var("CARGO_MANIFEST_DIR") {
+        let dir = cwd.join(dir);
+        if resolver.read_file(&dir.join("Cargo.toml")).is_ok() {
+            return find_manifests(resolver, &dir);
+        }
+    }
+    find_manifests(resolver, &cwd)
+}
+
+/// Walk `start` and its parent directories looking for Cargo manifests.
+///
+/// Stops at the first workspace root, since Cargo doesn't nest workspaces.
+pub(super) fn find_manifests(resolver: &dyn CargoResolver, start: &Path) -> ManifestLocation {
+    let mut location = ManifestLocation::default();
+    for dir in start.ancestors() {
+        let path = dir.join("Cargo.toml");
+        let Ok(content) = resolver.read_file(&path) else { continue };
+        let Ok(doc) = content.parse::<DocumentMut>() else {
+            twoslash_log!(LogLevel::Warn, "can't parse {}, skipping it", path.display());
+            continue;
+        };
+        if location.package.is_none() && has_table(&doc, "package") {
+            location.package = Some(path.clone());
+        }
+        if has_table(&doc, "workspace") {
+            location.workspace = Some(path);
+            break;
+        }
+    }
+    location
+}
+
+/// `cargo_toml` changed by `edit`, or as it is, with a warning, if it isn't valid TOML
+pub(super) fn edit_manifest(cargo_toml: &str, edit: impl FnOnce(&mut DocumentMut)) -> String {
+    match cargo_toml.parse::<DocumentMut>() {
+        Ok(mut doc) => {
+            edit(&mut doc);
+            doc.to_string()
+        }
+        Err(e) => {
+            twoslash_log!(LogLevel::Warn, "can't parse Cargo.toml, leaving it as it is: {e}");
+            cargo_toml.to_string()
+        }
+    }
+}
+
+/// Check whether a manifest contains the given table, or any of its subtables
+pub(super) fn has_table(doc: &DocumentMut, name: &str) -> bool {
+    doc.get(name).is_some_and(Item::is_table_like)
+}
+
+/// Rewrite relative `path = "..."` dependencies to absolute paths, in every dependency table
+/// including the `[target.*]` and `[workspace]` ones.
+///
+/// The temp project lives elsewhere, so paths relative to the original manifest would dangle.
+pub(super) fn absolutize_dependency_paths(doc: &mut DocumentMut, base: &Path) {
+    fn absolutize(table: &mut dyn TableLike, base: &Path) {
+        for (key, item) in table.iter_mut() {
+            let Some(table) = item.as_table_like_mut() else { continue };
+            if !matches!(key.get(), "dependencies" | "dev-dependencies" | "build-dependencies") {
+                absolutize(table, base);
+                continue;
+            }
+            for (_, spec) in table.iter_mut() {
+                let path = spec.as_table_like_mut().and_then(|spec| spec.get_mut("path"));
+                let Some(Item::Value(path)) = path else { continue };
+                let Some(relative) = path.as_str() else { continue };
+                let decor = path.decor().clone();
+                *path = base.join(relative).display().to_string().into();
+                *path.decor_mut() = decor;
+            }
+        }
+    }
+    absolutize(doc.as_table_mut(), base);
+}
+
+/// Make `workspace = true` inheritance in the temp project resolve.
+///
+/// Member manifests pull versions and package fields from the workspace root, which the
+/// temp project isn't part of. Copying the root's `[workspace.*]` tables (with paths made
+/// absolute) turns the temp project into a workspace root offering the same definitions.
+/// The root's own `[workspace]` keys are left out since its `members` are meaningless outside
+/// the original tree.
+pub(super) fn inherit_workspace_tables(
+    doc: &mut DocumentMut,
+    workspace: &DocumentMut,
+    workspace_dir: &Path,
+) {
+    // A `package.workspace` key would point back into the original tree
+    if let Some(package) = doc.get_mut("package").and_then(Item::as_table_like_mut) {
+        package.remove("workspace");
+    }
+    let mut workspace = workspace.clone();
+    absolutize_dependency_paths(&mut workspace, workspace_dir);
+    let mut inherited = Table::new();
+    if let Some(root) = workspace.get("workspace").and_then(Item::as_table_like) {
+        for (key, item) in root.iter().filter(|(_, item)| item.is_table_like()) {
+            inherited.insert(key, item.clone());
+        }
+    }
+    doc.insert("workspace", Item::Table(inherited));
+    // Positions are the root's, which would interleave its tables with the member's
+    let mut position = doc.iter().count();
+    for (_, table) in doc["workspace"].as_table_mut().into_iter().flat_map(Table::iter_mut) {
+        if let Some(table) = table.as_table_mut() {
+            position += 1;
+            table.set_position(position);
+        }
+    }
+}
+
+/// Edition declared by a package manifest.
+///
+/// `edition.workspace = true` is looked up in the workspace's `[workspace.package]`.
+/// Manifests without an edition get Cargo's default, 2015.
+pub(super) fn manifest_edition(doc: &DocumentMut, workspace: Option<&DocumentMut>) -> String {
+    let edition = doc.get("package").and_then(|package| package.get("edition"));
+    if let Some(edition) = edition.and_then(Item::as_str) {
+        return edition.to_string();
+    }
+    let inherited = edition.and_then(|edition| edition.get("workspace")?.as_bool());
+    let workspace_edition = workspace
+        .and_then(|workspace| workspace.get("workspace")?.get("package")?.get("edition")?.as_str())
+        .filter(|_| inherited == Some(true));
+    workspace_edition.unwrap_or("2015").to_string()
+}
+
+/// Set the `[package]` edition of a manifest, replacing whatever it declared
+pub(super) fn with_edition(doc: &mut DocumentMut, edition: &str) {
+    // Covers both `edition = "..."` and `edition.workspace = true`
+    if let Some(package) = doc.entry("package").or_insert(table()).as_table_like_mut() {
+        package.insert("edition", value(edition));
+    }
+}
+
+/// Resolve the Cargo.toml to use for twoslash analysis.
+///
+/// This lets twoslash-rust scaffold temp projects with the same dependencies
+/// as the crate being documented.
+pub(super) fn resolve_cargo_toml(
+    resolver: &dyn CargoResolver,
+    config: &TwoslashConfig,
+) -> Option<String> {
+    let location = &locate_manifests(resolver, config);
+
+    let Some(cargo_path) = location.package.as_ref().or(location.workspace.as_ref()) else {
+        twoslash_log!(LogLevel::Warn, "no Cargo.toml found, external deps won't have annotations");
+        return None;
+    };
+
+    let content = match resolver.read_file(cargo_path) {
+        Ok(c) => c,
+        Err(_) => {
+            twoslash_log!(
+                LogLevel::Warn,
+                "no Cargo.toml found, external deps won't have annotations"
+            );
+            return None;
+        }
+    };
+
+    twoslash_log!(LogLevel::Info, "using Cargo.toml from {}", cargo_path.display());
+
+    // Add the crate being documented as a path dependency so that
+    // code examples referencing `crate_name::foo` resolve correctly.
+    let crate_dir = cargo_path.parent()?;
+    let mut doc = match content.parse::<DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => {
+            let path = cargo_path.display();
+            twoslash_log!(LogLevel::Warn, "can't parse {path}, not using it: {e}");
+            return None;
+        }
+    };
+    absolutize_dependency_paths(&mut doc, crate_dir);
+    let features = requested_features(resolver, &config.features, &doc);
+    let content = doc.to_string();
+    let features: Vec<&str> = features.iter().map(String::as_str).collect();
+    let (augmented, injected) =
+        inject_self_dependency(resolver, &content, &crate_dir.to_string_lossy(), &features);
+    match injected {
+        InjectionResult::Injected => {
+            twoslash_log!(LogLevel::Info, "adding self-dependency on {}", crate_dir.display());
+        }
+        InjectionResult::NotInjected => {
+            twoslash_log!(LogLevel::Info, "manifest already depends on itself, keeping its entry");
+        }
+        InjectionResult::SkippedVirtualManifest => {
+            twoslash_log!(
+                LogLevel::Warn,
+                "{} is a virtual manifest, crate paths won't resolve",
+                cargo_path.display()
+            );
+            return Some(content);
+        }
+    }
+    let extra_deps: Vec<_> = config
+        .extra_deps
+        .iter()
+        .map(|(name, version)| (name.clone(), format!("\"{version}\"")))
+        .collect();
+    // The self-dependency was added to valid TOML, which stays valid
+    let mut augmented = augmented.parse::<DocumentMut>().ok()?;
+    inject_extra_dependencies(&mut augmented, &extra_deps);
+
+    // AnalyzerSettings has no edition of its own; the scaffold takes it from this manifest,
+    // so pin it explicitly rather than relying on inheritance or defaults there
+    match &location.workspace {
+        Some(workspace) if workspace != cargo_path => {
+            twoslash_log!(
+                LogLevel::Info,
+                "inheriting workspace settings from {}",
+                workspace.display()
+            );
+            let workspace_toml = resolver.read_file(workspace).ok()?.parse::<DocumentMut>().ok()?;
+            let workspace_dir = workspace.parent()?;
+            let edition = manifest_edition(&doc, Some(&workspace_toml));
+            inherit_workspace_tables(&mut augmented, &workspace_toml, workspace_dir);
+            with_edition(&mut augmented, &edition);
+        }
+        _ => {
+            let edition = manifest_edition(&doc, None);
+            with_edition(&mut augmented, &edition);
+        }
+    }
+    let resolved = augmented.to_string();
+    twoslash_log!(LogLevel::Debug, "manifest for the temp projects:\n{resolved}");
+    Some(resolved)
+}
+
+/// Features of the documented crate to enable.
+///
+/// These are the `configured` ones ([`TwoslashConfig::features`]), plus any feature declared
+/// in `cargo_toml` that Cargo reports as active through its `CARGO_FEATURE_*` variables.
+pub(super) fn requested_features(
+    resolver: &dyn CargoResolver,
+    configured: &[String],
+    cargo_toml: &DocumentMut,
+) -> Vec<String> {
+    let mut features = configured.to_vec();
+    for feature in declared_features(cargo_toml) {
+        if !features.iter().any(|f| f == feature)
+            && resolver.env_var(&cargo_feature_var(feature)).is_some()
+        {
+            features.push(feature.to_string());
+        }
+    }
+    features
+}
+
+/// Names of the features in a manifest's `[features]` table
+pub(super) fn declared_features(doc: &DocumentMut) -> Vec<&str> {
+    table_keys(doc, "features")
+}
+
+/// Keys of the top-level table `name` of a manifest
+pub(super) fn table_keys<'a>(doc: &'a DocumentMut, name: &str) -> Vec<&'a str> {
+    let table = doc.get(name).and_then(Item::as_table_like);
+    table.into_iter().flat_map(|table| table.iter().map(|(key, _)| key)).collect()
+}
+
+/// Variable Cargo sets when `feature` is enabled
+pub(super) fn cargo_feature_var(feature: &str) -> String {
+    format!("CARGO_FEATURE_{}", feature.to_uppercase().replace('-', "_"))
+}
+
+/// Names of the dependencies in a manifest's `[dependencies]` table
+pub(super) fn declared_dependencies(doc: &DocumentMut) -> Vec<&str> {
+    table_keys(doc, "dependencies")
+}
+
+/// Whether two dependency names refer to the same crate, which Cargo treats `-` and `_` alike for
+pub(super) fn same_crate(a: &str, b: &str) -> bool {
+    a.replace('-', "_") == b.replace('-', "_")
+}
+
+/// Add `deps` as `name = spec` entries at the end of the `[dependencies]` table, with each
+/// spec being a TOML value such as `"1.0"` or `{ version = "1", features = [...] }`.
+///
+/// That puts them after the self-dependency, which [`inject_self_dependency`] adds to the
+/// table first. Dependencies the manifest already declares are left as they are.
+pub(super) fn inject_extra_dependencies(doc: &mut DocumentMut, deps: &[(String, String)]) {
+    let declared: Vec<String> =
+        declared_dependencies(doc).into_iter().map(String::from).collect();
+    let Some(dependencies) = doc.entry("dependencies").or_insert(table()).as_table_like_mut()
+    else {
+        twoslash_log!(LogLevel::Warn, "`dependencies` in Cargo.toml isn't a table");
+        return;
+    };
+    for (name, spec) in deps {
+        if declared.iter().any(|dep| same_crate(dep, name)) {
+            twoslash_log!(LogLevel::Info, "{name} is already a dependency, not adding it again");
+            continue;
+        }
+        match spec.parse::<toml_edit::Value>() {
+            Ok(spec) => {
+                dependencies.insert(name, Item::Value(spec));
+            }
+            Err(e) => twoslash_log!(LogLevel::Warn, "invalid spec for {name}, not adding it: {e}"),
+        }
+    }
+}
+
+/// Name the scaffold package is renamed to, so it doesn't depend on itself
+pub(super) const TMP_PACKAGE_NAME: &str = "twoslash-rustdoc-tmp";
+
+/// Outcome of [`inject_self_dependency`]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum InjectionResult {
+    /// The crate was added to `[dependencies]`
+    Injected,
+    /// The manifest already depends on the crate, or has no crate to depend on
+    NotInjected,
+    /// A virtual manifest, none of whose `members` is the crate in the current directory
+    SkippedVirtualManifest,
+}
+
+/// Inject the crate being documented as a path dependency.
+///
+/// Parses the crate name from the Cargo.toml and adds it as:
+///   `crate_name = { path = "/path/to/crate", features = [...] }`
+///
+/// Returns the new manifest and whether the dependency was added; a manifest whose
+/// `[dependencies]` already has an entry for the crate, by its name or by a `path` to
+/// `crate_path`, keeps that entry as it is.
+///
+/// A virtual manifest (`[workspace]` without `[package]`) at `crate_path` depends on the
+/// member containing the current directory instead, see [`virtual_manifest_member`]. It gets
+/// a `[package]` of its own, and its `members` are dropped since they're relative to the
+/// original tree.
+pub(super) fn inject_self_dependency(
+    resolver: &dyn CargoResolver,
+    cargo_toml: &str,
+    crate_path: &str,
+    features: &[&str],
+) -> (String, InjectionResult) {
+    let mut doc = match cargo_toml.parse::<DocumentMut>() {
+        Ok(doc) => doc,
+        Err(e) => {
+            twoslash_log!(
+                LogLevel::Warn,
+                "can't parse Cargo.toml, not adding self-dependency: {e}"
+            );
+            return (cargo_toml.to_string(), InjectionResult::NotInjected);
+        }
+    };
+    // What relative dependency paths are relative to
+    let manifest_dir = PathBuf::from(crate_path);
+    let package = doc.get("package");
+    let package_name = package.and_then(|package| package.get("name")).and_then(Item::as_str);
+    let (crate_name, crate_path) = match package_name {
+        Some(name) => (name.to_string(), crate_path.to_string()),
+        None if package.is_none() && doc.contains_key("workspace") => {
+            let Some((name, member_dir)) =
+                virtual_manifest_member(resolver, &doc, Path::new(crate_path))
+            else {
+                return (cargo_toml.to_string(), InjectionResult::SkippedVirtualManifest);
+            };
+            if let Some(workspace) = doc["workspace"].as_table_like_mut() {
+                for key in ["members", "default-members", "exclude"] {
+                    workspace.remove(key);
+                }
+            }
+            doc.insert("package", table());
+            (name, member_dir.to_string_lossy().into_owned())
+        }
+        None => return (cargo_toml.to_string(), InjectionResult::NotInjected),
+    };
+    // Already renamed by an earlier call, which added the real crate then
+    if crate_name == TMP_PACKAGE_NAME {
+        return (cargo_toml.to_string(), InjectionResult::NotInjected);
+    }
+
+    // Rename the temp project to avoid circular dependency
+    doc["package"]["name"] = value(TMP_PACKAGE_NAME);
+
+    let Some(dependencies) = doc.entry("dependencies").or_insert(table()).as_table_like_mut()
+    else {
+        twoslash_log!(LogLevel::Warn, "`dependencies` in Cargo.toml isn't a table");
+        return (cargo_toml.to_string(), InjectionResult::NotInjected);
+    };
+    // Use underscore form for the dependency key (Cargo normalizes hyphens)
+    let dep_key = crate_name.replace('-', "_");
+    if dependencies.iter().any(|(dep, _)| same_crate(dep, &dep_key)) {
+        return (doc.to_string(), InjectionResult::NotInjected);
+    }
+    // Under another name, which would conflict with the injected one
+    let crate_dir = normalize_lexically(Path::new(&crate_path));
+    let self_dep = dependencies.iter().find(|(_, spec)| {
+        let path = spec.as_table_like().and_then(|spec| spec.get("path")?.as_str());
+        path.is_some_and(|path| normalize_lexically(&manifest_dir.join(path)) == crate_dir)
+    });
+    if let Some((dep, _)) = self_dep {
+        twoslash_log!(LogLevel::Debug, "`{dep}` is a path dependency on {crate_path} already");
+        return (doc.to_string(), InjectionResult::NotInjected);
+    }
+    let mut dep = InlineTable::new();
+    dep.insert("path", crate_path.as_str().into());
+    dep.insert("package", crate_name.as_str().into());
+    if !features.is_empty() {
+        dep.insert("features", Array::from_iter(features.iter().copied()).into());
+    }
+    dependencies.insert(&dep_key, Item::Value(dep.into()));
+
+    (doc.to_string(), InjectionResult::Injected)
+}
+
+/// `path` with its `.` and `..` components resolved, without looking at the file system
+pub(super) fn normalize_lexically(path: &Path) -> PathBuf {
+    let mut normalized = PathBuf::new();
+    for component in path.components() {
+        match component {
+            Component::CurDir => {}
+            Component::ParentDir if normalized.file_name().is_some() => {
+                normalized.pop();
+            }
+            component => normalized.push(component),
+        }
+    }
+    normalized
+}
+
+/// Package name and directory of the member of the virtual manifest `doc`, in `workspace_dir`,
+/// that contains the current directory.
+///
+/// `members` are matched the way Cargo does for the common cases, as paths relative to the
+/// workspace whose components may contain `*`.
+pub(super) fn virtual_manifest_member(
+    resolver: &dyn CargoResolver,
+    doc: &DocumentMut,
+    workspace_dir: &Path,
+) -> Option<(String, PathBuf)> {
+    let members = doc["workspace"].get("members")?.as_array()?;
+    let current_dir = resolver.current_dir().ok()?;
+    let relative = current_dir.strip_prefix(workspace_dir).ok()?;
+    // Innermost first, in case members are nested
+    relative.ancestors().find_map(|dir| {
+        let is_member = |member: &toml_edit::Value| {
+            member.as_str().is_some_and(|member| member_matches(member, dir))
+        };
+        if !members.iter().any(is_member) {
+            return None;
+        }
+        let member_dir = workspace_dir.join(dir);
+        let manifest = resolver.read_file(&member_dir.join("Cargo.toml")).ok()?;
+        let manifest = manifest.parse::<DocumentMut>().ok()?;
+        let name = manifest.get("package")?.get("name")?.as_str()?.to_string();
+        Some((name, member_dir))
+    })
+}
+
+/// Whether the `members` entry `pattern` matches the workspace-relative `dir`
+pub(super) fn member_matches(pattern: &str, dir: &Path) -> bool {
+    let pattern: Vec<_> =
+        pattern.split(['/', '\\']).filter(|part| !part.is_empty() && *part != ".").collect();
+    let dir: Vec<_> = dir.components().map(|part| part.as_os_str().to_string_lossy()).collect();
+    pattern.len() == dir.len()
+        && pattern.iter().zip(&dir).all(|(pattern, part)| wildcard_matches(pattern, part))
+}
+
+/// Whether `text` matches `pattern`, where `*` matches any run of characters
+pub(super) fn wildcard_matches(pattern: &str, text: &str) -> bool {
+    match pattern.split_once('*') {
+        None => pattern == text,
+        Some((prefix, rest)) => text.strip_prefix(prefix).is_some_and(|text| {
+            text.char_indices()
+                .map(|(i, _)| i)
+                .chain([text.len()])
+                .any(|i| wildcard_matches(rest, &text[i..]))
+        }),
+    }
+}
+
+/// Manifest for temp projects when there's no package manifest to use
+pub(super) const FALLBACK_CARGO_TOML: &str =
+    "[package]\nname = \"twoslash-rustdoc-tmp\"\nversion = \"0.0.0\"\nedition = \"2021\"\n";
+
+/// The manifest [`diagnostics_for_code`](super::diagnostics_for_code) checks code against: the
+/// one the analyzers use, if it's a package, with `runtime` as a dependency if the code is
+/// wrapped in an `async fn main` for it
+pub(super) fn diagnostics_cargo_toml(runtime: Option<AsyncRuntime>) -> String {
+    let shared_toml = shared_cargo_toml();
+    let cargo_toml = shared_toml
+        .as_deref()
+        .filter(|toml| toml.parse().is_ok_and(|doc| has_table(&doc, "package")))
+        .unwrap_or(FALLBACK_CARGO_TOML);
+    match runtime {
+        Some(runtime) => with_runtime_dependency(cargo_toml, runtime),
+        None => cargo_toml.to_string(),
+    }
+}
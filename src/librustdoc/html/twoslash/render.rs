@@ -0,0 +1,496 @@
+//! What becomes of a block's annotations after analysis: the annotation postprocessor,
+//! trimming, HTML lines, JSON lines and stats
+
+use once_cell::sync::Lazy;
+use regex::Regex;
+use rustc_data_structures::fx::FxHashMap;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::collections::hash_map::Entry;
+use std::fmt::Write as _;
+use std::fs::File;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError, RwLock};
+use std::{io, iter};
+
+use crate::html::escape::Escape;
+use crate::html::highlight;
+
+use super::config::{
+    AnnotationTrimStrategy, LogLevel, OutputFormat, TwoslashConfig, TypeTextConfig,
+};
+use super::{
+    BlockAttrs, DOCS_SEPARATOR, TypeAnnotation, avg_analysis_ms, line_starts, type_and_docs,
+};
+
+/// A line of a code block with the annotations starting on it, see [`group_by_line`]
+#[derive(Debug, Clone, PartialEq)]
+pub struct AnnotationLine {
+    /// 1-based
+    pub line_number: u32,
+    /// The line without its line ending
+    pub line_text: String,
+    /// Sorted by range, which is relative to the start of the line
+    pub annotations: Vec<TypeAnnotation>,
+}
+
+impl AnnotationLine {
+    pub fn has_annotations(&self) -> bool {
+        !self.annotations.is_empty()
+    }
+
+    /// The line followed by a comment giving the types of its annotations (see
+    /// [`TypeAnnotation::to_inline_comment`]), for plain-text output.
+    ///
+    /// A line with several annotations gets one comment naming each of them, as
+    /// `let (a, b) = f();  // a: i32, b: &str`. Annotations that don't fit the line are skipped.
+    pub fn as_commented_line(&self) -> String {
+        let line = self.line_text.as_str();
+        let valid: Vec<&TypeAnnotation> =
+            self.annotations.iter().filter(|ann| ann.validate(line).is_ok()).collect();
+        let comment = match valid.as_slice() {
+            [] => return line.to_string(),
+            [ann] => ann.to_inline_comment(line),
+            anns => {
+                let labels: Vec<String> = anns.iter().map(|ann| ann.inline_label(line)).collect();
+                format!("// {}", labels.join(", "))
+            }
+        };
+        format!("{line}  {comment}")
+    }
+
+    /// The line as HTML, with each annotated token in a `twoslash-hover` span carrying the same
+    /// `data-type` and `data-docs` attributes as the tokens of a highlighted code block.
+    ///
+    /// Where annotations overlap, only the first one gets a span.
+    pub fn to_html_line(&self) -> String {
+        let line = self.line_text.as_str();
+        let mut html = String::with_capacity(line.len());
+        let mut written = 0;
+        for ann in &self.annotations {
+            let range = ann.byte_range();
+            if range.start < written || ann.validate(line).is_err() {
+                continue;
+            }
+            write!(
+                html,
+                "{}<span class=\"twoslash-hover\"{}>{}</span>",
+                Escape(&line[written..range.start]),
+                highlight::annotation_attrs(&ann.type_text, ann.docs.as_deref(), ann.kind),
+                Escape(&line[range.clone()]),
+            )
+            .unwrap();
+            written = range.end;
+        }
+        write!(html, "{}", Escape(&line[written..])).unwrap();
+        html
+    }
+}
+
+/// Split `code` into its lines, each with the annotations starting on it.
+///
+/// Every line is included, annotated or not, so templates can render the block from the
+/// result alone. Annotations starting past the end of `code` are dropped.
+pub fn group_by_line(code: &str, mut annotations: Vec<TypeAnnotation>) -> Vec<AnnotationLine> {
+    annotations.sort();
+    let mut lines: Vec<(usize, AnnotationLine)> = code
+        .split_inclusive('\n')
+        .scan(0, |start, line| {
+            let line_start = *start;
+            *start += line.len();
+            Some((line_start, line))
+        })
+        .enumerate()
+        .map(|(i, (start, line))| {
+            let line_text = line.trim_end_matches(['\n', '\r']).to_string();
+            (start, AnnotationLine { line_number: i as u32 + 1, line_text, annotations: vec![] })
+        })
+        .collect();
+    for mut ann in annotations {
+        if ann.start as usize >= code.len() {
+            continue;
+        }
+        let i = lines.partition_point(|&(start, _)| start <= ann.start as usize) - 1;
+        let (start, line) = &mut lines[i];
+        ann.start -= *start as u32;
+        line.annotations.push(ann);
+    }
+    lines.into_iter().map(|(_, line)| line).collect()
+}
+
+/// What [`process_code_block`](super::process_code_block) returns for the `annotations` of a
+/// block, writing them out as [`TwoslashConfig::output`] asks for
+pub(super) fn block_output(
+    code: &str,
+    attrs: &BlockAttrs,
+    config: &TwoslashConfig,
+    annotations: Vec<TypeAnnotation>,
+) -> Vec<TypeAnnotation> {
+    if attrs.no_annotations {
+        return vec![];
+    }
+    let mut annotations = annotations;
+    // Kinds may have changed since the annotations were made, e.g. for query results
+    for ann in &mut annotations {
+        ann.priority = ann.kind.default_priority();
+    }
+    if let Some(postprocess) = annotation_postprocessor() {
+        postprocess(code, &mut annotations);
+    }
+    let annotations = match config.max_annotations {
+        Some(max) => trim_annotations(code, annotations, max, config.trim_strategy),
+        None => annotations,
+    };
+    if config.output == OutputFormat::JsonLines {
+        write_json_lines(code, &annotations, config);
+    }
+    write_stats(code, &annotations, config);
+    annotations
+}
+
+/// A function transforming the annotations of a code block, see
+/// [`set_annotation_postprocessor`]
+pub type AnnotationPostprocessor = fn(&str, &mut Vec<TypeAnnotation>);
+
+/// The [`AnnotationPostprocessor`], if one is set. Set rarely and read for every code block,
+/// hence the `RwLock`.
+pub(super) static ANNOTATION_POSTPROCESSOR: RwLock<Option<AnnotationPostprocessor>> =
+    RwLock::new(None);
+
+/// Have `postprocess` transform the annotations of every code block, given the block's
+/// code, before [`process_code_block`](super::process_code_block) returns them.
+///
+/// This is for themes to e.g. resolve type aliases, strip internal paths or set their own
+/// `url`s. It runs after the block's own settings are applied, but before
+/// [`TwoslashConfig::max_annotations`] trims the annotations. There's at most one
+/// postprocessor, so this replaces any earlier one.
+pub fn set_annotation_postprocessor(postprocess: AnnotationPostprocessor) {
+    *ANNOTATION_POSTPROCESSOR.write().unwrap_or_else(PoisonError::into_inner) = Some(postprocess);
+}
+
+/// Stop postprocessing annotations, see [`set_annotation_postprocessor`]
+pub fn clear_annotation_postprocessor() {
+    *ANNOTATION_POSTPROCESSOR.write().unwrap_or_else(PoisonError::into_inner) = None;
+}
+
+pub(super) fn annotation_postprocessor() -> Option<AnnotationPostprocessor> {
+    *ANNOTATION_POSTPROCESSOR.read().unwrap_or_else(PoisonError::into_inner)
+}
+
+/// At most `max` of the `annotations` of `code`, picked by `strategy` and kept in their
+/// original order
+pub fn trim_annotations(
+    code: &str,
+    annotations: Vec<TypeAnnotation>,
+    max: usize,
+    strategy: AnnotationTrimStrategy,
+) -> Vec<TypeAnnotation> {
+    if annotations.len() <= max && !matches!(strategy, AnnotationTrimStrategy::ByLine(_)) {
+        return annotations;
+    }
+    // Indices of the annotations to keep, best first
+    let by_informativeness = |indices: &mut Vec<usize>| {
+        indices.sort_by_key(|&i| (std::cmp::Reverse(annotations[i].type_text.len()), i));
+    };
+    let mut kept: Vec<usize> = (0..annotations.len()).collect();
+    match strategy {
+        AnnotationTrimStrategy::MostInformative => by_informativeness(&mut kept),
+        AnnotationTrimStrategy::First => {}
+        AnnotationTrimStrategy::Last => kept.reverse(),
+        AnnotationTrimStrategy::ByLine(per_line) => {
+            let line_of = |i: usize| {
+                let start = (annotations[i].start as usize).min(code.len());
+                code.as_bytes()[..start].iter().filter(|&&b| b == b'\n').count()
+            };
+            let mut lines: FxHashMap<usize, Vec<usize>> = FxHashMap::default();
+            for i in kept.drain(..) {
+                lines.entry(line_of(i)).or_default().push(i);
+            }
+            for mut line in lines.into_values() {
+                by_informativeness(&mut line);
+                kept.extend(line.into_iter().take(per_line));
+            }
+            kept.sort_unstable();
+        }
+    }
+    kept.truncate(max);
+    kept.sort_unstable();
+    let mut annotations: Vec<Option<TypeAnnotation>> = annotations.into_iter().map(Some).collect();
+    kept.into_iter().filter_map(|i| annotations[i].take()).collect()
+}
+
+/// One annotation as written with [`OutputFormat::JsonLines`]
+#[derive(Debug, Serialize)]
+pub struct JsonLinesRecord<'a> {
+    /// Hash of the code block's source, the same across runs
+    pub block_id: &'a str,
+    pub start: u32,
+    pub length: u32,
+    /// The annotated text
+    pub token_text: &'a str,
+    pub type_text: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub docs: Option<&'a str>,
+}
+
+/// Files JSON lines and stats are written to, truncated when the process first writes to them
+pub(super) static OUTPUT_FILES: Lazy<Mutex<FxHashMap<PathBuf, File>>> = Lazy::new(Default::default);
+
+/// The annotations of `code` as JSON lines, one [`JsonLinesRecord`] each
+pub(super) fn json_lines(code: &str, annotations: &[TypeAnnotation]) -> String {
+    let hash = Sha256::digest(code.as_bytes());
+    let block_id: String = hash[..8].iter().map(|b| format!("{b:02x}")).collect();
+    let mut lines = String::new();
+    for ann in annotations {
+        let record = JsonLinesRecord {
+            block_id: &block_id,
+            start: ann.start,
+            length: ann.length,
+            token_text: ann.source_text(code).unwrap_or_default(),
+            type_text: &ann.type_text,
+            docs: ann.docs.as_deref(),
+        };
+        lines.push_str(&serde_json::to_string(&record).expect("records always serialize"));
+        lines.push('\n');
+    }
+    lines
+}
+
+/// Write the annotations of `code` to [`TwoslashConfig::output_file`] as JSON lines.
+/// Failures are logged.
+pub(super) fn write_json_lines(
+    code: &str,
+    annotations: &[TypeAnnotation],
+    config: &TwoslashConfig,
+) {
+    let lines = json_lines(code, annotations);
+    let result = match &config.output_file {
+        None => io::stdout().lock().write_all(lines.as_bytes()),
+        Some(path) => append_to_output_file(path, &lines),
+    };
+    if let Err(e) = result {
+        let target = config.output_file.as_deref().unwrap_or(Path::new("stdout"));
+        twoslash_log!(LogLevel::Error, "couldn't write annotations to {}: {e}", target.display());
+    }
+}
+
+/// Append `lines` to `path`, one of the [`OUTPUT_FILES`]
+pub(super) fn append_to_output_file(path: &Path, lines: &str) -> io::Result<()> {
+    // Under the lock so the lines of concurrently processed blocks don't interleave
+    let mut files = OUTPUT_FILES.lock().unwrap_or_else(PoisonError::into_inner);
+    match files.entry(path.to_path_buf()) {
+        Entry::Occupied(mut file) => file.get_mut().write_all(lines.as_bytes()),
+        Entry::Vacant(entry) => {
+            File::create(path).and_then(|file| entry.insert(file).write_all(lines.as_bytes()))
+        }
+    }
+}
+
+/// How much of a code block is annotated, see [`annotation_stats`]
+#[derive(Debug, Clone, PartialEq, Serialize)]
+pub struct AnnotationStats {
+    /// Identifier-like tokens in the code, keywords included
+    pub total_tokens: u32,
+    /// Tokens covered by at least one annotation
+    pub annotated_tokens: u32,
+    /// `annotated_tokens` as a percentage of `total_tokens`, 100 for code without tokens
+    pub coverage_percent: f32,
+    /// 1-based lines that have tokens, none of them annotated
+    pub missing_lines: Vec<u32>,
+    /// Average time an analysis has taken in this process, up to when the stats were taken
+    pub avg_analysis_ms: f32,
+}
+
+impl AnnotationStats {
+    /// The stats as a single line of JSON
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("stats always serialize")
+    }
+
+}
+
+/// Count the identifier-like tokens of `code` and how many of them `annotations` cover.
+///
+/// Tokens are the runs of alphanumeric characters and `_` that don't start with a digit,
+/// so a token in a comment or string counts the same as any other.
+pub fn annotation_stats(code: &str, annotations: &[TypeAnnotation]) -> AnnotationStats {
+    let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+    let mut tokens = vec![];
+    let mut token_start = None;
+    // The trailing space ends a token at the end of the code
+    for (i, c) in code.char_indices().chain(iter::once((code.len(), ' '))) {
+        match (token_start, is_ident(c)) {
+            (None, true) => token_start = Some(i),
+            (Some(start), false) => {
+                if !code[start..].starts_with(|c: char| c.is_ascii_digit()) {
+                    tokens.push(start..i);
+                }
+                token_start = None;
+            }
+            _ => {}
+        }
+    }
+
+    let starts = line_starts(code);
+    // Whether each line with tokens has an annotated one
+    let mut lines: BTreeMap<u32, bool> = BTreeMap::new();
+    let mut annotated_tokens = 0;
+    for token in &tokens {
+        let annotated = annotations.iter().any(|ann| {
+            let (start, end) = (ann.start as usize, (ann.start + ann.length) as usize);
+            start <= token.start && token.end <= end
+        });
+        annotated_tokens += u32::from(annotated);
+        let line = starts.partition_point(|&start| start <= token.start) as u32;
+        *lines.entry(line).or_default() |= annotated;
+    }
+    let total_tokens = tokens.len() as u32;
+    let coverage_percent = match total_tokens {
+        0 => 100.0,
+        total => annotated_tokens as f32 * 100.0 / total as f32,
+    };
+    let missing_lines = lines.into_iter().filter(|&(_, annotated)| !annotated);
+    AnnotationStats {
+        total_tokens,
+        annotated_tokens,
+        coverage_percent,
+        missing_lines: missing_lines.map(|(line, _)| line).collect(),
+        avg_analysis_ms: avg_analysis_ms(),
+    }
+}
+
+/// Append the [`AnnotationStats`] of a code block to [`TwoslashConfig::stats_file`], if set
+pub(super) fn write_stats(code: &str, annotations: &[TypeAnnotation], config: &TwoslashConfig) {
+    let Some(path) = &config.stats_file else { return };
+    let stats = annotation_stats(code, annotations);
+    if let Err(e) = append_to_output_file(path, &(stats.to_json() + "\n")) {
+        let path = path.display();
+        twoslash_log!(LogLevel::Error, "couldn't write annotation stats to {path}: {e}");
+    }
+}
+
+/// Crates documented on doc.rust-lang.org rather than docs.rs
+pub(super) const STD_CRATES: &[&str] = &["std", "core", "alloc", "proc_macro", "test"];
+
+/// Root of the documentation of `krate`
+pub(super) fn crate_docs_root(krate: &str) -> String {
+    let krate = krate.replace('-', "_");
+    if STD_CRATES.contains(&krate.as_str()) {
+        format!("https://doc.rust-lang.org/stable/{krate}")
+    } else {
+        format!("https://docs.rs/{krate}/latest/{krate}")
+    }
+}
+
+/// Guess the documentation page for the item or type an annotation's signature refers to.
+///
+/// A declaration with the path of its module above it (`std::collections` followed by
+/// `pub struct HashMap<K, V>`) links to the item's own page. Otherwise the first fully
+/// qualified path in the signature is looked up with the crate's documentation search.
+pub(super) fn doc_url(type_text: &str) -> Option<String> {
+    static PATH: Lazy<Regex> =
+        Lazy::new(|| Regex::new(r"\b[A-Za-z_][\w-]*(?:::[A-Za-z_]\w*)+").unwrap());
+    static DECLARATION: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(concat!(
+            r"^(?:pub(?:\([^)]*\))? )?(?:(?:const|async|unsafe) )*",
+            r"(struct|enum|union|trait|type|fn|macro_rules!|const|static|mod) ([A-Za-z_]\w*)",
+        ))
+        .unwrap()
+    });
+
+    let signature = type_and_docs(type_text).0;
+    let mut lines = signature.lines().map(str::trim).filter(|line| !line.is_empty());
+    let first = lines.next()?;
+    let is_module_path = first.split("::").all(|segment| {
+        segment.starts_with(|c: char| c.is_alphabetic() || c == '_')
+            && segment.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    });
+    if is_module_path && let Some(decl) = lines.next().and_then(|line| DECLARATION.captures(line))
+    {
+        let mut segments = first.split("::");
+        let root = crate_docs_root(segments.next()?);
+        let dir: String = segments.map(|segment| format!("/{segment}")).collect();
+        let name = &decl[2];
+        let page = match &decl[1] {
+            "mod" => format!("{name}/index"),
+            "macro_rules!" => format!("macro.{name}"),
+            "const" => format!("constant.{name}"),
+            kind => format!("{kind}.{name}"),
+        };
+        return Some(format!("{root}{dir}/{page}.html"));
+    }
+
+    let path = PATH.find(signature)?.as_str();
+    let krate = path.split("::").next()?;
+    Some(format!("{}/index.html?search={}", crate_docs_root(krate), path.replace('-', "_")))
+}
+
+/// Shorten the signature in an annotation's hover text as `config` asks for.
+///
+/// Only the signature is changed; documentation after it is kept as it is.
+pub fn normalize_type_text(type_text: &str, config: &TypeTextConfig) -> String {
+    // One of `std`, `core` or `alloc`, its modules, and the type they lead to
+    static STD_PATH: Lazy<Regex> = Lazy::new(|| {
+        Regex::new(r"(^|[^\w:])(?:::)?(?:std|core|alloc)(?:::[a-z_][a-z0-9_]*)*::([A-Z])").unwrap()
+    });
+
+    let (signature, docs) = match type_text.find(DOCS_SEPARATOR) {
+        Some(i) => type_text.split_at(i),
+        None => (type_text, ""),
+    };
+    let mut signature = signature.to_string();
+    for prefix in &config.strip_prefixes {
+        let prefix = prefix.trim().trim_end_matches("::");
+        if !prefix.is_empty() {
+            signature = strip_path_prefix(&signature, &format!("{prefix}::"));
+        }
+    }
+    if config.collapse_std {
+        signature = STD_PATH.replace_all(&signature, "$1$2").into_owned();
+    }
+    if let Some(max) = config.max_length
+        && signature.chars().count() > max
+    {
+        signature = signature.chars().take(max.saturating_sub(1)).chain(['…']).collect();
+    }
+    signature + docs
+}
+
+/// Remove `prefix` wherever a path in `text` starts with it
+pub(super) fn strip_path_prefix(text: &str, prefix: &str) -> String {
+    let mut stripped = String::with_capacity(text.len());
+    let mut last = 0;
+    for (i, _) in text.match_indices(prefix) {
+        // Part of a longer name or path, as in `my_std::` for `std::`
+        let before = text[..i].chars().next_back();
+        if before.is_some_and(|c| c.is_alphanumeric() || c == '_' || c == ':') {
+            continue;
+        }
+        stripped.push_str(&text[last..i]);
+        last = i + prefix.len();
+    }
+    stripped.push_str(&text[last..]);
+    stripped
+}
+
+/// `docs` escaped for a double-quoted HTML attribute such as `data-docs`.
+///
+/// Newlines are kept as character references, other control characters are dropped.
+pub fn sanitize_docs_for_attribute(docs: &str) -> String {
+    let mut sanitized = String::with_capacity(docs.len());
+    for c in docs.chars() {
+        match c {
+            '"' => sanitized.push_str("&quot;"),
+            '&' => sanitized.push_str("&amp;"),
+            '<' => sanitized.push_str("&lt;"),
+            '>' => sanitized.push_str("&gt;"),
+            '\n' => sanitized.push_str("&#10;"),
+            '\t' => sanitized.push('\t'),
+            c if c.is_control() => {}
+            c => sanitized.push(c),
+        }
+    }
+    sanitized
+}
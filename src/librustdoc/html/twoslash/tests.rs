@@ -0,0 +1,2300 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, SystemTime};
+
+use proptest::prelude::*;
+use proptest::strategy::Union;
+use proptest::string::string_regex;
+use rustc_data_structures::fx::{FxHashMap, FxHashSet};
+use sha2::{Digest, Sha256};
+use twoslash_rust::{AnalysisResult, StaticQuickInfo};
+
+use crate::html::highlight::annotate_html;
+
+use super::cache::{CacheFormatError, CacheKind, TypeAnnotationCache, cached, load_cached};
+use super::config::{
+    AnnotationTrimStrategy, ExtraDepError, LogLevel, OutputFormat, TwoslashConfig, TypeTextConfig,
+    env_switch, parse_extra_deps, parse_features,
+};
+use super::manifest::{
+    CargoResolver, DefaultCargoResolver, InjectionResult, ManifestLocation,
+    absolutize_dependency_paths, cargo_feature_var, declared_features, edit_manifest,
+    find_manifests, inherit_workspace_tables, inject_extra_dependencies, inject_self_dependency,
+    locate_manifests, manifest_edition, member_matches, resolve_cargo_toml, with_edition,
+    with_runtime_dependency,
+};
+use super::render::{
+    annotation_stats, clear_annotation_postprocessor, doc_url, group_by_line, json_lines,
+    normalize_type_text, sanitize_docs_for_attribute, set_annotation_postprocessor,
+    trim_annotations, write_json_lines,
+};
+use super::{
+    AnalyzerSettingsBuilder, AnalyzerTrait, AnnotationError, AsyncRuntime, BlockAttrs,
+    CombinedFiles, ConfigError, DiagnosticError, ErrorsDirective, LexState, LineKind,
+    MultiFileBlock, Pool, QueryComment, QueryError, TokenClass, TokenKind, TwoslashError,
+    TypeAnnotation, WrappedCode, annotate, annotate_method_chains, annotations_for_html,
+    apply_ignore_directives, check_errors, classify_line, classify_token, dedent,
+    dedup_annotations, find_comment_spans, ignore_directives, inject_cfg_flag, is_no_std,
+    macro_call_len, module_name, nesting_delta, normalize_line_endings, offline_missing_dep,
+    parse_block_attrs, parse_diagnostics, parse_errors_directive, parse_query_comments,
+    process_code_block_with, quick_info_annotation, remove_ranges, resolve_queries,
+    run_with_timeout, split_files, split_items_and_statements, split_tuple_patterns,
+    strip_hidden_lines, synthesize_fn_signatures, unannotated_if_cut_short, validate_annotations,
+    with_files,
+};
+
+mod integration;
+
+fn write_manifest(dir: &Path, content: &str) {
+    fs::create_dir_all(dir).unwrap();
+    fs::write(dir.join("Cargo.toml"), content).unwrap();
+}
+
+/// A [`CargoResolver`] over in-memory files and environment variables
+#[derive(Default)]
+struct MockCargoResolver {
+    files: FxHashMap<PathBuf, String>,
+    current_dir: PathBuf,
+    env: FxHashMap<String, String>,
+}
+
+impl CargoResolver for MockCargoResolver {
+    fn read_file(&self, path: &Path) -> io::Result<String> {
+        self.files.get(path).cloned().ok_or_else(|| io::ErrorKind::NotFound.into())
+    }
+
+    fn current_dir(&self) -> io::Result<PathBuf> {
+        Ok(self.current_dir.clone())
+    }
+
+    fn env_var(&self, key: &str) -> Option<String> {
+        self.env.get(key).cloned()
+    }
+}
+
+/// An [`AnalyzerTrait`] answering with canned results, keyed by a hash of the code
+#[derive(Default)]
+struct MockAnalyzer {
+    results: FxHashMap<String, AnalysisResult>,
+    /// Hashes of the code it was asked to analyze, in order
+    analyzed: Vec<String>,
+}
+
+impl MockAnalyzer {
+    fn builder() -> MockAnalyzerBuilder {
+        MockAnalyzerBuilder::default()
+    }
+}
+
+impl AnalyzerTrait for MockAnalyzer {
+    fn analyze(&mut self, code: &str) -> Result<AnalysisResult, String> {
+        let hash = code_hash(code);
+        self.analyzed.push(hash.clone());
+        let result = self.results.get(&hash).ok_or_else(|| format!("no result for {code:?}"))?;
+        let infos = result.static_quick_infos.iter().map(|info| StaticQuickInfo {
+            start: info.start,
+            length: info.length,
+            text: info.text.clone(),
+            docs: info.docs.clone(),
+        });
+        Ok(AnalysisResult { static_quick_infos: infos.collect() })
+    }
+}
+
+fn code_hash(code: &str) -> String {
+    Sha256::digest(code.as_bytes()).iter().map(|b| format!("{b:02x}")).collect()
+}
+
+#[derive(Default)]
+struct MockAnalyzerBuilder {
+    results: FxHashMap<String, AnalysisResult>,
+}
+
+impl MockAnalyzerBuilder {
+    /// Answer for code block `block` with hover `text` at `start..start + length` of it.
+    ///
+    /// `block` is the code as the analyzer gets it before wrapping, that is without
+    /// directive comments; the wrapping, and the offsets, are taken care of here.
+    fn quick_info(mut self, block: &str, start: u32, length: u32, text: &str) -> Self {
+        let wrapped = WrappedCode::new(block, &TwoslashConfig::default());
+        let start = if wrapped.fn_main_offset == 0 || start < wrapped.preamble_len {
+            start
+        } else {
+            start + wrapped.fn_main_offset
+        };
+        let info = StaticQuickInfo { start, length, text: text.into(), docs: None };
+        let result = self
+            .results
+            .entry(code_hash(&wrapped.code))
+            .or_insert_with(|| AnalysisResult { static_quick_infos: vec![] });
+        result.static_quick_infos.push(info);
+        self
+    }
+
+    /// Like [`quick_info`](Self::quick_info) for the first occurrence of `token` in `block`
+    /// that isn't part of a longer name
+    fn hover(self, block: &str, token: &str, text: &str) -> Self {
+        let is_ident = |c: char| c.is_alphanumeric() || c == '_';
+        let (start, _) = block
+            .match_indices(token)
+            .find(|&(i, _)| {
+                !block[..i].ends_with(is_ident) && !block[i + token.len()..].starts_with(is_ident)
+            })
+            .expect("token is in the block");
+        self.quick_info(block, start as u32, token.len() as u32, text)
+    }
+
+    fn build(self) -> MockAnalyzer {
+        MockAnalyzer { results: self.results, analyzed: vec![] }
+    }
+}
+
+/// An [`AnalyzerTrait`] hovering every identifier that isn't a keyword with `name@offset`,
+/// its offset in the code it's given. That's enough for snapshots to show how blocks are
+/// wrapped and how annotations map back, without rust-analyzer.
+struct EchoAnalyzer;
+
+impl AnalyzerTrait for EchoAnalyzer {
+    fn analyze(&mut self, code: &str) -> Result<AnalysisResult, String> {
+        const KEYWORDS: &[&str] = &[
+            "async", "await", "const", "derive", "else", "enum", "false", "fn", "for", "if",
+            "impl", "in", "let", "match", "mod", "move", "mut", "pub", "ref", "return", "self",
+            "static", "struct", "trait", "true", "type", "use", "where",
+        ];
+        let mut infos = vec![];
+        let mut rest = code;
+        while let Some(c) = rest.chars().next() {
+            let offset = code.len() - rest.len();
+            let len = if rest.starts_with("//") {
+                rest.find('\n').unwrap_or(rest.len())
+            } else if rustc_lexer::is_id_start(c) {
+                let len = rest.find(|c| !rustc_lexer::is_id_continue(c)).unwrap_or(rest.len());
+                let name = &rest[..len];
+                if !KEYWORDS.contains(&name) {
+                    let (start, length) = (offset as u32, len as u32);
+                    let text = format!("{name}@{offset}");
+                    infos.push(StaticQuickInfo { start, length, text, docs: None });
+                }
+                len
+            } else {
+                c.len_utf8()
+            };
+            rest = &rest[len..];
+        }
+        Ok(AnalysisResult { static_quick_infos: infos })
+    }
+}
+
+/// Check the annotations of the code blocks in `dir` against their snapshots, or rewrite
+/// the snapshots when RUSTDOC_TWOSLASH_UPDATE_GOLDEN=1.
+///
+/// Each `<name>.rs.input` is a code block, with the JSON of its annotations (as given by
+/// [`EchoAnalyzer`]) in `<name>.rs.annotations` and, optionally, its highlighted HTML in
+/// `<name>.rs.html`.
+fn run_golden_tests(dir: &Path) {
+    let update = std::env::var("RUSTDOC_TWOSLASH_UPDATE_GOLDEN").is_ok_and(|v| v == "1");
+    let mut inputs: Vec<PathBuf> = fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.to_str().is_some_and(|path| path.ends_with(".rs.input")))
+        .collect();
+    inputs.sort();
+    assert!(!inputs.is_empty(), "no snapshots in {}", dir.display());
+
+    let config = TwoslashConfig { no_cache: true, ..Default::default() };
+    let mut outdated = vec![];
+    for input in inputs {
+        let code = fs::read_to_string(&input).unwrap();
+        let annotations = process_code_block_with(&code, None, &config, &mut EchoAnalyzer)
+            .unwrap_or_else(|e| panic!("{}: {e}", input.display()));
+        let json = serde_json::to_string_pretty(&annotations).unwrap() + "\n";
+        let mut snapshots = vec![(input.with_extension("annotations"), json)];
+        let html = input.with_extension("html");
+        if html.exists() {
+            snapshots.push((html, annotate_html(&code, &annotations)));
+        }
+        for (path, actual) in snapshots {
+            if update {
+                fs::write(&path, actual).unwrap();
+            } else if fs::read_to_string(&path).ok().as_deref() != Some(actual.as_str()) {
+                outdated.push(path);
+            }
+        }
+    }
+    assert!(
+        outdated.is_empty(),
+        "snapshots differ, rerun with RUSTDOC_TWOSLASH_UPDATE_GOLDEN=1 to update them: {outdated:?}"
+    );
+}
+
+#[test]
+fn test_process_code_block_with_mock() {
+    // Statements are wrapped in `fn main`, items stay in front of it
+    let code = "fn double(n: i32) -> i32 { n * 2 }\nlet (x, y) = (double(1), 2);";
+    let call_start = code.rfind("double").unwrap() as u32;
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(code, "double", "fn double(n: i32) -> i32")
+        .hover(code, "n", "n: i32")
+        .hover(code, "(x, y)", "let (x, y): (i32, i32)")
+        .quick_info(code, call_start, 6, "fn double(n: i32) -> i32")
+        .build();
+    let config = TwoslashConfig::default();
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let annotations: Vec<_> =
+        annotations.iter().map(|ann| (&code[ann.byte_range()], &*ann.type_text)).collect();
+    assert_eq!(
+        annotations,
+        [
+            ("double", "fn(n: i32) -> i32"),
+            ("x", "let x: i32"),
+            ("y", "let y: i32"),
+            ("double", "fn double(n: i32) -> i32"),
+        ]
+    );
+    assert_eq!(analyzer.analyzed.len(), 1);
+
+    // Blocks it has no answer for fail
+    let error = process_code_block_with("let z = 1;", None, &config, &mut analyzer).unwrap_err();
+    assert!(matches!(error, TwoslashError::AnalysisFailed(_)));
+
+    let attrs = BlockAttrs { no_annotations: true, ..Default::default() };
+    let annotations = process_code_block_with(code, Some(&attrs), &config, &mut analyzer);
+    assert_eq!(annotations, Ok(vec![]));
+}
+
+#[test]
+fn test_golden_snapshots() {
+    run_golden_tests(&Path::new(env!("CARGO_MANIFEST_DIR")).join("html/twoslash/tests/snapshots"));
+}
+
+#[test]
+fn test_find_manifests_package() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_manifest(tmp.path(), "[package]\nname = \"foo\"\n");
+    let src = tmp.path().join("src");
+    fs::create_dir_all(&src).unwrap();
+
+    let location = find_manifests(&DefaultCargoResolver, &src);
+    assert_eq!(location.package, Some(tmp.path().join("Cargo.toml")));
+    assert_eq!(location.workspace, None);
+}
+
+#[test]
+fn test_find_manifests_workspace_member() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_manifest(tmp.path(), "[workspace]\nmembers = [\"crates/*\"]\n");
+    let member = tmp.path().join("crates/foo");
+    write_manifest(&member, "[package]\nname = \"foo\"\nversion.workspace = true\n");
+
+    let location = find_manifests(&DefaultCargoResolver, &member.join("src"));
+    assert_eq!(
+        location,
+        ManifestLocation {
+            package: Some(member.join("Cargo.toml")),
+            workspace: Some(tmp.path().join("Cargo.toml")),
+        }
+    );
+    assert_eq!(location.lock_file(), Some(tmp.path().join("Cargo.lock")));
+}
+
+#[test]
+fn test_find_manifests_virtual_workspace() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_manifest(tmp.path(), "[workspace]\nmembers = [\"foo\"]\n");
+
+    let location = find_manifests(&DefaultCargoResolver, tmp.path());
+    assert_eq!(location.package, None);
+    assert_eq!(location.workspace, Some(tmp.path().join("Cargo.toml")));
+}
+
+#[test]
+fn test_find_manifests_package_is_workspace_root() {
+    let tmp = tempfile::tempdir().unwrap();
+    write_manifest(tmp.path(), "[package]\nname = \"foo\"\n\n[workspace]\n");
+    // An outer workspace must not be picked up past the first root
+    let inner = tmp.path().join("inner");
+    write_manifest(&inner, "[package]\nname = \"bar\"\n\n[workspace.dependencies]\n");
+
+    let location = find_manifests(&DefaultCargoResolver, &inner);
+    assert_eq!(location.package, Some(inner.join("Cargo.toml")));
+    assert_eq!(location.workspace, Some(inner.join("Cargo.toml")));
+}
+
+#[test]
+fn test_locate_manifests_priority() {
+    let mut resolver = MockCargoResolver { current_dir: "/build".into(), ..Default::default() };
+    for dir in ["/build", "/work/foo", "/work/bar"] {
+        resolver
+            .files
+            .insert(Path::new(dir).join("Cargo.toml"), "[package]\nname = \"x\"\n".into());
+    }
+    let package = |resolver: &MockCargoResolver| {
+        locate_manifests(resolver, &TwoslashConfig::from_resolver(resolver)).package
+    };
+    assert_eq!(package(&resolver), Some("/build/Cargo.toml".into()));
+
+    resolver.env.insert("CARGO_MANIFEST_DIR".into(), "/work/foo".into());
+    assert_eq!(package(&resolver), Some("/work/foo/Cargo.toml".into()));
+
+    resolver.env.insert("RUSTDOC_TWOSLASH_CARGO_TOML".into(), "/work/bar/Cargo.toml".into());
+    assert_eq!(package(&resolver), Some("/work/bar/Cargo.toml".into()));
+
+    // A CARGO_MANIFEST_DIR without a manifest falls back to the current directory
+    resolver.env.remove("RUSTDOC_TWOSLASH_CARGO_TOML");
+    resolver.env.insert("CARGO_MANIFEST_DIR".into(), "/gone".into());
+    assert_eq!(package(&resolver), Some("/build/Cargo.toml".into()));
+}
+
+#[test]
+fn test_absolutize_dependency_paths() {
+    let base = Path::new("/ws");
+    let toml = "[lib]\npath = \"src/lib.rs\"\n\n[dependencies]\nbar = { path = \"../bar\" }\nabs = { path = \"/abs\" }\n\n[target.'cfg(unix)'.dependencies]\nnix = { path = \"nix\" }\n";
+    assert_eq!(
+        edit_manifest(toml, |doc| absolutize_dependency_paths(doc, base)),
+        "[lib]\npath = \"src/lib.rs\"\n\n[dependencies]\nbar = { path = \"/ws/../bar\" }\nabs = { path = \"/abs\" }\n\n[target.'cfg(unix)'.dependencies]\nnix = { path = \"/ws/nix\" }\n",
+    );
+}
+
+#[test]
+fn test_inherit_workspace_tables() {
+    let member = "[package]\nname = \"foo\"\nworkspace = \"../..\"\nversion.workspace = true\n\n[dependencies]\nserde.workspace = true\n";
+    let root = "[workspace]\nmembers = [\"crates/*\"]\n\n[workspace.package]\nversion = \"0.1.0\"\n\n[workspace.dependencies]\nserde = \"1\"\nutil = { path = \"crates/util\" }\n";
+    let root = root.parse().unwrap();
+    let result = edit_manifest(member, |doc| {
+        inherit_workspace_tables(doc, &root, Path::new("/ws"));
+    });
+    assert_eq!(
+        result,
+        "[package]\nname = \"foo\"\nversion.workspace = true\n\n[dependencies]\nserde.workspace = true\n\
+         \n[workspace]\n\n[workspace.package]\nversion = \"0.1.0\"\n\n[workspace.dependencies]\nserde = \"1\"\nutil = { path = \"/ws/crates/util\" }\n",
+    );
+}
+
+#[test]
+fn test_parse_query_comments() {
+    let code = "let value = 1;\n//  ^?\nlet x = value;\n//  ^? i32\n// ^? stacked\n";
+    assert_eq!(
+        parse_query_comments(code),
+        vec![
+            QueryComment { line: 2, range: 15..22, target: 4, expected: None },
+            QueryComment { line: 4, range: 37..48, target: 26, expected: Some("i32".into()) },
+            QueryComment { line: 5, range: 48..62, target: 25, expected: Some("stacked".into()) },
+        ]
+    );
+}
+
+#[test]
+fn test_parse_query_comments_ignores_unanchored() {
+    // No line above, caret past the end of the line above, and a non-query comment
+    assert_eq!(parse_query_comments("// ^?\nx;\n//    ^?\n// not ^? a query\n"), vec![]);
+}
+
+#[test]
+fn test_remove_ranges() {
+    let code = "aa\n// ^?\nbb\n// ^?\ncc";
+    let (stripped, offsets) = remove_ranges(code, &[3..9, 12..18]);
+    assert_eq!(stripped, "aa\nbb\ncc");
+    assert_eq!(offsets.to_original(0), 0);
+    assert_eq!(offsets.to_original(3), 9);
+    assert_eq!(offsets.to_original(6), 18);
+    assert_eq!(&code[offsets.to_original(7) as usize..], "c");
+}
+
+#[test]
+fn test_strip_hidden_lines() {
+    let code = "# use std::collections::HashMap;\n#\nlet map: HashMap<u8, u8> = HashMap::new();\n";
+    let (stripped, segments) = strip_hidden_lines(code);
+    assert_eq!(
+        stripped,
+        "use std::collections::HashMap;\n\nlet map: HashMap<u8, u8> = HashMap::new();\n"
+    );
+    assert_eq!(segments, [(0, 2), (31, 3)]);
+    // Indented, escaped with `##`, and lines that are only shown
+    let (stripped, _) =
+        strip_hidden_lines("    # let x = 1;\n##[derive(Debug)]\n#[derive(Clone)]\n#x");
+    assert_eq!(stripped, "let x = 1;\n#[derive(Debug)]\n#[derive(Clone)]\n#x");
+    assert_eq!(strip_hidden_lines("let x = 1;"), ("let x = 1;".to_string(), vec![]));
+
+    // Analyzed without the prefixes, annotated at the hidden lines' code
+    let (stripped, _) = strip_hidden_lines(code);
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(&stripped, "HashMap", "struct HashMap<K, V>")
+        .hover(&stripped, "map", "let map: HashMap<u8, u8>")
+        .build();
+    let config = TwoslashConfig::default();
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let annotations: Vec<_> =
+        annotations.iter().map(|ann| (ann.start, &code[ann.byte_range()])).collect();
+    assert_eq!(annotations, [(24, "HashMap"), (39, "map")]);
+}
+
+#[test]
+fn test_strip_indented_hidden_lines() {
+    // Stripped as `markdown::map_line(line).for_code()` has them in the doctest
+    let code = "fn main() {\n    # let x = 1;\n  #  \n    let y = x;\n}\n";
+    let (stripped, segments) = strip_hidden_lines(code);
+    assert_eq!(stripped, "fn main() {\nlet x = 1;\n\n    let y = x;\n}\n");
+    assert_eq!(segments, [(12, 6), (23, 11)]);
+
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(&stripped, "x", "let x: i32")
+        .hover(&stripped, "y", "let y: i32")
+        .build();
+    let config = TwoslashConfig::default();
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let annotations: Vec<_> =
+        annotations.iter().map(|ann| (ann.start, &code[ann.byte_range()])).collect();
+    assert_eq!(annotations, [(22, "x"), (43, "y")]);
+}
+
+#[test]
+fn test_annotations_for_html() {
+    let code = "# let hidden = 1;\r\n##![allow(unused)]\r\nlet shown = hidden;\r\n";
+    let shown = "#![allow(unused)]\nlet shown = hidden;";
+    let annotation_of = |token: &str, from: usize| {
+        TypeAnnotation::new(
+            (from + code[from..].find(token).unwrap()) as u32,
+            token.len() as u32,
+            "",
+        )
+    };
+    let annotations = vec![
+        annotation_of("hidden", 0),
+        annotation_of("allow", 0),
+        annotation_of("shown", 0),
+        annotation_of("hidden", 20),
+        // Spanning the `\r` of a shown line, and the whole of a hidden one
+        TypeAnnotation::new(36, 5, ""),
+        TypeAnnotation::new(0, 19, ""),
+    ];
+    let annotations = annotations_for_html(code, annotations);
+    let annotations: Vec<_> =
+        annotations.iter().map(|ann| (ann.start, &shown[ann.byte_range()])).collect();
+    assert_eq!(annotations, [(3, "allow"), (22, "shown"), (30, "hidden"), (16, "]\nle")]);
+    let code = "let x = 1;\n";
+    assert_eq!(
+        annotations_for_html(code, vec![TypeAnnotation::new(4, 1, "")]),
+        [TypeAnnotation::new(4, 1, "")]
+    );
+}
+
+#[test]
+fn test_resolve_queries() {
+    let queries = parse_query_comments("let answer = 42;\n//  ^? i32\n");
+    let annotations =
+        vec![TypeAnnotation::new(4, 6, "let answer: i32"), TypeAnnotation::new(0, 3, "let")];
+
+    let resolved = resolve_queries(&queries, annotations.clone()).unwrap();
+    assert_eq!(resolved[0].kind, TokenKind::QueryResult);
+    assert_eq!(resolved[1].kind, TokenKind::Unknown);
+
+    let queries = parse_query_comments("let answer = 42;\n//  ^? u8\n");
+    assert_eq!(
+        resolve_queries(&queries, annotations).unwrap_err(),
+        QueryError::Mismatch { line: 2, expected: "u8".into(), actual: "let answer: i32".into() }
+    );
+    assert_eq!(
+        resolve_queries(&queries, vec![]).unwrap_err(),
+        QueryError::NoAnnotation { line: 2 }
+    );
+}
+
+#[test]
+fn test_mismatched_query_fails_the_block() {
+    let code = "let answer = 42;\n//  ^? u8\n";
+    let mut analyzer =
+        MockAnalyzer::builder().hover("let answer = 42;\n", "answer", "let answer: i32").build();
+    let config = TwoslashConfig::default();
+    assert_eq!(
+        process_code_block_with(code, None, &config, &mut analyzer),
+        Err(TwoslashError::QueryFailed(QueryError::Mismatch {
+            line: 2,
+            expected: "u8".into(),
+            actual: "let answer: i32".into(),
+        }))
+    );
+}
+
+#[test]
+fn test_parse_block_attrs() {
+    assert_eq!(parse_block_attrs("rust"), BlockAttrs::default());
+    assert_eq!(
+        parse_block_attrs("rust,twoslash,no-annotations"),
+        BlockAttrs { enabled: Some(true), no_annotations: true, ..Default::default() }
+    );
+    assert_eq!(
+        parse_block_attrs("rust, twoslash edition=2018"),
+        BlockAttrs {
+            enabled: Some(true),
+            no_annotations: false,
+            edition: Some("2018".into()),
+            files: false,
+            dedent: false,
+            ignore: false,
+            no_run: false,
+        }
+    );
+    assert_eq!(parse_block_attrs("rust,edition2021").edition.as_deref(), Some("2021"));
+    assert_eq!(parse_block_attrs("rust,edition=1999").edition, None);
+    assert_eq!(parse_block_attrs("rust,no-twoslash").enabled, Some(false));
+    assert!(parse_block_attrs("rust,twoslash,files").files);
+    assert!(parse_block_attrs("rust,twoslash,dedent").dedent);
+
+    // `ignore` wins over an explicit `twoslash`, `no_run` and `ignore-<target>` change nothing
+    let attrs = parse_block_attrs("rust,ignore,twoslash");
+    assert!(attrs.ignore && !attrs.enabled());
+    let attrs = parse_block_attrs("rust,no_run,twoslash");
+    assert!(attrs.no_run && attrs.enabled());
+    let attrs = parse_block_attrs("rust,ignore-wasm32,twoslash");
+    assert!(!attrs.ignore && attrs.enabled());
+}
+
+#[test]
+fn test_with_edition() {
+    assert_eq!(
+        edit_manifest("[package]\nname = \"foo\"\nedition = \"2021\"\n\n[dependencies]\n", |doc| {
+            with_edition(doc, "2018")
+        }),
+        "[package]\nname = \"foo\"\nedition = \"2018\"\n\n[dependencies]\n",
+    );
+    assert_eq!(
+        edit_manifest("[package]\nedition.workspace = true\n", |doc| with_edition(doc, "2015")),
+        "[package]\nedition = \"2015\"\n",
+    );
+}
+
+#[test]
+fn test_annotation_cache_round_trip() {
+    let mut annotations = vec![
+        TypeAnnotation::new(0, 3, "let"),
+        TypeAnnotation::new(4, 5, "let café: &str\n\n---\n\n\"quoted\" \\ docs"),
+        TypeAnnotation::new(u32::MAX - 1, 1, ""),
+    ];
+    annotations[1].docs = Some("emoji 🦀 and\ttabs\n".into());
+    annotations[2].kind = TokenKind::QueryResult;
+
+    let json = TypeAnnotationCache::new(annotations.clone()).to_json();
+    let decoded = TypeAnnotationCache::from_json(&json).unwrap();
+    assert_eq!(decoded.to_json(), json);
+    for (decoded, original) in decoded.annotations.iter().zip(&annotations) {
+        assert_eq!((decoded.start, decoded.length), (original.start, original.length));
+        assert_eq!(decoded.type_text, original.type_text);
+        assert_eq!(decoded.docs, original.docs);
+        assert_eq!(decoded.kind, original.kind);
+    }
+}
+
+#[test]
+fn test_annotation_cache_rejects_other_versions() {
+    let json = r#"{"version":0,"annotations":[]}"#;
+    assert!(matches!(
+        TypeAnnotationCache::from_json(json),
+        Err(CacheFormatError::UnsupportedVersion(0))
+    ));
+    assert!(matches!(TypeAnnotationCache::from_json("[]"), Err(CacheFormatError::Json(_))));
+}
+
+#[test]
+fn test_manifest_edition() {
+    let package = "[package]\nname = \"foo\"\nedition = \"2021\"\n".parse().unwrap();
+    assert_eq!(manifest_edition(&package, None), "2021");
+    assert_eq!(manifest_edition(&"[package]\nname = \"foo\"\n".parse().unwrap(), None), "2015");
+
+    let member = "[package]\nname = \"foo\"\nedition.workspace = true\n".parse().unwrap();
+    let workspace = "[workspace]\nmembers = [\"foo\"]\n\n[workspace.package]\nedition = \"2024\"\n"
+        .parse()
+        .unwrap();
+    assert_eq!(manifest_edition(&member, Some(&workspace)), "2024");
+    assert_eq!(manifest_edition(&member, None), "2015");
+
+    // Keys of other tables don't count
+    let dependency =
+        "[package]\nname = \"foo\"\n\n[dependencies.bar]\nedition = \"2018\"\n".parse().unwrap();
+    assert_eq!(manifest_edition(&dependency, None), "2015");
+}
+
+#[test]
+fn test_token_kind_from_signature() {
+    let cases = [
+        ("let x: i32", TokenKind::Variable),
+        ("let mut v: Vec<u8>", TokenKind::Variable),
+        ("x: &str", TokenKind::Variable),
+        ("pub fn helper() -> i32", TokenKind::Function),
+        ("pub const unsafe fn new() -> Self", TokenKind::Function),
+        ("core::str\npub const fn len(&self) -> usize", TokenKind::Method),
+        ("alloc::vec::Vec\npub fn push(&mut self, value: T)", TokenKind::Method),
+        ("fn by_value(self) -> u8", TokenKind::Method),
+        ("fn selfish(selfie: u8)", TokenKind::Function),
+        ("pub(crate) struct Foo {\n    a: i32,\n}", TokenKind::Type),
+        ("pub enum Option<T>\n\n---\n\nlet docs: here", TokenKind::Type),
+        ("pub trait Iterator", TokenKind::Type),
+        ("pub unsafe trait Send", TokenKind::Type),
+        ("type Alias = u8", TokenKind::Type),
+        ("crate::Foo\npub a: i32", TokenKind::Field),
+        ("macro_rules! vec", TokenKind::Macro),
+        ("pub const MAX: u32 = 4294967295", TokenKind::Constant),
+        ("static COUNTER: AtomicUsize", TokenKind::Constant),
+        ("mod io", TokenKind::Module),
+        ("extern crate std", TokenKind::Module),
+        ("'a", TokenKind::Lifetime),
+        ("i32", TokenKind::Unknown),
+        ("core::option::Option", TokenKind::Unknown),
+    ];
+    for (signature, kind) in cases {
+        assert_eq!(TokenKind::from_analyzer_kind(signature), kind, "{signature:?}");
+    }
+
+    assert_eq!(TokenKind::from_opt_kind(None), TokenKind::Unknown);
+    assert_eq!(TokenKind::from_opt_kind(Some(" \n")), TokenKind::Unknown);
+    assert_eq!(TokenKind::from_opt_kind(Some("let x: i32")), TokenKind::Variable);
+    assert!(TokenKind::Variable.is_known() && !TokenKind::Unknown.is_known());
+    assert_eq!(TokenKind::Unknown.css_name(), None);
+    // Cached before the rename
+    assert_eq!(serde_json::from_str::<TokenKind>("\"Hover\"").unwrap(), TokenKind::Unknown);
+    assert_eq!(serde_json::to_string(&TokenKind::Unknown).unwrap(), "\"Unknown\"");
+}
+
+#[test]
+fn test_errors_directive() {
+    let code = "let x: i32 = \"no\";\n// @errors 1\n";
+    let directive = parse_errors_directive(code).unwrap();
+    assert_eq!(directive, ErrorsDirective { line: 2, expected: Some(1) });
+    assert_eq!(
+        parse_errors_directive("// @errors\nfoo();"),
+        Some(ErrorsDirective { line: 1, expected: None })
+    );
+    assert_eq!(parse_errors_directive("// @errorsome\n// @errors many\n"), None);
+
+    let mut errors = vec![
+        TypeAnnotation::new(0, 5, "mismatched types"),
+        TypeAnnotation::new(6, 3, "let x: i32"),
+    ];
+    errors[0].kind = TokenKind::CompileError;
+    assert_eq!(check_errors(&directive, &errors), Ok(()));
+    assert_eq!(check_errors(&ErrorsDirective { line: 1, expected: None }, &[]), Ok(()));
+    assert_eq!(
+        check_errors(&ErrorsDirective { line: 1, expected: Some(2) }, &errors),
+        Err(DiagnosticError::WrongErrorCount { expected: 2, actual: 1 })
+    );
+}
+
+#[test]
+fn test_self_dependency_features() {
+    let resolver = MockCargoResolver::default();
+    assert_eq!(parse_features(" serde,, async ,"), ["serde", "async"]);
+
+    let manifest = "[package]\nname = \"my-crate\"\n\n[dependencies]\n";
+    let (injected, added) =
+        inject_self_dependency(&resolver, manifest, "/src/my-crate", &["serde", "async"]);
+    assert_eq!(added, InjectionResult::Injected);
+    assert!(injected.contains(
+        "my_crate = { path = \"/src/my-crate\", package = \"my-crate\", features = [\"serde\", \"async\"] }"
+    ));
+    let (injected, _) = inject_self_dependency(&resolver, manifest, "/src/my-crate", &[]);
+    assert!(injected.contains("my_crate = { path = \"/src/my-crate\", package = \"my-crate\" }"));
+}
+
+#[test]
+fn test_self_dependency_structured() {
+    let resolver = MockCargoResolver::default();
+    let parse = |toml: &str| toml.parse::<toml_edit::DocumentMut>().unwrap();
+
+    // `[dependencies]` in a comment, a metadata table before the real one
+    let manifest = "# see [dependencies]\n[package]\nname = \"my-crate\"\n\n\
+                    [package.metadata.docs]\nall = true\n\n[dependencies]\nserde = \"1\"\n";
+    let (injected, added) = inject_self_dependency(&resolver, manifest, "/src/my-crate", &[]);
+    assert_eq!(added, InjectionResult::Injected);
+    let doc = parse(&injected);
+    assert_eq!(doc["package"]["name"].as_str(), Some("twoslash-rustdoc-tmp"));
+    assert_eq!(doc["package"]["metadata"]["docs"]["all"].as_bool(), Some(true));
+    assert_eq!(doc["dependencies"]["serde"].as_str(), Some("1"));
+    assert_eq!(doc["dependencies"]["my_crate"]["path"].as_str(), Some("/src/my-crate"));
+
+    // Inline `dependencies` table and a Windows path
+    let manifest = "dependencies = { serde = \"1\" }\n\n[package]\nname = \"my-crate\"\n";
+    let (injected, added) =
+        inject_self_dependency(&resolver, manifest, r"C:\src\my-crate", &["serde"]);
+    assert_eq!(added, InjectionResult::Injected);
+    let doc = parse(&injected);
+    assert_eq!(doc["dependencies"]["my_crate"]["path"].as_str(), Some(r"C:\src\my-crate"));
+    assert_eq!(doc["dependencies"]["my_crate"]["features"][0].as_str(), Some("serde"));
+
+    // No `[package]` to take the crate name from
+    let (unchanged, added) = inject_self_dependency(&resolver, "[workspace]\n", "/src", &[]);
+    assert_eq!(added, InjectionResult::SkippedVirtualManifest);
+    assert_eq!(unchanged, "[workspace]\n");
+}
+
+#[test]
+fn test_self_dependency_idempotent() {
+    let resolver = MockCargoResolver::default();
+    let manifest = "[package]\nname = \"my-crate\"\n\n[dependencies]\n";
+    let (once, added) = inject_self_dependency(&resolver, manifest, "/src/my-crate", &[]);
+    assert_eq!(added, InjectionResult::Injected);
+    let (twice, added) = inject_self_dependency(&resolver, &once, "/src/my-crate", &[]);
+    assert_eq!(added, InjectionResult::NotInjected);
+    assert_eq!(twice, once);
+    assert_eq!(twice.matches("my_crate =").count(), 1);
+
+    let manifest =
+        "[package]\nname = \"my-crate\"\n\n[dependencies]\nmy-crate = { path = \".\" }\n";
+    let (kept, added) = inject_self_dependency(&resolver, manifest, "/src/my-crate", &[]);
+    assert_eq!(added, InjectionResult::NotInjected);
+    assert!(kept.contains("my-crate = { path = \".\" }"));
+    assert!(!kept.contains("my_crate ="));
+
+    // Under another name, as absolutized by `resolve_cargo_toml` or as written
+    for path in ["/src/my-crate/.", "/src/other/../my-crate/", "../my-crate"] {
+        let manifest = format!(
+            "[package]\nname = \"my-crate\"\n\n[dependencies]\nme = {{ path = \"{path}\" }}\n"
+        );
+        let (kept, added) = inject_self_dependency(&resolver, &manifest, "/src/my-crate", &[]);
+        assert_eq!(added, InjectionResult::NotInjected, "{path}");
+        assert!(!kept.contains("my_crate ="));
+    }
+    let manifest = "[package]\nname = \"my-crate\"\n\n[dependencies]\n\
+                    sibling = { path = \"../sibling\" }\n";
+    let (_, added) = inject_self_dependency(&resolver, manifest, "/src/my-crate", &[]);
+    assert_eq!(added, InjectionResult::Injected);
+}
+
+#[test]
+fn test_self_dependency_virtual_manifest() {
+    let mut resolver =
+        MockCargoResolver { current_dir: "/ws/crates/my-crate/src".into(), ..Default::default() };
+    resolver
+        .files
+        .insert("/ws/crates/my-crate/Cargo.toml".into(), "[package]\nname = \"my-crate\"\n".into());
+    let manifest = "[workspace]\nmembers = [\"tools\", \"crates/*\"]\n\n\
+                    [workspace.dependencies]\nserde = \"1\"\n";
+    let (injected, added) = inject_self_dependency(&resolver, manifest, "/ws", &["serde"]);
+    assert_eq!(added, InjectionResult::Injected);
+    let doc = injected.parse::<toml_edit::DocumentMut>().unwrap();
+    assert_eq!(doc["package"]["name"].as_str(), Some("twoslash-rustdoc-tmp"));
+    assert!(doc["workspace"].get("members").is_none());
+    assert_eq!(doc["workspace"]["dependencies"]["serde"].as_str(), Some("1"));
+    let dep = &doc["dependencies"]["my_crate"];
+    assert_eq!(dep["path"].as_str(), Some("/ws/crates/my-crate"));
+    assert_eq!(dep["package"].as_str(), Some("my-crate"));
+    assert_eq!(dep["features"][0].as_str(), Some("serde"));
+
+    // Outside of every member, like rustdoc run from the workspace root
+    resolver.current_dir = "/ws".into();
+    let (unchanged, added) = inject_self_dependency(&resolver, manifest, "/ws", &[]);
+    assert_eq!(added, InjectionResult::SkippedVirtualManifest);
+    assert_eq!(unchanged, manifest);
+
+    assert!(member_matches("crates/*", Path::new("crates/my-crate")));
+    assert!(member_matches("./tools/", Path::new("tools")));
+    assert!(member_matches("crates/my-*", Path::new("crates/my-crate")));
+    assert!(!member_matches("crates/*", Path::new("crates")));
+    assert!(!member_matches("crates/other-*", Path::new("crates/my-crate")));
+}
+
+#[test]
+fn test_pool_reuses_and_blocks() {
+    let pool = Pool::new(2, |slot| Ok(slot * 10));
+    {
+        let a = pool.acquire().unwrap();
+        let b = pool.acquire().unwrap();
+        assert_eq!((*a, *b), (0, 10));
+    }
+    // Returned instances are handed out again instead of creating new ones
+    let a = pool.acquire().unwrap();
+    let b = pool.acquire().unwrap();
+    assert!(*a == 0 || *a == 10);
+    assert_eq!(*a + *b, 10);
+
+    std::thread::scope(|scope| {
+        let waiter = scope.spawn(|| *pool.acquire().unwrap());
+        drop(a);
+        assert!(waiter.join().unwrap() < 20);
+    });
+    drop(b);
+    assert_eq!(pool.state.lock().unwrap().created, 2);
+}
+
+#[test]
+fn test_pool_reset() {
+    static CREATED: AtomicUsize = AtomicUsize::new(0);
+    let pool = Pool::new(2, |slot| Ok((slot, CREATED.fetch_add(1, Ordering::Relaxed))));
+    let a = pool.acquire().unwrap();
+    drop(pool.acquire().unwrap());
+    pool.reset();
+    // The idle instance is replaced, and so is the acquired one once it's returned
+    assert_eq!(pool.acquire().unwrap().1, 2);
+    drop(a);
+    let (a, b) = (pool.acquire().unwrap(), pool.acquire().unwrap());
+    let mut slots = [a.0, b.0];
+    slots.sort();
+    assert_eq!(slots, [0, 1]);
+    assert!(a.1 >= 2 && b.1 >= 2 && a.1 != b.1);
+    assert_eq!(pool.state.lock().unwrap().created, 2);
+}
+
+#[test]
+fn test_pool_create_error() {
+    static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+    let pool = Pool::new(1, |slot| match ATTEMPTS.fetch_add(1, Ordering::Relaxed) {
+        0 => Err(TwoslashError::InvalidSettings(ConfigError::InvalidEdition("2019".into()))),
+        _ => Ok(slot),
+    });
+    assert_eq!(
+        pool.acquire().err(),
+        Some(TwoslashError::InvalidSettings(ConfigError::InvalidEdition("2019".into())))
+    );
+    // The slot is free again, rather than waited on forever
+    assert_eq!(*pool.acquire().unwrap(), 0);
+}
+
+#[test]
+fn test_run_with_timeout() {
+    assert_eq!(run_with_timeout(None, || Ok(1)), Ok(1));
+    assert_eq!(run_with_timeout(Some(Duration::from_secs(60)), || Ok(2)), Ok(2));
+    let failing = || Err::<(), _>(TwoslashError::AnalysisFailed("no cargo".into()));
+    assert_eq!(run_with_timeout(Some(Duration::from_secs(60)), failing), failing());
+    let slow = || {
+        std::thread::sleep(Duration::from_secs(1));
+        Ok(())
+    };
+    let timeout = Duration::from_millis(10);
+    assert_eq!(run_with_timeout(Some(timeout), slow), Err(TwoslashError::Timeout(timeout)));
+    assert_eq!(
+        run_with_timeout(Some(Duration::from_secs(60)), || -> Result<(), _> {
+            panic!("analyzer crashed")
+        }),
+        Err(TwoslashError::Panicked)
+    );
+
+    // On the long-lived workers, which outlive a panicking job
+    let worker = || Ok(std::thread::current().name().map(String::from));
+    let name = run_with_timeout(Some(Duration::from_secs(60)), worker).unwrap();
+    assert!(name.is_some_and(|name| name.starts_with("twoslash-worker-")));
+    // Not started once past the deadline
+    let started = Arc::new(AtomicUsize::new(0));
+    let counter = Arc::clone(&started);
+    let count = move || Ok(counter.fetch_add(1, Ordering::Relaxed));
+    assert_eq!(
+        run_with_timeout(Some(Duration::ZERO), count),
+        Err(TwoslashError::Timeout(Duration::ZERO))
+    );
+    assert_eq!(run_with_timeout(Some(Duration::from_secs(60)), || Ok(())), Ok(()));
+    assert_eq!(started.load(Ordering::Relaxed), 0);
+}
+
+#[test]
+fn test_partial_on_timeout() {
+    let timeout = Duration::from_secs(10);
+    let config = TwoslashConfig::default();
+    let timed_out = unannotated_if_cut_short(Err(TwoslashError::Timeout(timeout)), &config);
+    assert_eq!(timed_out, Err(TwoslashError::Timeout(timeout)));
+    let config = TwoslashConfig { partial_on_timeout: true, ..config };
+    let timed_out = unannotated_if_cut_short(Err(TwoslashError::Timeout(timeout)), &config);
+    assert_eq!(timed_out, Ok(vec![]));
+    let failed = Err(TwoslashError::AnalysisFailed("no cargo".into()));
+    assert_eq!(unannotated_if_cut_short(failed.clone(), &config), failed);
+    assert_eq!(unannotated_if_cut_short(Err(TwoslashError::Panicked), &config), Ok(vec![]));
+}
+
+#[test]
+fn test_analyzer_panic() {
+    struct PanickingAnalyzer;
+    impl AnalyzerTrait for PanickingAnalyzer {
+        fn analyze(&mut self, _code: &str) -> Result<AnalysisResult, String> {
+            panic!("analyzer crashed")
+        }
+    }
+
+    let code = "let malformed = ;";
+    let config = TwoslashConfig::default();
+    let annotations = process_code_block_with(code, None, &config, &mut PanickingAnalyzer);
+    assert_eq!(annotations, Ok(vec![]));
+
+    // Not cached, so the next run analyzes the block again
+    let code = format!("let malformed = ; // {:?}", SystemTime::now());
+    for _ in 1..=2 {
+        let analyzed = cached(&code, None, CacheKind::Annotations, &config, || {
+            annotate(&mut PanickingAnalyzer, &code, &config)
+        });
+        assert_eq!(analyzed, Err(TwoslashError::Panicked));
+        assert_eq!(load_cached(&code, None, CacheKind::Annotations, &config), None);
+    }
+}
+
+#[test]
+fn test_cache_key() {
+    let tmp = tempfile::tempdir().unwrap();
+    let config = TwoslashConfig { cache_dir: tmp.path().join("cache"), ..Default::default() };
+    let code = "let x = 1;";
+    let annotations = vec![TypeAnnotation::new(4, 1, "let x: i32")];
+    let analyzed = cached(code, None, CacheKind::Annotations, &config, || Ok(annotations.clone()));
+    assert_eq!(analyzed.as_ref(), Ok(&annotations));
+    assert_eq!(fs::read_dir(&config.cache_dir).unwrap().count(), 1);
+    assert_eq!(
+        load_cached(code, None, CacheKind::Annotations, &config).as_ref(),
+        Some(&annotations)
+    );
+    assert_eq!(load_cached(code, Some("[package]"), CacheKind::Annotations, &config), None);
+    assert_eq!(load_cached(code, None, CacheKind::Diagnostics, &config), None);
+
+    // Settings the analysis depends on are part of the key, the others aren't
+    let changed = [
+        TwoslashConfig { no_std: true, ..config.clone() },
+        TwoslashConfig { async_runtime: AsyncRuntime::AsyncStd, ..config.clone() },
+        TwoslashConfig { resolve_urls: true, ..config.clone() },
+        TwoslashConfig { annotate_single_char_bindings: false, ..config.clone() },
+    ];
+    for changed in &changed {
+        assert_eq!(load_cached(code, None, CacheKind::Annotations, changed), None, "{changed:?}");
+    }
+    let unchanged = TwoslashConfig { show_iterator_steps: true, ..config.clone() };
+    assert_eq!(
+        load_cached(code, None, CacheKind::Annotations, &unchanged).as_ref(),
+        Some(&annotations)
+    );
+}
+
+#[test]
+fn test_inject_cfg_flag() {
+    let code = concat!(
+        "#[cfg(twoslash)]\n",
+        "fn shown() {}\n",
+        "#[cfg(all(unix, not(twoslash)))]\n",
+        "fn hidden() {}\n",
+        "#[cfg_attr(twoslash, doc = \"twoslash\")]\n",
+        "let twoslash = cfg!(twoslash) || cfg!(feature = \"twoslash\");\n",
+    );
+    let mut injected = code.to_string();
+    inject_cfg_flag(&mut injected);
+    assert_eq!(
+        injected,
+        concat!(
+            "#[cfg(all()   )]\n",
+            "fn shown() {}\n",
+            "#[cfg(all(unix, not(all()   )))]\n",
+            "fn hidden() {}\n",
+            "#[cfg_attr(all()   , doc = \"twoslash\")]\n",
+            "let twoslash = cfg!(all()   ) || cfg!(feature = \"twoslash\");\n",
+        )
+    );
+}
+
+#[test]
+fn test_macro_call_len() {
+    let code = "let v = vec![1, 2, 3];\nprintln!(\"{v:?}\");\nassert!(a != b);";
+    assert_eq!(macro_call_len(code, code.find("vec").unwrap()), Some("vec!".len()));
+    assert_eq!(macro_call_len(code, code.find("println").unwrap()), Some("println!".len()));
+    assert_eq!(macro_call_len(code, code.find("v =").unwrap()), None);
+    assert_eq!(macro_call_len(code, code.find("a !=").unwrap()), None);
+    assert_eq!(macro_call_len("m!()", 0), Some(2));
+    assert_eq!(macro_call_len(code, code.len()), None);
+}
+
+#[test]
+fn test_declared_features() {
+    let manifest = "[package]\nname = \"foo\"\n\n[features]\ndefault = [\"std\"]\nstd = []\n\"serde-json\" = [\"dep:serde_json\"]\n# old = []\n\n[dependencies]\nstd = \"1\"\n";
+    assert_eq!(declared_features(&manifest.parse().unwrap()), ["default", "std", "serde-json"]);
+    assert_eq!(cargo_feature_var("serde-json"), "CARGO_FEATURE_SERDE_JSON");
+}
+
+#[test]
+fn test_ignore_directives() {
+    let code = "let a = 1;\n// twoslash-ignore-next\n// unrelated comment\nlet huge = make();\nlet b = a; // twoslash-ignore-line\nlet c = b;\n";
+    let at =
+        |token: &str| TypeAnnotation::new(code.find(token).unwrap() as u32, token.len() as u32, "");
+    let annotations = vec![at("a ="), at("huge"), at("make"), at("b ="), at("c =")];
+    let kept = apply_ignore_directives(code, annotations);
+    let kept: Vec<_> = kept.iter().map(|ann| &code[ann.start as usize..][..1]).collect();
+    assert_eq!(kept, ["a", "c"]);
+
+    // The whole `ignore-next` line and only the comment of `ignore-line` are stripped
+    let stripped: Vec<_> = ignore_directives(code).into_iter().map(|d| &code[d.range]).collect();
+    assert_eq!(stripped, ["// twoslash-ignore-next\n", "// twoslash-ignore-line"]);
+}
+
+#[test]
+fn test_dedup_annotations() {
+    let mut binding = TypeAnnotation::new(4, 3, "let foo: Foo");
+    binding.kind = TokenKind::Variable;
+    let mut ty = TypeAnnotation::new(4, 3, "struct Foo");
+    ty.docs = Some("A foo.".into());
+    let annotations = vec![
+        TypeAnnotation::new(4, 3, "let foo: Foo"),
+        TypeAnnotation::new(0, 3, "let"),
+        binding,
+        ty,
+        TypeAnnotation::new(4, 2, "fo"),
+    ];
+    let deduped = dedup_annotations(annotations);
+    let summary: Vec<_> =
+        deduped.iter().map(|ann| (ann.start, ann.length, ann.type_text.as_str())).collect();
+    assert_eq!(summary, [(4, 3, "let foo: Foo\nstruct Foo"), (0, 3, "let"), (4, 2, "fo")]);
+    assert_eq!(deduped[0].docs.as_deref(), Some("A foo."));
+    assert_eq!(deduped[0].kind, TokenKind::Variable);
+}
+
+#[test]
+fn test_annotation_priority() {
+    assert!(TokenKind::QueryResult.default_priority() < TokenKind::CompileError.default_priority());
+    assert!(TokenKind::Variable.default_priority() < TokenKind::Type.default_priority());
+    assert!(TokenKind::Type.default_priority() < TokenKind::Macro.default_priority());
+    assert_eq!(TokenKind::Unknown.default_priority(), 90);
+
+    let code = "let v = vec![1];";
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(code, "v", "let v: Vec<i32>")
+        .hover(code, "vec", "macro_rules! vec")
+        .build();
+    let config = TwoslashConfig {
+        no_cache: true,
+        annotate_single_char_bindings: true,
+        ..Default::default()
+    };
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let priorities: Vec<_> = annotations.iter().map(|ann| ann.priority).collect();
+    assert_eq!(priorities, [10, 30]);
+}
+
+#[test]
+fn test_classify_token() {
+    let code = "let r#type = 'a'; x += 1_000u32 as f64 * 2.5; v::<T>; b\"\\\"\"; r#\"s\"# 'l ==";
+    let class = |token: &str| {
+        let start = code.find(token).unwrap();
+        classify_token(code, start, token.len())
+    };
+    for (token, expected) in [
+        ("let", TokenClass::Keyword),
+        ("as", TokenClass::Keyword),
+        ("r#type", TokenClass::Identifier),
+        ("x", TokenClass::Identifier),
+        ("'l", TokenClass::Identifier),
+        ("'a'", TokenClass::Literal),
+        ("1_000u32", TokenClass::Literal),
+        ("2.5", TokenClass::Literal),
+        ("b\"\\\"\"", TokenClass::Literal),
+        ("r#\"s\"#", TokenClass::Literal),
+        ("+=", TokenClass::Operator),
+        ("*", TokenClass::Operator),
+        ("==", TokenClass::Operator),
+        ("::", TokenClass::Punctuation),
+        (";", TokenClass::Punctuation),
+        ("<", TokenClass::Operator),
+        ("x +=", TokenClass::Unknown),
+        (" ", TokenClass::Unknown),
+    ] {
+        assert_eq!(class(token), expected, "{token:?}");
+    }
+    assert_eq!(classify_token("self.0", 0, 4), TokenClass::Identifier);
+    assert_eq!(classify_token("true", 0, 4), TokenClass::Literal);
+    assert_eq!(classify_token("ab", 1, 5), TokenClass::Unknown);
+
+    // Stray operator hovers are dropped, and the tokens kept carry their class
+    let code = "let total = 1 + 2;";
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(code, "total", "let total: i32")
+        .hover(code, "+", "impl Add for i32")
+        .hover(code, "let", "let")
+        .quick_info(code, 12, 1, "i32")
+        .build();
+    let config = TwoslashConfig { no_cache: true, ..Default::default() };
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let classes: Vec<_> =
+        annotations.iter().map(|ann| (ann.source_text(code).unwrap(), ann.token_class)).collect();
+    assert_eq!(classes, [("total", TokenClass::Identifier), ("1", TokenClass::Literal)]);
+}
+
+#[test]
+fn test_doc_url() {
+    assert_eq!(
+        doc_url("std::collections\npub struct HashMap<K, V, S = RandomState>").as_deref(),
+        Some("https://doc.rust-lang.org/stable/std/collections/struct.HashMap.html")
+    );
+    assert_eq!(
+        doc_url("core::option\n\npub enum Option<T>\n\n---\n\nThe `Option` type.").as_deref(),
+        Some("https://doc.rust-lang.org/stable/core/option/enum.Option.html")
+    );
+    assert_eq!(
+        doc_url("serde_json\npub fn to_string<T>(value: &T) -> Result<String>").as_deref(),
+        Some("https://docs.rs/serde_json/latest/serde_json/fn.to_string.html")
+    );
+    assert_eq!(
+        doc_url("std\npub mod collections").as_deref(),
+        Some("https://doc.rust-lang.org/stable/std/collections/index.html")
+    );
+    assert_eq!(
+        doc_url("let map: std::collections::HashMap<String, i32>").as_deref(),
+        Some("https://doc.rust-lang.org/stable/std/index.html?search=std::collections::HashMap")
+    );
+    assert_eq!(
+        doc_url("let v: my-crate::Thing").as_deref(),
+        Some("https://docs.rs/my_crate/latest/my_crate/index.html?search=my_crate::Thing")
+    );
+    assert_eq!(doc_url("let x: i32"), None);
+}
+
+#[test]
+fn test_parse_diagnostics() {
+    let code = "let x: i32 = \"a\";\nlet y = 1";
+    let wrapped = WrappedCode::new(code, &TwoslashConfig::default());
+    let message = |level: &str, text: &str, spans: &str, children: &str| {
+        format!(
+            r#"{{"reason":"compiler-message","message":{{"level":"{level}","message":"{text}","spans":[{spans}],"children":[{children}]}}}}"#
+        )
+    };
+    let span = |start: u32, end: u32| {
+        format!(
+            r#"{{"file_name":"src/main.rs","byte_start":{start},"byte_end":{end},"is_primary":true}}"#
+        )
+    };
+    let messages = [
+        message(
+            "error",
+            "mismatched types",
+            &span(25, 28),
+            r#"{"level":"note","message":"expected `i32`, found `&str`"}"#,
+        ),
+        message("warning", "unused variable: `y`", &span(34, 35), ""),
+        message("error", "expected `;`", &span(39, 39), ""),
+        message("error", "in the wrapper", &span(3, 7), ""),
+        message("error", "aborting due to 2 previous errors", "", ""),
+        r#"{"reason":"build-finished","success":false}"#.to_string(),
+        "not json".to_string(),
+    ]
+    .join("\n");
+
+    let diagnostics = parse_diagnostics(&messages, code, &wrapped);
+    let summary: Vec<_> = diagnostics
+        .iter()
+        .map(|ann| (ann.start, ann.length, ann.kind, ann.type_text.as_str(), ann.docs.as_deref()))
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (
+                13,
+                3,
+                TokenKind::CompileError,
+                "mismatched types",
+                Some("note: expected `i32`, found `&str`")
+            ),
+            (22, 1, TokenKind::CompileWarning, "unused variable: `y`", None),
+            (26, 1, TokenKind::CompileError, "expected `;`", None),
+        ]
+    );
+}
+
+#[test]
+fn test_split_files() {
+    let code = "// @filename: utils.rs\npub fn helper() {}\n// @filename: main.rs\nmod utils;\n";
+    let (block, offsets) = split_files(code);
+    assert_eq!(
+        block,
+        MultiFileBlock {
+            files: vec![
+                ("utils.rs".into(), "pub fn helper() {}\n".into()),
+                ("main.rs".into(), "mod utils;\n".into()),
+            ],
+            entry: "main.rs".into(),
+        }
+    );
+    // `mod` in the joined contents
+    assert_eq!(offsets.to_original(19), 64);
+
+    let (block, _) = split_files("use a::X;\n// @filename: a.rs\npub struct X;\n");
+    let names: Vec<_> = block.files.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, ["main.rs", "a.rs"]);
+    assert_eq!(block.entry, "main.rs");
+
+    let (block, _) = split_files("// @filename: a.rs\n// @filename: b.rs\n");
+    assert_eq!(block.entry, "b.rs");
+    assert_eq!(module_name("net/mod.rs"), "net");
+    assert_eq!(module_name("my-utils.rs"), "my_utils");
+}
+
+#[test]
+fn test_combined_files() {
+    let block = MultiFileBlock {
+        files: vec![
+            ("a.rs".into(), "pub struct A;".into()),
+            ("b.rs".into(), "pub struct B;".into()),
+            ("main.rs".into(), "mod b;\nuse a::A;".into()),
+        ],
+        entry: "main.rs".into(),
+    };
+    let combined = CombinedFiles::new(&block);
+    assert_eq!(combined.code, "mod a {\npub struct A;\n}\nmod b {\npub struct B;\n}\nuse a::A;");
+    let offset_of = |needle: &str| combined.code.find(needle).unwrap() as u32;
+    assert_eq!(combined.to_files(offset_of("A;")), Some(11));
+    assert_eq!(combined.to_files(offset_of("B;")), Some(24));
+    assert_eq!(combined.to_files(offset_of("mod b")), Some(26));
+    assert_eq!(combined.to_files(offset_of("use")), Some(33));
+    assert_eq!(combined.to_files(0), None);
+}
+
+#[test]
+fn test_with_files() {
+    let code = "// @filename: utils.rs\npub fn helper() {}\n\
+                // @filename: main.rs\nmod utils;\nfn main() { utils::helper() }\n";
+    let annotations = with_files(code, |combined| {
+        // The braces inlining utils.rs aren't in the block
+        let inserted = combined.find(" {\npub").unwrap() as u32;
+        let start = combined.rfind("helper").unwrap() as u32;
+        Ok(vec![
+            TypeAnnotation::new(inserted, 2, "{"),
+            TypeAnnotation::new(start, 6, "pub fn helper()"),
+        ])
+    })
+    .unwrap();
+    let starts: Vec<_> = annotations.iter().map(|ann| ann.start).collect();
+    assert_eq!(starts, [code.rfind("helper").unwrap() as u32]);
+}
+
+#[test]
+fn test_analyzer_settings_builder() {
+    let settings = AnalyzerSettingsBuilder::default()
+        .cargo_toml(Some("[package]\nname = \"foo\"\n".into()))
+        .target_dir(Some("/tmp/target".into()))
+        .edition(Some("2018".into()))
+        .build()
+        .unwrap();
+    assert_eq!(
+        settings.cargo_toml.as_deref(),
+        Some("[package]\nname = \"foo\"\nedition = \"2018\"\n")
+    );
+    assert_eq!(settings.target_dir, Some("/tmp/target".into()));
+
+    let settings = AnalyzerSettingsBuilder::default().build().unwrap();
+    assert_eq!(settings.cargo_toml, None);
+    let settings = AnalyzerSettingsBuilder::default().edition(Some("2021".into())).build().unwrap();
+    assert!(settings.cargo_toml.unwrap().contains("edition = \"2021\""));
+
+    let build_err = |builder: AnalyzerSettingsBuilder| builder.build().err();
+    assert_eq!(
+        build_err(AnalyzerSettingsBuilder::default().edition(Some("2019".into()))),
+        Some(ConfigError::InvalidEdition("2019".into()))
+    );
+}
+
+#[test]
+fn test_offline_missing_dep() {
+    let unresolved = "error: no matching package named `serde` found\n\
+                      location searched: registry `crates-io`\n\
+                      required by package `twoslash-example v0.0.0 (/tmp/.tmpX)`\n\
+                      As a reminder, you're using offline mode (--offline) which can sometimes \
+                      cause surprising resolution failures";
+    assert_eq!(offline_missing_dep(unresolved), Some("serde".into()));
+    let undownloaded = "error: failed to download `regex v1.10.2`\n\n\
+                        Caused by:\n  attempting to make an HTTP request, but --offline was \
+                        specified";
+    assert_eq!(offline_missing_dep(undownloaded), Some("regex".into()));
+    assert_eq!(offline_missing_dep("error[E0425]: cannot find value `serde` in this scope"), None);
+    assert_eq!(
+        TwoslashError::OfflineMissingDep("serde".into()).to_string(),
+        "`serde` isn't available offline, it has to be fetched first"
+    );
+
+    // What a code block comes out as when the analyzer's Cargo fails like that
+    struct OfflineAnalyzer(&'static str);
+    impl AnalyzerTrait for OfflineAnalyzer {
+        fn analyze(&mut self, _code: &str) -> Result<AnalysisResult, String> {
+            Err(self.0.to_string())
+        }
+    }
+    let code = "let value = serde_json::json!({});";
+    let config = TwoslashConfig { offline: true, ..Default::default() };
+    assert_eq!(
+        process_code_block_with(code, None, &config, &mut OfflineAnalyzer(unresolved)),
+        Err(TwoslashError::OfflineMissingDep("serde".into()))
+    );
+    let config = TwoslashConfig { offline: false, ..config };
+    assert_eq!(
+        process_code_block_with(code, None, &config, &mut OfflineAnalyzer(unresolved)),
+        Err(TwoslashError::AnalysisFailed(unresolved.into()))
+    );
+}
+
+#[test]
+fn test_resolve_cargo_toml() {
+    let mut resolver =
+        MockCargoResolver { current_dir: "/work/foo/src".into(), ..Default::default() };
+    let resolve = |resolver: &MockCargoResolver| {
+        resolve_cargo_toml(resolver, &TwoslashConfig::from_resolver(resolver))
+    };
+    assert_eq!(resolve(&resolver), None);
+
+    resolver.files.insert(
+        "/work/foo/Cargo.toml".into(),
+        "[package]\nname = \"foo\"\n\n[features]\nfast = []\nsmall = []\n\n\
+         [dependencies]\nbar = { path = \"../bar\" }\n"
+            .into(),
+    );
+    resolver.env.insert("RUSTDOC_TWOSLASH_FEATURES".into(), "serde".into());
+    resolver.env.insert("CARGO_FEATURE_FAST".into(), "1".into());
+    assert_eq!(
+        resolve(&resolver).as_deref(),
+        Some(
+            "[package]\nname = \"twoslash-rustdoc-tmp\"\nedition = \"2015\"\n\n[features]\n\
+             fast = []\nsmall = []\n\n[dependencies]\n\
+             bar = { path = \"/work/foo/../bar\" }\n\
+             foo = { path = \"/work/foo\", package = \"foo\", features = [\"serde\", \"fast\"] }\n"
+        )
+    );
+}
+
+#[test]
+fn test_config_from_env() {
+    let mut resolver = MockCargoResolver::default();
+    let default = TwoslashConfig::default();
+    assert_eq!(TwoslashConfig::from_resolver(&resolver), default);
+    assert_eq!(default.timeout(), Some(Duration::from_secs(10)));
+
+    for (key, value) in [
+        ("RUSTDOC_TWOSLASH", "1"),
+        ("RUSTDOC_TWOSLASH_CARGO_TOML", "../Cargo.toml"),
+        ("RUSTDOC_TWOSLASH_TARGET_DIR", "/tmp/ts"),
+        ("RUSTDOC_TWOSLASH_CACHE_DIR", "/tmp/ts-cache"),
+        ("RUSTDOC_TWOSLASH_TIMEOUT_SECS", "0"),
+        ("RUSTDOC_TWOSLASH_FEATURES", "serde, fast"),
+        ("RUSTDOC_TWOSLASH_LOG_LEVEL", "Debug"),
+        ("RUSTDOC_TWOSLASH_PARALLELISM", "3"),
+        ("RUSTDOC_TWOSLASH_NO_CACHE", "1"),
+        ("RUSTDOC_TWOSLASH_DEDENT", "1"),
+        ("RUSTDOC_TWOSLASH_NO_STD", "1"),
+        ("RUSTDOC_TWOSLASH_WATCH_CARGO", "1"),
+        ("RUSTDOC_TWOSLASH_THREAD_LOCAL", "1"),
+        ("RUSTDOC_TWOSLASH_OFFLINE", "1"),
+        ("RUSTDOC_TWOSLASH_EXTRA_DEPS", "anyhow=1"),
+        ("RUSTDOC_TWOSLASH_ASYNC_RUNTIME", "smol"),
+        ("RUSTDOC_TWOSLASH_RESOLVE_URLS", "1"),
+        ("RUSTDOC_TWOSLASH_MAX_TYPE_LEN", "40"),
+        ("RUSTDOC_TWOSLASH_STRIP_PREFIXES", "my_crate::internal, serde_json"),
+        ("RUSTDOC_TWOSLASH_SINGLE_CHAR_BINDINGS", "false"),
+        ("RUSTDOC_TWOSLASH_ITERATOR_STEPS", "true"),
+        ("RUSTDOC_TWOSLASH_OUTPUT", "JSONLines"),
+        ("RUSTDOC_TWOSLASH_OUTPUT_FILE", "annotations.jsonl"),
+        ("RUSTDOC_TWOSLASH_STATS_FILE", "stats.jsonl"),
+        ("RUSTDOC_TWOSLASH_SLOW_THRESHOLD_MS", "500"),
+        ("RUSTDOC_TWOSLASH_PARTIAL_ON_TIMEOUT", "1"),
+        ("RUSTDOC_TWOSLASH_MAX_ANNOTATIONS", "20"),
+        ("RUSTDOC_TWOSLASH_TRIM_STRATEGY", "by-line:1"),
+    ] {
+        resolver.env.insert(key.into(), value.into());
+    }
+    let config = TwoslashConfig::from_resolver(&resolver);
+    assert_eq!(
+        config,
+        TwoslashConfig {
+            enabled: true,
+            cargo_toml_path: Some("../Cargo.toml".into()),
+            target_dir: "/tmp/ts".into(),
+            cache_dir: "/tmp/ts-cache".into(),
+            timeout_secs: 0,
+            features: vec!["serde".into(), "fast".into()],
+            log_level: LogLevel::Debug,
+            parallelism: 3,
+            no_cache: true,
+            dedent: true,
+            no_std: true,
+            watch_cargo: true,
+            thread_local_analyzers: true,
+            offline: true,
+            extra_deps: vec![("anyhow".into(), "1".into())],
+            async_runtime: AsyncRuntime::Smol,
+            resolve_urls: true,
+            type_text: TypeTextConfig {
+                max_length: Some(40),
+                strip_prefixes: vec!["my_crate::internal".into(), "serde_json".into()],
+                collapse_std: false,
+            },
+            annotate_single_char_bindings: false,
+            show_iterator_steps: true,
+            output: OutputFormat::JsonLines,
+            output_file: Some("annotations.jsonl".into()),
+            stats_file: Some("stats.jsonl".into()),
+            slow_threshold_ms: 500,
+            partial_on_timeout: true,
+            max_annotations: Some(20),
+            trim_strategy: AnnotationTrimStrategy::ByLine(1),
+        }
+    );
+    assert_eq!(config.timeout(), None);
+    // Messages are logged at or below the configured level, so `off` is below all of them
+    assert_eq!("off".parse(), Ok(LogLevel::Off));
+    assert!(LogLevel::Off < LogLevel::Error && LogLevel::Info < LogLevel::Debug);
+
+    // Invalid values fall back to their defaults
+    for (key, value) in [
+        ("RUSTDOC_TWOSLASH_TIMEOUT_SECS", "soon"),
+        ("RUSTDOC_TWOSLASH_LOG_LEVEL", "loud"),
+        ("RUSTDOC_TWOSLASH_PARALLELISM", "0"),
+        ("RUSTDOC_TWOSLASH_EXTRA_DEPS", "anyhow"),
+        ("RUSTDOC_TWOSLASH_OUTPUT", "xml"),
+        // Stdout
+        ("RUSTDOC_TWOSLASH_OUTPUT_FILE", "-"),
+    ] {
+        resolver.env.insert(key.into(), value.into());
+    }
+    let config = TwoslashConfig::from_resolver(&resolver);
+    assert_eq!(config.timeout_secs, default.timeout_secs);
+    assert_eq!(config.log_level, default.log_level);
+    assert_eq!(config.parallelism, default.parallelism);
+    assert_eq!(config.extra_deps, []);
+    assert_eq!(config.output, OutputFormat::Html);
+    assert_eq!(config.output_file, None);
+}
+
+#[test]
+fn test_env_switch() {
+    let enabled = |value: &str| {
+        let mut resolver = MockCargoResolver::default();
+        resolver.env.insert("RUSTDOC_TWOSLASH".into(), value.into());
+        TwoslashConfig::from_resolver(&resolver).enabled
+    };
+    for value in ["", "1", "TRUE", "on", " yes "] {
+        assert!(enabled(value), "{value:?}");
+    }
+    // Values other than the falsy ones are invalid, and ignored
+    for value in ["0", "false", "Off", "no", "maybe"] {
+        assert!(!enabled(value), "{value:?}");
+    }
+    assert_eq!(env_switch("No"), Some(false));
+    assert_eq!(env_switch("maybe"), None);
+}
+
+#[test]
+fn test_json_lines() {
+    let code = "let x = 1;";
+    let mut with_docs = TypeAnnotation::new(4, 1, "let x: i32");
+    with_docs.docs = Some("A number".into());
+    let annotations = [with_docs, TypeAnnotation::new(8, 1, "i32")];
+    let lines = json_lines(code, &annotations);
+    let records: Vec<serde_json::Value> =
+        lines.lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+    assert_eq!(records.len(), 2);
+    assert_eq!(records[0]["token_text"], "x");
+    assert_eq!(records[0]["type_text"], "let x: i32");
+    assert_eq!(records[0]["docs"], "A number");
+    assert_eq!((&records[1]["start"], &records[1]["length"]), (&8.into(), &1.into()));
+    assert!(records[1].get("docs").is_none());
+    // Blocks are told apart by their source
+    let block_id = records[0]["block_id"].as_str().unwrap();
+    assert_eq!(block_id.len(), 16);
+    assert_eq!(records[1]["block_id"], block_id);
+    let other = json_lines("let y = 1;", &annotations[..1]);
+    let other: serde_json::Value = serde_json::from_str(other.trim_end()).unwrap();
+    assert_ne!(other["block_id"], block_id);
+
+    // Writes to the same file append to it
+    let tmp = tempfile::tempdir().unwrap();
+    let path = tmp.path().join("annotations.jsonl");
+    let config = TwoslashConfig { output_file: Some(path.clone()), ..Default::default() };
+    write_json_lines(code, &annotations[..1], &config);
+    write_json_lines(code, &annotations[1..], &config);
+    assert_eq!(fs::read_to_string(&path).unwrap(), lines);
+}
+
+#[test]
+fn test_annotation_stats() {
+    let code = "let v = vec![1, 2];\n\nlet n = v.len(); // 3\ndrop(n);\n";
+    let annotations = [
+        TypeAnnotation::new(4, 1, "let v: Vec<i32>"),
+        TypeAnnotation::new(25, 1, "let n: usize"),
+        TypeAnnotation::new(31, 3, "fn len"),
+    ];
+    let stats = annotation_stats(code, &annotations);
+    // Keywords count as tokens, numbers don't
+    assert_eq!(stats.total_tokens, 9);
+    assert_eq!(stats.annotated_tokens, 3);
+    assert!((stats.coverage_percent - 100.0 / 3.0).abs() < 0.01);
+    assert_eq!(stats.missing_lines, [4]);
+    let json: serde_json::Value = serde_json::from_str(&stats.to_json()).unwrap();
+    assert_eq!(json["missing_lines"], serde_json::json!([4]));
+
+    let stats = annotation_stats("// 1 + 2", &[]);
+    assert_eq!((stats.total_tokens, stats.coverage_percent), (0, 100.0));
+}
+
+#[test]
+fn test_normalize_type_text() {
+    let text = "let map: std::collections::hash::map::HashMap<alloc::string::String, \
+                alloc::vec::Vec<u8>>";
+    let config = |max_length, strip_prefixes: &[&str], collapse_std| TypeTextConfig {
+        max_length,
+        strip_prefixes: strip_prefixes.iter().map(|p| p.to_string()).collect(),
+        collapse_std,
+    };
+    assert_eq!(normalize_type_text(text, &TypeTextConfig::default()), text);
+    assert_eq!(
+        normalize_type_text(text, &config(None, &[], true)),
+        "let map: HashMap<String, Vec<u8>>"
+    );
+    assert_eq!(
+        normalize_type_text(text, &config(None, &["std::collections::hash::map::"], false)),
+        "let map: HashMap<alloc::string::String, alloc::vec::Vec<u8>>"
+    );
+    assert_eq!(normalize_type_text(text, &config(Some(12), &[], true)), "let map: Ha…");
+
+    // Only whole leading segments are stripped, and functions and modules keep their path
+    assert_eq!(
+        normalize_type_text(
+            "fn f(a: my_crate::A, b: other_crate::B)",
+            &config(None, &["crate"], false)
+        ),
+        "fn f(a: my_crate::A, b: other_crate::B)"
+    );
+    assert_eq!(
+        normalize_type_text(
+            "my_crate::inner\n\npub fn f() -> ::core::fmt::Result",
+            &config(None, &["my_crate"], true)
+        ),
+        "inner\n\npub fn f() -> Result"
+    );
+    assert_eq!(
+        normalize_type_text("std::mem\npub fn swap", &config(None, &[], true)),
+        "std::mem\npub fn swap"
+    );
+
+    // Docs after the signature are left alone
+    let text = "let s: alloc::string::String\n\n---\n\nSee alloc::string::String";
+    assert_eq!(
+        normalize_type_text(text, &config(Some(10), &[], true)),
+        "let s: St…\n\n---\n\nSee alloc::string::String"
+    );
+}
+
+#[test]
+fn test_parse_extra_deps() {
+    assert_eq!(
+        parse_extra_deps("serde_json=1.0, anyhow = ^1 ,tokio=>=1.2.3-rc.1,any=*").unwrap(),
+        [
+            ("serde_json".to_string(), "1.0".to_string()),
+            ("anyhow".into(), "^1".into()),
+            ("tokio".into(), ">=1.2.3-rc.1".into()),
+            ("any".into(), "*".into()),
+        ]
+    );
+    assert_eq!(parse_extra_deps("").unwrap(), []);
+    assert_eq!(parse_extra_deps("serde_json"), Err(ExtraDepError::Malformed("serde_json".into())));
+    assert_eq!(parse_extra_deps("=1.0"), Err(ExtraDepError::InvalidName("".into())));
+    assert_eq!(
+        parse_extra_deps("serde=latest"),
+        Err(ExtraDepError::InvalidVersion { name: "serde".into(), version: "latest".into() })
+    );
+    assert!(parse_extra_deps("serde=1.0.0.0").is_err());
+}
+
+#[test]
+fn test_inject_extra_dependencies() {
+    let deps = [("serde_json".to_string(), "\"1.0\"".to_string()), ("foo".into(), "\"2\"".into())];
+    assert_eq!(
+        edit_manifest(
+            "[package]\nname = \"x\"\n\n[dependencies]\nfoo = { path = \"/foo\" }\n\n[features]\n",
+            |doc| inject_extra_dependencies(doc, &deps),
+        ),
+        "[package]\nname = \"x\"\n\n[dependencies]\nfoo = { path = \"/foo\" }\n\
+         serde_json = \"1.0\"\n\n[features]\n",
+    );
+    assert_eq!(
+        edit_manifest("[package]\nname = \"x\"\n", |doc| inject_extra_dependencies(doc, &deps)),
+        "[package]\nname = \"x\"\n\n[dependencies]\nserde_json = \"1.0\"\nfoo = \"2\"\n",
+    );
+}
+
+#[test]
+fn test_struct_literal_field_annotations() {
+    let code = "struct Point { x: f64, y: f64 }\nlet p = Point { x: 1.0, y: 2.0 };";
+    let wrapped = WrappedCode::with_runtime(code, AsyncRuntime::Tokio);
+    let in_wrapped = |token: &str| wrapped.code.find(token).unwrap() as u32;
+    let in_code = |token: &str| code.find(token).unwrap() as u32;
+    let config = TwoslashConfig::default();
+    // Offsets of the analyzer's hover infos are into the wrapped code
+    let info = |start: u32, length: u32, text: &str| {
+        quick_info_annotation(code, &wrapped, (start, length), text.into(), None, &config)
+    };
+
+    let field = info(in_wrapped("x: 1.0"), 1, "main::Point\npub x: f64").unwrap();
+    assert_eq!((field.start, field.length), (in_code("x: 1.0"), 1));
+    assert_eq!(field.kind, TokenKind::Field);
+    let field = info(in_wrapped("y: f64"), 1, "main::Point\npub y: f64").unwrap();
+    assert_eq!((field.start, field.kind), (in_code("y: f64"), TokenKind::Field));
+    let binding = info(in_wrapped("p ="), 1, "let p: Point").unwrap();
+    assert_eq!((binding.start, binding.kind), (in_code("p ="), TokenKind::Variable));
+
+    // Punctuation, the wrapper and ranges leaving the code block are skipped
+    assert_eq!(info(in_wrapped("= Point"), 1, "="), None);
+    assert_eq!(info(in_wrapped("main"), 4, "fn main()"), None);
+    assert_eq!(info(in_wrapped("}\nfn main"), 6, "fn main()"), None);
+    assert_eq!(info(in_wrapped("2.0"), 8, "f64"), None);
+
+    let config = TwoslashConfig { annotate_single_char_bindings: false, ..Default::default() };
+    let info = |start: u32, length: u32, text: &str| {
+        quick_info_annotation(code, &wrapped, (start, length), text.into(), None, &config)
+    };
+    assert_eq!(info(in_wrapped("p ="), 1, "let p: Point"), None);
+    assert!(info(in_wrapped("x: 1.0"), 1, "main::Point\npub x: f64").is_some());
+}
+
+#[test]
+fn test_to_inline_comment() {
+    let code = "let x = 1;\npub fn add(a: u8, b: u8) -> u8 { a + b }";
+    let binding = TypeAnnotation::new(4, 1, "let x: i32\n\n---\n\nA number");
+    assert_eq!(binding.to_inline_comment(code), "// : i32");
+    let function = TypeAnnotation::new(18, 3, "main\npub const fn add(a: u8,\n    b: u8) -> u8");
+    assert_eq!(function.to_inline_comment(code), "// : const fn(a: u8, b: u8) -> u8");
+    let item = TypeAnnotation::new(0, 3, "struct   Foo");
+    assert_eq!(item.to_inline_comment(code), "// : struct Foo");
+    let error = TypeAnnotation {
+        kind: TokenKind::CompileError,
+        ..TypeAnnotation::new(4, 1, "mismatched types\nexpected `u8`")
+    };
+    assert_eq!(error.to_inline_comment(code), "// error: mismatched types expected `u8`");
+}
+
+#[test]
+fn test_display() {
+    let code = "let x = 1;";
+    let mut ann = TypeAnnotation::new(4, 1, "let x: i32");
+    assert_eq!(ann.to_string(), "let x: i32");
+    assert_eq!(ann.display(code).to_string(), "x: i32");
+    ann.docs = Some("The answer".into());
+    assert_eq!(ann.to_string(), "let x: i32\n\nThe answer");
+    assert_eq!(ann.display(code).to_string(), "x: i32\n\nThe answer");
+    assert!(format!("{ann:?}").starts_with("TypeAnnotation { range: 4..5, type_text: "));
+}
+
+#[test]
+fn test_group_by_line() {
+    let code = "let x = 1;\r\n\nlet s = \"<&>\"; let t = s;";
+    let mut s = TypeAnnotation::new(17, 1, "let s: &str\n\n---\n\nA string");
+    s.kind = TokenKind::Variable;
+    let annotations =
+        vec![TypeAnnotation::new(32, 1, "let t: &str"), s, TypeAnnotation::new(4, 1, "let x: i32")];
+    let lines = group_by_line(code, annotations);
+    let summary: Vec<_> = lines
+        .iter()
+        .map(|line| {
+            let starts: Vec<_> = line.annotations.iter().map(|ann| ann.start).collect();
+            (line.line_number, line.line_text.as_str(), starts)
+        })
+        .collect();
+    assert_eq!(
+        summary,
+        [
+            (1, "let x = 1;", vec![4]),
+            (2, "", vec![]),
+            (3, "let s = \"<&>\"; let t = s;", vec![4, 19])
+        ]
+    );
+    assert!(lines[0].has_annotations() && !lines[1].has_annotations());
+
+    assert_eq!(lines[0].as_commented_line(), "let x = 1;  // : i32");
+    assert_eq!(lines[1].as_commented_line(), "");
+    assert_eq!(lines[2].as_commented_line(), "let s = \"<&>\"; let t = s;  // s: &str, t: &str");
+    let html = lines[2].to_html_line();
+    assert_eq!(html.matches("<span class=\"twoslash-hover\" data-type=\"").count(), 2);
+    assert_eq!(html.matches(" data-kind=\"variable\">s</span>").count(), 1);
+    assert_eq!(html.matches(" data-docs=\"").count(), 1);
+    assert!(html.contains(" = &quot;&lt;&amp;&gt;&quot;; let "), "{html}");
+}
+
+#[test]
+fn test_trim_annotations() {
+    let code = "let a = v;\nlet b = w; let c = x;\n";
+    let annotations = vec![
+        TypeAnnotation::new(4, 1, "let a: Vec<String>"),
+        TypeAnnotation::new(15, 1, "let b: u8"),
+        TypeAnnotation::new(26, 1, "let c: Option<u8>"),
+    ];
+    let starts = |max, strategy| -> Vec<u32> {
+        let trimmed = trim_annotations(code, annotations.clone(), max, strategy);
+        trimmed.iter().map(|ann| ann.start).collect()
+    };
+    assert_eq!(starts(3, AnnotationTrimStrategy::MostInformative), [4, 15, 26]);
+    // Kept in order of the code, whichever are picked
+    assert_eq!(starts(2, AnnotationTrimStrategy::MostInformative), [4, 26]);
+    assert_eq!(starts(2, AnnotationTrimStrategy::First), [4, 15]);
+    assert_eq!(starts(2, AnnotationTrimStrategy::Last), [15, 26]);
+    assert_eq!(starts(3, AnnotationTrimStrategy::ByLine(1)), [4, 26]);
+    assert_eq!(starts(1, AnnotationTrimStrategy::ByLine(1)), [4]);
+    assert!(starts(0, AnnotationTrimStrategy::First).is_empty());
+
+    assert_eq!("by-line:2".parse(), Ok(AnnotationTrimStrategy::ByLine(2)));
+    assert_eq!("Most_Informative".parse(), Ok(AnnotationTrimStrategy::MostInformative));
+    assert_eq!("by-line".parse::<AnnotationTrimStrategy>(), Err(()));
+
+    let config = TwoslashConfig {
+        max_annotations: Some(1),
+        trim_strategy: AnnotationTrimStrategy::Last,
+        ..Default::default()
+    };
+    let code = "let a = 1;\nlet b = 2;";
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(code, "a", "let a: i32")
+        .hover(code, "b", "let b: i32")
+        .build();
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let tokens: Vec<_> = annotations.iter().map(|ann| &code[ann.byte_range()]).collect();
+    assert_eq!(tokens, ["b"]);
+}
+
+#[test]
+fn test_annotation_postprocessor() {
+    // Other tests process code blocks concurrently, so only this one's is touched
+    const CODE: &str = "let postprocessed = 1;";
+    fn add_url(code: &str, annotations: &mut Vec<TypeAnnotation>) {
+        if code == CODE {
+            annotations.iter_mut().for_each(|ann| ann.url = Some("https://example.com".into()));
+            annotations.push(TypeAnnotation::new(CODE.len() as u32 - 2, 1, "i32"));
+        }
+    }
+
+    let mut analyzer =
+        MockAnalyzer::builder().hover(CODE, "postprocessed", "let postprocessed: i32").build();
+    let config = TwoslashConfig::default();
+    set_annotation_postprocessor(add_url);
+    let annotations = process_code_block_with(CODE, None, &config, &mut analyzer).unwrap();
+    assert_eq!(annotations[0].url.as_deref(), Some("https://example.com"));
+    assert_eq!(annotations.len(), 2);
+
+    clear_annotation_postprocessor();
+    let annotations = process_code_block_with(CODE, None, &config, &mut analyzer).unwrap();
+    assert_eq!(annotations[0].url, None);
+    assert_eq!(annotations.len(), 1);
+}
+
+#[test]
+fn test_split_tuple_patterns() {
+    let code = "let (a, (mut bc, _), ref d) = f();";
+    let config = TwoslashConfig::default();
+    let pattern =
+        TypeAnnotation::new(4, 23, "let (a, (mut bc, _), ref d): (i32, (Vec<(u8, u8)>, ()), &str)");
+    let split = split_tuple_patterns(code, vec![pattern.clone()], &config);
+    let bindings: Vec<_> =
+        split.iter().map(|ann| (ann.start, ann.length, &*ann.type_text)).collect();
+    assert_eq!(
+        bindings,
+        [(5, 1, "let a: i32"), (13, 2, "let bc: Vec<(u8, u8)>"), (25, 1, "let d: &str")]
+    );
+    assert!(split.iter().all(|ann| ann.kind == TokenKind::Variable));
+
+    // The analyzer's own annotation of a binding is kept
+    let own = TypeAnnotation::new(13, 2, "let mut bc: Vec<(u8, u8)>");
+    let split = split_tuple_patterns(code, vec![pattern.clone(), own.clone()], &config);
+    assert_eq!(split.len(), 3);
+    assert_eq!(split[2].type_text, own.type_text);
+
+    let config = TwoslashConfig { annotate_single_char_bindings: false, ..Default::default() };
+    let split = split_tuple_patterns(code, vec![pattern], &config);
+    assert_eq!(split.iter().map(|ann| ann.start).collect::<Vec<_>>(), [13]);
+
+    // Patterns that don't match their type are left alone
+    let mismatched = TypeAnnotation::new(4, 23, "let (a, (mut bc, _), ref d): (i32, &str)");
+    assert_eq!(split_tuple_patterns(code, vec![mismatched.clone()], &config), [mismatched]);
+}
+
+#[test]
+fn test_annotate_method_chains() {
+    let code = "let n = v.len();\nlet doubled = v.iter()\n    .map(|x| x * 2)\n    \
+                .filter(|x| *x > 0)\n    .collect::<Vec<_>>();";
+    let method = |name: &str, type_text: &str| {
+        let start = code.find(name).unwrap() as u32;
+        TypeAnnotation {
+            kind: TokenKind::Method,
+            ..TypeAnnotation::new(start, name.len() as u32, type_text)
+        }
+    };
+    let annotations = vec![
+        method("len", "core::slice\nimpl<T> [T]\npub const fn len(&self) -> usize"),
+        TypeAnnotation::new(code.find("doubled").unwrap() as u32, 7, "let doubled: Vec<i32>"),
+        method("iter", "core::slice\nimpl<T> [T]\npub fn iter(&self) -> Iter<'_, T>"),
+        method(
+            "map",
+            "core::iter::traits::iterator::Iterator\npub fn map<B, F>(self, f: F) -> Map<Self, F>\n\
+             where\n    Self: Sized,\n    F: FnMut(Self::Item) -> B,\n\n---\n\nTakes a closure",
+        ),
+        method("filter", "pub fn filter<P>(self, predicate: P) -> Filter<Self, P>"),
+        method("collect", "pub fn collect<B>(self) -> B"),
+    ];
+    let config = TwoslashConfig { show_iterator_steps: true, ..Default::default() };
+    let annotated = annotate_method_chains(code, annotations.clone(), &config);
+    let type_texts: Vec<_> = annotated.iter().map(|ann| &*ann.type_text).collect();
+    assert_eq!(
+        type_texts,
+        [
+            // Calls outside a chain, and the last call of one, are left alone
+            &*annotations[0].type_text,
+            &annotations[1].type_text,
+            "iter() -> Iter<'_, T>",
+            "map(…) -> Map<Iter<'_, T>, F>\n\n---\n\nTakes a closure",
+            "filter(…) -> Filter<Map<Iter<'_, T>, F>, P>",
+            &annotations[5].type_text,
+        ]
+    );
+
+    let config = TwoslashConfig::default();
+    let annotated = annotate_method_chains(code, annotations.clone(), &config);
+    assert!(annotated.iter().zip(&annotations).all(|(a, b)| a.type_text == b.type_text));
+}
+
+#[test]
+fn test_synthesize_fn_signatures() {
+    let code =
+        "fn greet<T: Fn() -> u8>(name: &str,\n    count: T) -> Result<String, ()> where T: Copy {}";
+    let name_start = code.find("name").unwrap() as u32;
+    let mut query = TypeAnnotation::new(code.find("count").unwrap() as u32, 5, "count: T");
+    query.kind = TokenKind::QueryResult;
+    let annotations = vec![
+        TypeAnnotation::new(3, 5, "main\nfn greet<T>(name: &str, count: T)\n\n---\n\nGreets"),
+        TypeAnnotation::new(name_start, 4, "name: &'static str"),
+        query.clone(),
+        TypeAnnotation::new(code.find("String").unwrap() as u32, 6, "pub struct String"),
+    ];
+    let synthesized = synthesize_fn_signatures(code, annotations);
+    assert_eq!(synthesized.len(), 3);
+    assert_eq!(
+        synthesized[0].type_text,
+        "fn(name: &'static str, count: T) -> Result<String, ()>\n\n---\n\nGreets"
+    );
+    assert_eq!(synthesized[0].kind, TokenKind::Function);
+    // The query is kept, and so is the return type's own annotation
+    assert_eq!(synthesized[1], query);
+    assert_eq!(synthesized[2].type_text, "pub struct String");
+
+    let code = "impl S {\n    fn get(&mut self, key: &[u8]);\n}";
+    let start = code.find("get").unwrap() as u32;
+    let synthesized = synthesize_fn_signatures(code, vec![TypeAnnotation::new(start, 3, "fn get")]);
+    assert_eq!(synthesized[0].type_text, "fn(&mut self, key: &[u8])");
+
+    // Functions without an annotation of their own, and ones in comments, are left alone
+    let code = "// fn f(x: u8) {}\nfn g(y: u8) {}";
+    let annotations = vec![
+        TypeAnnotation::new(6, 1, "fn f"),
+        TypeAnnotation::new(code.find("y:").unwrap() as u32, 1, "y: u8"),
+    ];
+    assert_eq!(synthesize_fn_signatures(code, annotations.clone()), annotations);
+}
+
+#[test]
+fn test_validate() {
+    let code = "let café = \"🦀\"; café";
+    let cafe = code.rfind("café").unwrap() as u32;
+    let ann = TypeAnnotation::new(cafe, "café".len() as u32, "let café: &str");
+    assert_eq!(ann.validate(code), Ok(()));
+
+    // Ends in the middle of `é`
+    let split = TypeAnnotation::new(cafe, 4, "");
+    assert_eq!(split.validate(code), Err(AnnotationError::MisalignedEnd { byte_offset: 24 }));
+
+    let past_end = TypeAnnotation::new(cafe, 10, "");
+    assert_eq!(
+        past_end.validate(code),
+        Err(AnnotationError::OutOfBounds { start: 20, length: 10, code_len: 25 })
+    );
+    // Starts in the middle of `é`
+    let inside = TypeAnnotation::new(cafe + 4, 1, "");
+    assert_eq!(inside.validate(code), Err(AnnotationError::MisalignedStart { byte_offset: 24 }));
+
+    assert_eq!(
+        validate_annotations(code, &[ann, split, past_end, inside]),
+        [
+            AnnotationError::MisalignedEnd { byte_offset: 24 },
+            AnnotationError::OutOfBounds { start: 20, length: 10, code_len: 25 },
+            AnnotationError::MisalignedStart { byte_offset: 24 },
+        ]
+    );
+}
+
+#[test]
+fn test_sanitize_docs_for_attribute() {
+    assert_eq!(
+        sanitize_docs_for_attribute("<a href=\"x\">&</a>\n\u{0}\tend"),
+        "&lt;a href=&quot;x&quot;&gt;&amp;&lt;/a&gt;&#10;\tend"
+    );
+}
+
+#[test]
+fn test_source_text() {
+    let code = "let café = r#type + 10 + \"s\";";
+    let token = |start, length| TypeAnnotation::new(start, length, "");
+    assert_eq!(token(4, 5).source_text(code), Some("café"));
+    assert_eq!(token(4, 4).source_text(code), None);
+    assert_eq!(token(30, 4).source_text(code), None);
+}
+
+#[test]
+fn test_annotation_order() {
+    let mut annotations = [
+        TypeAnnotation::new(10, 3, "a"),
+        TypeAnnotation::new(4, 5, "b"),
+        TypeAnnotation::new(4, 2, "c"),
+        TypeAnnotation::new(10, 3, "d"),
+    ];
+    assert!(annotations[2] < annotations[1]);
+    // The same range with another type text is a different annotation
+    assert_ne!(annotations[0], annotations[3]);
+    annotations.sort();
+    let texts: Vec<_> = annotations.iter().map(|ann| ann.type_text.as_str()).collect();
+    assert_eq!(texts, ["c", "b", "a", "d"]);
+
+    // Docs don't count, so this is a duplicate of the `b` annotation
+    let mut documented = TypeAnnotation::new(4, 5, "b");
+    documented.docs = Some("Docs".into());
+    assert_eq!(documented, annotations[1]);
+    let unique: FxHashSet<_> = annotations.into_iter().chain([documented]).collect();
+    assert_eq!(unique.len(), 4);
+}
+
+#[test]
+fn test_nesting_delta() {
+    let delta = |line: &str| nesting_delta(line, &mut LexState::Code);
+    assert_eq!(delta("fn f() {"), 1);
+    assert_eq!(delta(r#"fn f() { println!("{{ {} }}", "{"); }"#), 0);
+    assert_eq!(delta(r#"let s = "escaped \" {";"#), 0);
+    assert_eq!(delta(r###"const S: &str = r##"raw "# {"##; {"###), 1);
+    assert_eq!(delta(r#"let s = br"{"; let t = bar"#), 0);
+    assert_eq!(delta("let c = '{'; let d = b'}'; let e = '\\''; {"), 1);
+    assert_eq!(delta("fn f<'a>(x: &'a str) -> &'a str {"), 1);
+    assert_eq!(delta("let x = 1; // {"), 0);
+    assert_eq!(delta("/* { /* } */ { */ }"), -1);
+    assert_eq!(delta("#[derive(Debug, Clone,"), 2);
+    assert_eq!(delta("let v = [(1, \"]\"), (2, \")\")];"), 0);
+
+    let mut state = LexState::Code;
+    assert_eq!(nesting_delta("const S: &str = \"{", &mut state), 0);
+    assert_eq!(state, LexState::Str);
+    assert_eq!(nesting_delta("}\";", &mut state), 0);
+    assert_eq!(state, LexState::Code);
+    assert_eq!(nesting_delta("/* {", &mut state), 0);
+    assert_eq!(state, LexState::BlockComment(1));
+}
+
+#[test]
+fn test_split_items_and_statements_literals() {
+    let code = "fn greet() {\n    println!(\"}} {}\", \"{\");\n}\nlet x = greet();\n";
+    assert_eq!(
+        split_items_and_statements(code),
+        (
+            "fn greet() {\n    println!(\"}} {}\", \"{\");\n}\n".to_string(),
+            "let x = greet();\n".to_string()
+        )
+    );
+
+    let code = "const T: &str = r#\"\n{\n\"#;\nlet t = T;\n";
+    assert_eq!(split_items_and_statements(code).1, "let t = T;\n");
+
+    // Doc comments stay with the item they document
+    let code = "/// Returns `{`\nfn open() -> char { '{' }\n\n/* } */\nlet c = open();";
+    assert_eq!(split_items_and_statements(code).1, "let c = open();");
+}
+
+#[test]
+fn test_split_items_and_statements_attributes() {
+    let code = "#[derive(Debug, Clone,\n    PartialEq)]\nstruct Foo;\nlet foo = Foo;\n";
+    assert_eq!(
+        split_items_and_statements(code),
+        (
+            "#[derive(Debug, Clone,\n    PartialEq)]\nstruct Foo;\n".to_string(),
+            "let foo = Foo;\n".to_string()
+        )
+    );
+
+    // Parameters and return types spread over several lines
+    let code = "fn add(\n    a: i32,\n    b: i32,\n) -> i32 {\n    a + b\n}\nadd(1, 2);";
+    assert_eq!(split_items_and_statements(code).1, "add(1, 2);");
+
+    // An attribute on a statement goes with the statement
+    let code = "struct Foo;\n#[allow(unused_variables)]\nlet foo = Foo;\n";
+    assert_eq!(split_items_and_statements(code).1, "#[allow(unused_variables)]\nlet foo = Foo;\n");
+}
+
+#[test]
+fn test_split_items_and_statements_extern() {
+    // The block's closing brace ends the item, not its opening line
+    let code = "extern \"C\" {\n    fn abs(x: i32) -> i32;\n}\nlet x = unsafe { abs(-1) };\n";
+    assert_eq!(
+        split_items_and_statements(code),
+        (
+            "extern \"C\" {\n    fn abs(x: i32) -> i32;\n}\n".to_string(),
+            "let x = unsafe { abs(-1) };\n".to_string()
+        )
+    );
+
+    // `extern crate` is a one-line item, `extern` blocks can be too
+    let code = "extern crate alloc;\nunsafe extern \"C\" {\n    safe fn abs(x: i32) -> i32;\n}\n\
+                extern \"C\" { fn labs(x: i64) -> i64; }\nlet x = abs(-1);";
+    assert_eq!(split_items_and_statements(code).1, "let x = abs(-1);");
+}
+
+#[test]
+fn test_split_items_and_statements_impl() {
+    let code = "struct Counter(u32);\nimpl Counter { fn new() -> Self { Counter(0) } }\n\
+                let x = Counter::new();\n";
+    assert_eq!(split_items_and_statements(code).1, "let x = Counter::new();\n");
+
+    // Method bodies over several lines, with braces opening and closing on the same line
+    let code = "impl Counter {\n    fn new() -> Self {\n        if true { Counter(0) } else {\n\
+                \x20           Counter(1)\n        }\n    }\n}\nlet x = Counter::new();\n";
+    let (items, body) = split_items_and_statements(code);
+    assert!(items.ends_with("    }\n}\n"), "{items}");
+    assert_eq!(body, "let x = Counter::new();\n");
+
+    let code = "impl<T> Trait for Vec<T> where T: Clone {\n    fn f(&self) {}\n}\nlet v = vec![1];";
+    assert_eq!(split_items_and_statements(code).1, "let v = vec![1];");
+}
+
+#[test]
+fn test_preamble_only_block() {
+    let code =
+        "fn add(lhs: i32, rhs: i32) -> i32 {\n    lhs + rhs\n}\n\nstruct Point {\n    x: f64,\n}\n";
+    let wrapped = WrappedCode::new(code, &TwoslashConfig::default());
+    assert_eq!((wrapped.code.as_str(), wrapped.preamble_len, wrapped.fn_main_offset), (code, 0, 0));
+    assert_eq!(wrapped.to_original(0), Some(0));
+    assert_eq!(wrapped.to_original(code.len() as u32 - 1), Some(code.len() as u32 - 1));
+
+    let last = code.len() as u32 - 2;
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(code, "add", "fn add(lhs: i32, rhs: i32) -> i32")
+        // Parameters are folded into the signature, but their uses in the body are kept
+        .quick_info(code, code.find("+ rhs").unwrap() as u32 + 2, 3, "rhs: i32")
+        .hover(code, "Point", "struct Point")
+        .hover(code, "x", "crate::Point\nx: f64")
+        // Running past the end of the block
+        .quick_info(code, last, 5, "}")
+        .build();
+    let config = TwoslashConfig { no_cache: true, ..Default::default() };
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let tokens: Vec<_> = annotations.iter().map(|ann| ann.source_text(code).unwrap()).collect();
+    assert_eq!(tokens, ["add", "rhs", "Point", "x"]);
+    assert!(annotations.iter().all(|ann| ((ann.start + ann.length) as usize) <= code.len()));
+}
+
+#[test]
+fn test_no_std_block() {
+    let code = "//! A `no_std` example\n#![allow(unused)]\n#![ no_std ]\n\nlet x = 1;\n";
+    assert!(is_no_std(code));
+    let wrapped = WrappedCode::new(code, &TwoslashConfig::default());
+    assert_eq!((wrapped.code.as_str(), wrapped.fn_main_offset), (code, 0));
+    // Only as an inner attribute at the top of the block
+    assert!(!is_no_std("let x = 1;\n#![no_std]"));
+    assert!(!is_no_std("// #![no_std]\nlet x = 1;"));
+    assert!(!is_no_std("#![no_std_compat]"));
+
+    let code = "let x = 1;";
+    assert_eq!(
+        WrappedCode::new(code, &TwoslashConfig::default()).code,
+        "fn main() {\nlet x = 1;\n}"
+    );
+    let config = TwoslashConfig { no_std: true, ..Default::default() };
+    assert_eq!(WrappedCode::new(code, &config).code, code);
+}
+
+#[test]
+fn test_find_comment_spans() {
+    let code = "/// Makes a `Foo`\nlet s = \"// no\"; // yes\n/* a /* b */\nc */ let c = '/';\n";
+    let spans: Vec<&str> = find_comment_spans(code).into_iter().map(|span| &code[span]).collect();
+    assert_eq!(spans, ["/// Makes a `Foo`", "// yes", "/* a /* b */", "c */"]);
+
+    // A commented-out `fn main` still needs wrapping
+    let code = "// fn main() {\nlet x = 1;";
+    assert_eq!(split_items_and_statements(code).1, "let x = 1;");
+    assert_eq!(split_items_and_statements("fn main() {}").1, "");
+
+    let code = "/// Doubles `x`\nfn double(x: u8) -> u8 {\n    x * 2\n}\n";
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(code, "Doubles", "fn double(x: u8) -> u8")
+        .hover(code, "double", "fn double(x: u8) -> u8")
+        .build();
+    let config = TwoslashConfig { no_cache: true, ..Default::default() };
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let tokens: Vec<_> = annotations.iter().map(|ann| ann.source_text(code).unwrap()).collect();
+    assert_eq!(tokens, ["double"]);
+}
+
+#[test]
+fn test_dedent() {
+    let code = "    struct Foo;\n\n      let foo = Foo;\n  \n    let s = \"a\n  b\";\n";
+    // The string's second line has less indentation, so only two spaces are shared
+    assert_eq!(dedent(code).1, 2);
+    let code = "    struct Foo;\n\n      let foo = Foo;\n  \n    foo;\n";
+    let (dedented, indent) = dedent(code);
+    assert_eq!((dedented.as_str(), indent), ("struct Foo;\n\n  let foo = Foo;\n  \nfoo;\n", 4));
+    assert_eq!(dedent("fn main() {\n    1;\n}").1, 0);
+
+    // Annotations are of the code as it was given
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(&dedented, "foo", "let foo: Foo")
+        .hover(&dedented, "Foo", "struct Foo")
+        .quick_info(&dedented, dedented.rfind("foo").unwrap() as u32, 3, "let foo: Foo")
+        .build();
+    let config = TwoslashConfig { dedent: true, ..Default::default() };
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let tokens: Vec<_> =
+        annotations.iter().map(|ann| (ann.start, &code[ann.byte_range()])).collect();
+    assert_eq!(tokens, [(11, "Foo"), (27, "foo"), (45, "foo")]);
+}
+
+#[test]
+fn test_normalize_line_endings() {
+    let code = "struct Foo;\r\nlet foo = Foo;\r\nlet bar = 1;\rlet baz = 2;\n";
+    let (normalized, map) = normalize_line_endings(code);
+    // A lone `\r` isn't a line ending
+    assert_eq!(normalized, "struct Foo;\nlet foo = Foo;\nlet bar = 1;\rlet baz = 2;\n");
+    assert_eq!(split_items_and_statements(&normalized).0, "struct Foo;\n");
+    let foo = normalized.find("foo").unwrap() as u32;
+    assert_eq!(&code[map.to_original(foo) as usize..][..3], "foo");
+    let baz = normalized.find("baz").unwrap() as u32;
+    assert_eq!(&code[map.to_original(baz) as usize..][..3], "baz");
+    assert_eq!(normalize_line_endings("let x = 1;\n"), ("let x = 1;\n".into(), Default::default()));
+
+    // Annotations are of the code as it was given
+    let (normalized, _) = normalize_line_endings(code);
+    let mut analyzer = MockAnalyzer::builder()
+        .hover(&normalized, "foo", "let foo: Foo")
+        .hover(&normalized, "baz", "let baz: i32")
+        .build();
+    let config = TwoslashConfig::default();
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+    let tokens: Vec<_> = annotations.iter().map(|ann| &code[ann.byte_range()]).collect();
+    assert_eq!(tokens, ["foo", "baz"]);
+}
+
+/// Code made of Rust-looking items, statements, comments and blank lines
+fn rust_like_code() -> impl Strategy<Value = String> {
+    let items = [
+        r"fn [a-z]{1,6}\(\) \{ [a-z0-9 +;é]{0,12} \}",
+        r"fn [a-z]{1,6}\(\n    [a-z]: (u8|i32),\n\) -> u8 \{\n    [a-z0-9 +]{1,8}\n\}",
+        r"struct [A-Z][a-z]{0,5} \{ [a-z]{1,4}: (u8|i32|String), \}",
+        r"#\[derive\(Debug\)\]\nenum [A-Z][a-z]{0,5} \{ [A-Z][a-z]{0,4}, [A-Z]\([a-z0-9]{1,3}\) \}",
+        concat!(
+            r"impl [A-Z][a-z]{0,5} \{\n",
+            r#"    fn [a-z]{1,5}\(&self\) -> &str \{ "[{}a-zé ]{0,6}" \}\n\}"#
+        ),
+        r#"const [A-Z]{1,5}: &str = "[{}a-zé ]{0,8}";"#,
+    ];
+    let statements = [
+        r"let [a-z]{1,5} = [0-9]{1,3};",
+        r"[a-z]{1,6}\([0-9]{0,2}\);",
+        r#"println!\("[{}a-zé ]{0,8}"\);"#,
+        r"let [a-z]{1,5} = \{\n    [a-z]{1,4}\(\)\n\};",
+    ];
+    let others = [r"", r"// [a-zé {}]{0,8}", r"/\* [a-zé{}\n]{0,8} \*/"];
+    let line = |patterns: &[&str]| {
+        let strategies: Vec<_> =
+            patterns.iter().map(|pattern| string_regex(pattern).unwrap().boxed()).collect();
+        Union::new(strategies)
+    };
+    let chunk = prop_oneof![line(&items), line(&statements), line(&others)];
+    (proptest::collection::vec(chunk, 0..8), any::<bool>())
+        .prop_map(|(chunks, newline)| chunks.join("\n") + if newline { "\n" } else { "" })
+}
+
+proptest! {
+    #[test]
+    fn test_split_items_and_statements_properties(code in rust_like_code()) {
+        let (preamble, body) = split_items_and_statements(&code);
+        prop_assert!(code.is_char_boundary(preamble.len()));
+        prop_assert_eq!(format!("{preamble}{body}"), code.clone());
+        // A block only gets a `fn main` of its own if it has statements
+        if preamble.contains("fn main") {
+            prop_assert!(body.is_empty());
+        }
+        let wrapped = WrappedCode::with_runtime(&code, AsyncRuntime::Tokio);
+        prop_assert_eq!(wrapped.code.contains("fn main"), !body.is_empty());
+
+        // Undoing the wrapping gives the code back
+        let (preamble_len, fn_main_offset) =
+            (wrapped.preamble_len as usize, wrapped.fn_main_offset as usize);
+        let unwrapped = if fn_main_offset == 0 {
+            wrapped.code.clone()
+        } else {
+            let rest = &wrapped.code[preamble_len + fn_main_offset..];
+            let body = rest.strip_suffix("\n}").expect("the body is followed by a `}`");
+            format!("{}{body}", &wrapped.code[..preamble_len])
+        };
+        prop_assert_eq!(unwrapped, code);
+    }
+}
+
+#[test]
+fn test_classify_line() {
+    for line in [
+        "const X: i32 = 42;",
+        "pub const _: () = ();",
+        "const fn double(x: i32) -> i32 { x * 2 }",
+        "const unsafe fn raw() {}",
+        "async fn answer() -> i32 { 42 }",
+        "unsafe impl Send for Foo {}",
+        "static GREETING: &str = \"hi\";",
+        "static mut COUNTER: u32 = 0;",
+        "#[derive(Debug)]",
+        "    fn indented() {}",
+    ] {
+        assert_eq!(classify_line(line), LineKind::Item, "{line}");
+    }
+    for line in [
+        "const { let x = 1; x };",
+        "const{ 1 };",
+        "constant.set(1);",
+        "static || yield 1;",
+        "async move { 1 };",
+        "unsafe { raw() };",
+        "statics::init();",
+        "let x = 1;",
+    ] {
+        assert_eq!(classify_line(line), LineKind::Statement, "{line}");
+    }
+    for line in ["", "   ", "// comment", "/// doc", "/* block */"] {
+        assert_eq!(classify_line(line), LineKind::Blank, "{line:?}");
+    }
+
+    let code = "const X: i32 = 1;\nconst { assert!(X == 1) };\n";
+    assert_eq!(split_items_and_statements(code).1, "const { assert!(X == 1) };\n");
+}
+
+#[test]
+fn test_async_main() {
+    let code = "async fn answer() -> i32 { 42 }\nlet x = answer().await;\n";
+    let wrapped = WrappedCode::with_runtime(code, AsyncRuntime::Tokio);
+    assert_eq!(
+        wrapped.code,
+        "async fn answer() -> i32 { 42 }\n#[tokio::main(flavor = \"current_thread\")]\n\
+         async fn main() {\nlet x = answer().await;\n\n}"
+    );
+    let x = wrapped.code.find("x =").unwrap() as u32;
+    assert_eq!(wrapped.to_original(x), code.find("x =").map(|x| x as u32));
+
+    let wrapped = WrappedCode::with_runtime(code, AsyncRuntime::Smol);
+    assert!(wrapped.code.contains("fn main() { smol::block_on(async {\nlet x"));
+    assert!(wrapped.code.ends_with("\n}) }"));
+    let config = TwoslashConfig { async_runtime: AsyncRuntime::Smol, ..Default::default() };
+    assert_eq!(WrappedCode::new(code, &config).code, wrapped.code);
+    // `async` blocks alone don't need an async main
+    let wrapped = WrappedCode::with_runtime("let f = async { 1 };", AsyncRuntime::Tokio);
+    assert_eq!(wrapped.code, "fn main() {\nlet f = async { 1 };\n}");
+
+    // Only blocks wrapped in an async main get the runtime as a dependency
+    let config = TwoslashConfig::default();
+    assert!(WrappedCode::is_async_main(code, &config));
+    assert!(!WrappedCode::is_async_main("let f = async { 1 };", &config));
+    assert!(!WrappedCode::is_async_main("async fn f() -> i32 { answer().await }", &config));
+    let cargo_toml = "[package]\nname = \"foo\"\n\n[dependencies]\nserde = \"1\"\n";
+    assert_eq!(
+        with_runtime_dependency(cargo_toml, AsyncRuntime::Smol),
+        "[package]\nname = \"foo\"\n\n[dependencies]\nserde = \"1\"\nsmol = \"2\"\n"
+    );
+    let cargo_toml = "[dependencies]\ntokio = { version = \"1\", features = [\"full\"] }\n";
+    assert_eq!(with_runtime_dependency(cargo_toml, AsyncRuntime::Tokio), cargo_toml);
+
+    let runtime = |value: &str| {
+        let mut resolver = MockCargoResolver::default();
+        resolver.env.insert("RUSTDOC_TWOSLASH_ASYNC_RUNTIME".into(), value.into());
+        AsyncRuntime::from_env(&resolver)
+    };
+    assert_eq!(AsyncRuntime::from_env(&MockCargoResolver::default()), AsyncRuntime::Tokio);
+    assert_eq!(runtime("async-std"), AsyncRuntime::AsyncStd);
+    assert_eq!(runtime("smol"), AsyncRuntime::Smol);
+    assert_eq!(runtime("glommio"), AsyncRuntime::Tokio);
+}
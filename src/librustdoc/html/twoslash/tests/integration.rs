@@ -0,0 +1,124 @@
+//! Analysis of code blocks against a real crate, with rust-analyzer and a Rust toolchain.
+//!
+//! Most are `#[ignore]`d and additionally need `RUSTDOC_TWOSLASH_INTEGRATION_TESTS=1`, since
+//! scaffolding the temp project resolves dependencies over the network. Run them with
+//! `RUSTDOC_TWOSLASH_INTEGRATION_TESTS=1 cargo test -- --ignored integration`. Those running
+//! Cargo offline only need the `cargo` the tests are run with.
+
+use std::fs;
+use std::path::Path;
+
+use twoslash_rust::Analyzer;
+
+use crate::html::twoslash::{
+    AnalyzerSettingsBuilder, DefaultCargoResolver, TwoslashConfig, TwoslashError, TypeAnnotation,
+    cargo_check, process_code_block_with, resolve_cargo_toml,
+};
+
+const FIXTURE_CARGO_TOML: &str = r#"[package]
+name = "twoslash-fixture"
+version = "0.1.0"
+edition = "2021"
+
+[dependencies]
+"#;
+
+const FIXTURE_LIB_RS: &str = r#"
+/// Adds two numbers
+pub fn add(a: i32, b: i32) -> i32 {
+    a + b
+}
+
+pub struct Counter {
+    count: u32,
+}
+
+impl Counter {
+    pub fn new() -> Self {
+        Counter { count: 0 }
+    }
+
+    pub fn increment(&mut self) -> u32 {
+        self.count += 1;
+        self.count
+    }
+}
+"#;
+
+fn enabled() -> bool {
+    std::env::var("RUSTDOC_TWOSLASH_INTEGRATION_TESTS").is_ok_and(|v| v == "1")
+}
+
+/// Write the fixture crate to `dir`
+fn write_fixture(dir: &Path) {
+    fs::create_dir_all(dir.join("src")).unwrap();
+    fs::write(dir.join("Cargo.toml"), FIXTURE_CARGO_TOML).unwrap();
+    fs::write(dir.join("src/lib.rs"), FIXTURE_LIB_RS).unwrap();
+}
+
+/// Type text of the first annotation on `token` in `code`
+fn type_of<'a>(code: &str, annotations: &'a [TypeAnnotation], token: &str) -> &'a str {
+    annotations
+        .iter()
+        .find(|ann| {
+            let start = ann.start as usize;
+            code.get(start..start + ann.length as usize) == Some(token)
+        })
+        .map(|ann| ann.type_text.as_str())
+        .unwrap_or_else(|| panic!("no annotation on `{token}` in {annotations:#?}"))
+}
+
+#[test]
+#[ignore = "needs a Rust toolchain, rust-analyzer and network access"]
+fn test_integration_fixture_crate() {
+    if !enabled() {
+        eprintln!("skipping, RUSTDOC_TWOSLASH_INTEGRATION_TESTS isn't set to 1");
+        return;
+    }
+    let tmp = tempfile::tempdir().unwrap();
+    let crate_dir = tmp.path().join("fixture");
+    write_fixture(&crate_dir);
+
+    let config = TwoslashConfig {
+        cargo_toml_path: Some(crate_dir.join("Cargo.toml")),
+        target_dir: tmp.path().join("target"),
+        ..Default::default()
+    };
+    let cargo_toml = resolve_cargo_toml(&DefaultCargoResolver, &config);
+    assert!(cargo_toml.as_deref().is_some_and(|toml| toml.contains("twoslash_fixture")));
+    let settings = AnalyzerSettingsBuilder::default()
+        .cargo_toml(cargo_toml)
+        .target_dir(Some(config.target_dir.clone()))
+        .build()
+        .unwrap();
+    let mut analyzer = Analyzer::new(settings);
+
+    let code = "\
+use twoslash_fixture::{Counter, add};
+let sum = add(1, 2);
+let mut counter = Counter::new();
+let next = counter.increment();
+";
+    let annotations = process_code_block_with(code, None, &config, &mut analyzer).unwrap();
+
+    // Return type of a function
+    assert!(type_of(code, &annotations, "add").contains("-> i32"));
+    // Type of a let binding
+    assert!(type_of(code, &annotations, "sum").contains("i32"));
+    // Return type of a method call
+    assert!(type_of(code, &annotations, "increment").contains("-> u32"));
+    assert!(type_of(code, &annotations, "next").contains("u32"));
+}
+
+#[test]
+fn test_integration_offline_missing_dep() {
+    let tmp = tempfile::tempdir().unwrap();
+    let config = TwoslashConfig {
+        target_dir: tmp.path().join("target"),
+        offline: true,
+        ..Default::default()
+    };
+    let cargo_toml = format!("{FIXTURE_CARGO_TOML}twoslash-never-published = \"1\"\n");
+    let checked = cargo_check("fn main() {}", &cargo_toml, &config);
+    assert_eq!(checked, Err(TwoslashError::OfflineMissingDep("twoslash-never-published".into())));
+}